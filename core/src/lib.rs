@@ -0,0 +1,554 @@
+//! The scheduling core of `ratelim` without `std`, only `alloc`: the `Logic` trait, the simplest logics,
+//! and `Core`, the bookkeeping of the delayed frees, over any `Clock`.
+//! There is no lock and no sleep, the caller serializes the calls, e.g. with an embassy mutex,
+//! and sleeps until `Core::next_free` on its own executor.
+//!
+//! The `std` feature, on by default, adds `StdClock`.
+
+#![no_std]
+
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate std;
+
+use alloc::collections::BinaryHeap;
+use core::cmp::Ordering;
+use core::ops::{Add, Sub};
+use core::time::Duration;
+
+/// **Logic** requires minimum code to represent the limitations logic: `is_ready`, `add_for` and `free`,
+/// everything else has a default. Thread safety is promised by the Limiter implementation.
+/// Timeouts are specified inside of `fn add_for(...) -> Duration` and could vary,
+/// depending on the function result.
+///
+/// ## Example
+/// ```
+/// use core::time::Duration;
+/// use ratelim_core::Logic;
+///
+/// #[derive(Clone)]
+/// struct QuotaPerSecond {
+///     quota: u64,
+///     operations_counter: u64,
+/// }
+///
+/// impl Logic<u64> for QuotaPerSecond {
+///     fn is_ready(&self) -> bool { self.operations_counter < self.quota }
+///
+///     fn is_ready_for(&self, state: &u64) -> bool { self.operations_counter + state <= self.quota }
+///
+///     fn add_for(&mut self, state: &u64) -> Duration {
+///         self.operations_counter += state;
+///         Duration::from_secs(1)
+///     }
+///
+///     // a free after a `reset` finds nothing left to free
+///     fn free(&mut self, state: &u64) { self.operations_counter = self.operations_counter.saturating_sub(*state); }
+///
+///     fn reset(&mut self) { self.operations_counter = 0; }
+/// }
+///
+/// let mut logic = QuotaPerSecond { quota: 3, operations_counter: 0 };
+/// logic.add_for(&2);
+/// assert!(logic.is_ready() && !logic.is_ready_for(&2));
+/// logic.free(&2);
+/// assert!(logic.is_ready_for(&2));
+/// ```
+pub trait Logic<State> {
+    fn is_ready(&self) -> bool;
+
+    /// Whether `state` in particular can be added now, checked right before it is. `is_ready` by default,
+    /// which only tells whether anything can, so a cost above what is left is still admitted and overshoots.
+    /// Readiness without a request at hand, e.g. `Limiter::subscribe`, stays `is_ready`.
+    fn is_ready_for(&self, _state: &State) -> bool { self.is_ready() }
+
+    /// How long `state` stays reserved. A delay too long to be scheduled, e.g. `Duration::MAX`,
+    /// is never freed, for quotas consumed once and for all.
+    fn add_for(&mut self, state: &State) -> Duration;
+
+    fn free(&mut self, state: &State);
+
+    /// Same as `add_for`, but also returns what was actually reserved, which `free` is handed later
+    /// instead of `state`. For logics that clamp or transform the cost, so every free matches its add.
+    /// `state` itself by default.
+    fn reserve(&mut self, state: State) -> (Duration, State) {
+        let delay = self.add_for(&state);
+        (delay, state)
+    }
+
+    /// Largest `state` the logic could ever admit, if there is such a limit.
+    /// `Limiter::sync_checked` rejects anything above it instead of waiting forever.
+    fn max_capacity(&self) -> Option<State> { None }
+
+    /// How much more the logic could admit right now, if it keeps count.
+    fn headroom(&self) -> Option<State> { None }
+
+    /// How long until the logic becomes ready again by time passing alone, rather than a free, e.g. the rest
+    /// of a pause. Waiters wake up then at the latest, instead of only at the next free.
+    fn ready_in(&self) -> Option<Duration> { None }
+
+    /// Forgets everything admitted so far, as if freshly constructed. Used by `Limiter::reset`,
+    /// does nothing by default.
+    fn reset(&mut self) {}
+}
+
+impl<State, L: Logic<State> + ?Sized> Logic<State> for alloc::boxed::Box<L> {
+    fn is_ready(&self) -> bool { (**self).is_ready() }
+
+    fn is_ready_for(&self, state: &State) -> bool { (**self).is_ready_for(state) }
+
+    fn add_for(&mut self, state: &State) -> Duration { (**self).add_for(state) }
+
+    fn free(&mut self, state: &State) { (**self).free(state) }
+
+    fn reserve(&mut self, state: State) -> (Duration, State) { (**self).reserve(state) }
+
+    fn max_capacity(&self) -> Option<State> { (**self).max_capacity() }
+
+    fn headroom(&self) -> Option<State> { (**self).headroom() }
+
+    fn ready_in(&self) -> Option<Duration> { (**self).ready_in() }
+
+    fn reset(&mut self) { (**self).reset() }
+}
+
+/// Simplest Logic implementation. Ensures actions has fixed timeout.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Timeout {
+    is_timed_out: bool,
+    timeout: Duration,
+}
+
+impl Timeout {
+    pub fn new(timeout: Duration) -> Timeout {
+        Timeout {
+            is_timed_out: false,
+            timeout,
+        }
+    }
+}
+
+impl Logic<()> for Timeout {
+    fn is_ready(&self) -> bool { !self.is_timed_out }
+
+    fn add_for(&mut self, _: &()) -> Duration {
+        self.is_timed_out = true;
+        self.timeout
+    }
+
+    fn free(&mut self, _: &()) { self.is_timed_out = false; }
+
+    fn reset(&mut self) { self.is_timed_out = false; }
+}
+
+/// Numeric weight of a request, implemented for the primitive integers and floats.
+pub trait Weight: sealed::Sealed + Copy + PartialOrd + Default + Add<Output=Self> + Sub<Output=Self> {
+    /// `self - other`, clamped to zero.
+    fn saturating_sub(self, other: Self) -> Self { if self > other { self - other } else { Self::default() } }
+}
+
+mod sealed {
+    pub trait Sealed {
+        // floats round, a sum and its parts freed one by one may not add up exactly
+        const EXACT: bool;
+    }
+}
+
+macro_rules! impl_weight {
+    ($exact:literal: $($t:ty),*) => { $(impl sealed::Sealed for $t { const EXACT: bool = $exact; } impl Weight for $t {})* };
+}
+
+impl_weight!(true: u8, u16, u32, u64, u128, usize, i8, i16, i32, i64, i128, isize);
+impl_weight!(false: f32, f64);
+
+/// Simple Logic implementation. Ensures actions are performed less often than the given quota/time.
+/// Weights may be any primitive number, e.g. `QuotaPer<f64>` for fractional costs.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct QuotaPer<W = u64> {
+    quota: W,
+    state: W,
+    timeout: Duration,
+    // reserved before a reset, may still be freed
+    #[cfg_attr(feature = "serde", serde(default))]
+    forgotten: W,
+}
+
+impl<W: Weight> QuotaPer<W> {
+    pub fn new(quota: W, timeout: Duration) -> QuotaPer<W> {
+        QuotaPer {
+            quota,
+            state: W::default(),
+            timeout,
+            forgotten: W::default(),
+        }
+    }
+
+    /// `quota` per second.
+    pub fn per_second(quota: W) -> QuotaPer<W> { QuotaPer::new(quota, Duration::from_secs(1)) }
+
+    /// `quota` per minute.
+    ///
+    /// ```
+    /// use ratelim_core::{Logic, QuotaPer};
+    ///
+    /// let logic = QuotaPer::per_minute(5000);
+    /// assert_eq!(logic.max_capacity(), Some(5000));
+    /// ```
+    pub fn per_minute(quota: W) -> QuotaPer<W> { QuotaPer::new(quota, Duration::from_secs(60)) }
+
+    /// `quota` per hour.
+    pub fn per_hour(quota: W) -> QuotaPer<W> { QuotaPer::new(quota, Duration::from_secs(3600)) }
+
+    pub fn quota(&self) -> W { self.quota }
+
+    /// Reserved and not yet freed.
+    pub fn used(&self) -> W { self.state }
+
+    /// Changes the quota in place, what is already reserved stays reserved.
+    /// Lowering it below the current usage simply blocks until enough is freed.
+    pub fn set_quota(&mut self, quota: W) { self.quota = quota; }
+}
+
+impl<W: Weight> Logic<W> for QuotaPer<W> {
+    fn is_ready(&self) -> bool { self.state < self.quota }
+
+    // a cost above the whole quota could never fit, it goes alone instead of waiting forever,
+    // `Limiter::sync_checked` rejects it up front
+    fn is_ready_for(&self, state: &W) -> bool {
+        self.state + *state <= self.quota || (self.state == W::default() && *state > self.quota)
+    }
+
+    fn add_for(&mut self, state: &W) -> Duration {
+        self.state = self.state + *state;
+        self.timeout
+    }
+
+    // every free must match an earlier add, also one from before a reset, a mismatch is a bug elsewhere,
+    // caught in debug builds, in release it should not underflow and lock the limiter up forever
+    fn free(&mut self, state: &W) {
+        debug_assert!(!W::EXACT || *state <= self.state + self.forgotten, "freed more than was added to the quota");
+        self.forgotten = self.forgotten.saturating_sub(state.saturating_sub(self.state));
+        self.state = self.state.saturating_sub(*state);
+    }
+
+    fn max_capacity(&self) -> Option<W> { Some(self.quota) }
+
+    fn headroom(&self) -> Option<W> { Some(self.quota.saturating_sub(self.state)) }
+
+    fn reset(&mut self) {
+        self.forgotten = self.forgotten + self.state;
+        self.state = W::default();
+    }
+}
+
+/// `count` requests per `per`, e.g. a limit copied from a third-party's documentation. Rates that are
+/// the same, whatever the unit, compare equal, `Rate::per_minute(600) == Rate::per_second(10)`.
+#[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Rate {
+    count: u64,
+    per: Duration,
+}
+
+impl Rate {
+    pub fn new(count: u64, per: Duration) -> Rate { Rate { count, per } }
+
+    pub fn per_second(count: u64) -> Rate { Rate::new(count, Duration::from_secs(1)) }
+
+    pub fn per_minute(count: u64) -> Rate { Rate::new(count, Duration::from_secs(60)) }
+
+    pub fn per_hour(count: u64) -> Rate { Rate::new(count, Duration::from_secs(3600)) }
+
+    pub fn count(&self) -> u64 { self.count }
+
+    pub fn per(&self) -> Duration { self.per }
+
+    /// Time between two requests at this rate, `Duration::MAX` for a rate of zero.
+    pub fn interval(&self) -> Duration {
+        if self.count == 0 {
+            return Duration::MAX;
+        }
+        self.per / u32::try_from(self.count).unwrap_or(u32::MAX)
+    }
+
+    /// Same rate over `per`, the count rounded down, e.g. `Rate::per_minute(90).over(Duration::from_secs(1))`
+    /// is 1 per second.
+    pub fn over(&self, per: Duration) -> Rate {
+        let count = self.count as u128 * per.as_nanos() / self.per.as_nanos().max(1);
+        Rate::new(u64::try_from(count).unwrap_or(u64::MAX), per)
+    }
+
+    /// Same rate over the shortest period holding a whole count, e.g. 1 per second for 60 per minute.
+    pub fn normalized(&self) -> Rate {
+        let nanos = self.per.as_nanos();
+        let divisor = gcd(self.count as u128, nanos).max(1);
+        Rate::new((self.count as u128 / divisor) as u64, duration_from_nanos(nanos / divisor))
+    }
+}
+
+impl PartialEq for Rate {
+    fn eq(&self, other: &Rate) -> bool { self.count as u128 * other.per.as_nanos() == other.count as u128 * self.per.as_nanos() }
+}
+
+impl Eq for Rate {}
+
+impl From<(u64, Duration)> for Rate {
+    fn from((count, per): (u64, Duration)) -> Rate { Rate::new(count, per) }
+}
+
+/// The quota of the `Rate::normalized` rate, so 60 per minute admits one per second rather than bursts of 60.
+/// Use `QuotaPer::new` for a quota over the documented window.
+impl From<Rate> for QuotaPer<u64> {
+    fn from(rate: Rate) -> QuotaPer<u64> {
+        let rate = rate.normalized();
+        QuotaPer::new(rate.count, rate.per)
+    }
+}
+
+fn gcd(mut a: u128, mut b: u128) -> u128 {
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    a
+}
+
+fn duration_from_nanos(nanos: u128) -> Duration {
+    Duration::new(u64::try_from(nanos / 1_000_000_000).unwrap_or(u64::MAX), (nanos % 1_000_000_000) as u32)
+}
+
+/// Source of time for the `Core`, e.g. a hardware timer. `Instant` only needs an order and to be shifted by a `Duration`.
+pub trait Clock {
+    type Instant: Copy + Ord + Add<Duration, Output=Self::Instant>;
+
+    fn now(&self) -> Self::Instant;
+
+    /// `instant + delay`, `None` if that is past the latest `Instant`, then the free is never scheduled.
+    /// Only overflows by panicking unless implemented.
+    fn checked_add(instant: Self::Instant, delay: Duration) -> Option<Self::Instant> { Some(instant + delay) }
+}
+
+/// `std::time::Instant::now()`.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StdClock;
+
+#[cfg(feature = "std")]
+impl Clock for StdClock {
+    type Instant = std::time::Instant;
+
+    fn now(&self) -> std::time::Instant { std::time::Instant::now() }
+
+    fn checked_add(instant: std::time::Instant, delay: Duration) -> Option<std::time::Instant> { instant.checked_add(delay) }
+}
+
+/// A delayed free of `T` due at `I`, ordered so a `BinaryHeap` pops the earliest one first.
+#[derive(Clone, Debug)]
+pub struct HeapValue<I, T>(pub (I, T));
+
+//noinspection RsTraitImplementation -- my RustRover (beta) is being a bitch for no reason
+impl<I: Ord, T> Eq for HeapValue<I, T> {}
+
+impl<I: Ord, T> PartialEq<Self> for HeapValue<I, T> { fn eq(&self, other: &Self) -> bool { self.0.0 == other.0.0 } }
+
+impl<I: Ord, T> PartialOrd<Self> for HeapValue<I, T> { fn partial_cmp(&self, other: &Self) -> Option<Ordering> { Some(self.cmp(other)) } }
+
+// reversed, so the `BinaryHeap` pops the earliest instant first
+impl<I: Ord, T> Ord for HeapValue<I, T> {
+    fn cmp(&self, other: &Self) -> Ordering { other.0.0.cmp(&self.0.0) }
+}
+
+/// Frees every delayed free due by `now` in `logic`, returns how many.
+pub fn reclaim<I: Ord, State, L: Logic<State>>(delayed_frees: &mut BinaryHeap<HeapValue<I, State>>, logic: &mut L, now: I) -> usize {
+    let mut freed = 0;
+    while let Some(HeapValue((delayed_for, state))) = delayed_frees.peek() {
+        if now < *delayed_for {
+            break;
+        }
+
+        logic.free(state);
+        delayed_frees.pop();
+        freed += 1;
+    }
+    freed
+}
+
+/// A logic with its delayed frees, the same bookkeeping the `Limiter` does under its lock.
+pub struct Core<L, State, C: Clock> {
+    logic: L,
+    delayed_frees: BinaryHeap<HeapValue<C::Instant, State>>,
+    clock: C,
+}
+
+impl<L: Logic<State>, State, C: Clock> Core<L, State, C> {
+    pub fn new(logic: L, clock: C) -> Core<L, State, C> {
+        Core {
+            logic,
+            delayed_frees: BinaryHeap::new(),
+            clock,
+        }
+    }
+
+    pub fn logic(&self) -> &L { &self.logic }
+
+    /// Frees what is due, then asks the logic.
+    pub fn ready(&mut self) -> bool {
+        self.cleanup();
+        self.logic.is_ready()
+    }
+
+    /// Reserves `state` whether the logic is ready or not, it is freed after the logic's `add_for` delay.
+    pub fn add(&mut self, state: State) {
+        let (delay, state) = self.logic.reserve(state);
+        // otherwise reserved for good
+        if let Some(delayed_for) = C::checked_add(self.clock.now(), delay) {
+            self.delayed_frees.push(HeapValue((delayed_for, state)));
+        }
+    }
+
+    /// Same as `ready`, for `state` in particular, see `Logic::is_ready_for`.
+    pub fn ready_for(&mut self, state: &State) -> bool {
+        self.cleanup();
+        self.logic.is_ready_for(state)
+    }
+
+    /// Reserves `state` if the logic is ready for it, hands it back otherwise.
+    pub fn try_add(&mut self, state: State) -> Result<(), State> {
+        if !self.ready_for(&state) {
+            return Err(state);
+        }
+        self.add(state);
+        Ok(())
+    }
+
+    /// Frees every delayed free that is due, returns how many.
+    pub fn cleanup(&mut self) -> usize {
+        let now = self.clock.now();
+        reclaim(&mut self.delayed_frees, &mut self.logic, now)
+    }
+
+    /// Instant of the earliest scheduled free, if any, i.e. when to check again.
+    pub fn next_free(&self) -> Option<C::Instant> { self.delayed_frees.peek().map(|HeapValue((delayed_for, _))| *delayed_for) }
+
+    /// Number of delayed frees not reclaimed yet.
+    pub fn pending(&self) -> usize { self.delayed_frees.len() }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::cell::Cell;
+    use super::*;
+
+    // milliseconds, moved by hand
+    struct MockClock<'a>(&'a Cell<u64>);
+
+    #[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+    struct Millis(u64);
+
+    impl Add<Duration> for Millis {
+        type Output = Millis;
+
+        fn add(self, duration: Duration) -> Millis { Millis(self.0 + duration.as_millis() as u64) }
+    }
+
+    impl Clock for MockClock<'_> {
+        type Instant = Millis;
+
+        fn now(&self) -> Millis { Millis(self.0.get()) }
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "freed more than was added"))]
+    fn quota_free_underflow() {
+        let mut logic = QuotaPer::new(5, Duration::from_secs(1));
+        logic.add_for(&2);
+        logic.free(&3);
+        assert_eq!(logic.state, 0);
+        assert!(logic.is_ready());
+    }
+
+    #[test]
+    fn quota_free_after_reset() {
+        let mut logic = QuotaPer::new(5, Duration::from_secs(1));
+        logic.add_for(&2);
+        logic.reset();
+        logic.add_for(&3);
+        logic.free(&2);
+        logic.free(&3);
+        assert_eq!((logic.state, logic.forgotten), (0, 0));
+
+        // fractional costs round, they aren't checked
+        let mut logic = QuotaPer::new(1.0, Duration::from_secs(1));
+        logic.add_for(&0.1);
+        logic.add_for(&0.7);
+        logic.free(&0.1);
+        logic.free(&0.7);
+        assert!(logic.is_ready_for(&1.0));
+    }
+
+    #[test]
+    fn rate() {
+        assert_eq!(Rate::per_minute(600), Rate::per_second(10));
+        assert_eq!(Rate::new(5, Duration::from_millis(500)), Rate::per_second(10));
+        assert_ne!(Rate::per_hour(5000), Rate::per_second(1));
+        assert_eq!(Rate::per_minute(90).over(Duration::from_secs(1)).count(), 1);
+        assert_eq!(Rate::per_second(10).interval(), Duration::from_millis(100));
+
+        let normalized = Rate::per_hour(5000).normalized();
+        assert_eq!((normalized.count(), normalized.per()), (1, Duration::from_millis(720)));
+        let normalized = Rate::new(6, Duration::from_millis(4)).normalized();
+        assert_eq!((normalized.count(), normalized.per()), (3, Duration::from_millis(2)));
+    }
+
+    #[test]
+    fn quota_cost() {
+        let mut logic = QuotaPer::new(10, Duration::from_secs(1));
+        logic.add_for(&7);
+        assert!(logic.is_ready());
+        assert!(logic.is_ready_for(&3));
+        assert!(!logic.is_ready_for(&4));
+
+        // too large to ever fit, only once the quota is unused
+        assert!(!logic.is_ready_for(&11));
+        logic.free(&7);
+        assert!(logic.is_ready_for(&11));
+    }
+
+    #[test]
+    fn heap_order() {
+        let early = || HeapValue((Millis(0), 'e'));
+        let late = || HeapValue((Millis(1000), 'l'));
+        assert_eq!(early().max(late()).0.1, 'e');
+        assert_eq!(early().min(late()).0.1, 'l');
+
+        let mut heap = BinaryHeap::new();
+        for (offset, value) in [(3, 'c'), (1, 'a'), (4, 'd'), (2, 'b')] {
+            heap.push(HeapValue((Millis(offset), value)));
+        }
+        let popped: alloc::string::String = core::iter::from_fn(|| heap.pop().map(|HeapValue((_, value))| value)).collect();
+        assert_eq!(popped, "abcd");
+    }
+
+    #[test]
+    fn cleanup() {
+        let now = Cell::new(0);
+        let mut core = Core::new(QuotaPer::new(2, Duration::from_millis(100)), MockClock(&now));
+        assert!(core.try_add(1).is_ok());
+        now.set(50);
+        assert!(core.try_add(1).is_ok());
+        assert_eq!(core.try_add(1), Err(1));
+        assert_eq!(core.next_free(), Some(Millis(100)));
+
+        // only the first one is due
+        now.set(100);
+        assert_eq!(core.cleanup(), 1);
+        assert_eq!(core.pending(), 1);
+        assert!(core.try_add(1).is_ok());
+        assert!(!core.ready());
+
+        now.set(250);
+        assert!(core.ready());
+        assert_eq!(core.pending(), 0);
+    }
+}
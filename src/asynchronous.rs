@@ -0,0 +1,205 @@
+use std::collections::BinaryHeap;
+use std::sync::Arc;
+use std::time::Duration;
+use crate::time::Instant;
+use ratelim_core::HeapValue;
+use crate::runtime::Mutex;
+use crate::sleeper::{self, Sleeper};
+use crate::logic::AsyncLogic;
+
+/// `Limiter` counterpart for `AsyncLogic`s, whose checks and frees may await I/O,
+/// e.g. a counter kept in a database. Synchronous logics can be used through `logic::FromSync`.
+///
+/// The internal lock is held while the logic awaits, so callers are serialized the same way
+/// as for the `Limiter`, just without blocking the executor.
+///
+/// Unlike the `Limiter`'s, `sync` is not fully cancellation safe: cancelled while the logic awaits `add_for`,
+/// the logic may have counted a reservation that is never freed.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use ratelim::asynchronous::AsyncLimiter;
+/// use ratelim::logic::{FromSync, QuotaPer};
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let limiter = AsyncLimiter::new(FromSync::new(QuotaPer::new(5, Duration::from_millis(500))));
+/// limiter.sync(1).await;
+/// # }
+/// ```
+pub struct AsyncLimiter<Logic: AsyncLogic<State>, State> {
+    internal: Arc<Mutex<AsyncLogicWrapper<Logic, State>>>,
+    polling_timeout: Duration,
+    sleeper: Arc<dyn Sleeper>,
+}
+
+impl<Logic: AsyncLogic<State>, State> Clone for AsyncLimiter<Logic, State> {
+    fn clone(&self) -> AsyncLimiter<Logic, State> {
+        AsyncLimiter {
+            internal: self.internal.clone(),
+            polling_timeout: self.polling_timeout,
+            sleeper: self.sleeper.clone(),
+        }
+    }
+}
+
+impl<Logic: AsyncLogic<State>, State> AsyncLimiter<Logic, State> {
+    pub fn new(logic: Logic) -> AsyncLimiter<Logic, State> {
+        AsyncLimiter::with_polling_timeout(logic, Duration::from_millis(1))
+    }
+
+    pub fn with_polling_timeout(logic: Logic, poll_timeout: Duration) -> AsyncLimiter<Logic, State> {
+        AsyncLimiter {
+            internal: Arc::new(Mutex::new(AsyncLogicWrapper::new(logic))),
+            polling_timeout: poll_timeout,
+            sleeper: sleeper::default_sleeper(),
+        }
+    }
+
+    /// Waits with `sleeper` rather than the default `Sleeper`.
+    pub fn with_sleeper(mut self, sleeper: impl Sleeper + 'static) -> AsyncLimiter<Logic, State> {
+        self.sleeper = Arc::new(sleeper);
+        self
+    }
+
+    pub async fn sync(&self, state: State) {
+        loop {
+            let until_next_free = {
+                let mut internal = self.internal.lock().await;
+
+                if internal.ready().await {
+                    internal.add(state).await;
+                    return;
+                }
+
+                internal.next_free().map(|next_free| next_free.saturating_duration_since(Instant::now()))
+            };
+
+            self.sleeper.sleep(until_next_free.unwrap_or(self.polling_timeout)).await;
+        }
+    }
+
+    /// Non-blocking version of `sync`. Hands the `state` back instead of waiting if the logic is not ready.
+    pub async fn try_sync(&self, state: State) -> Result<(), State> {
+        let mut internal = self.internal.lock().await;
+
+        if !internal.ready().await {
+            return Err(state);
+        }
+
+        internal.add(state).await;
+
+        Ok(())
+    }
+}
+
+struct AsyncLogicWrapper<Logic: AsyncLogic<State>, State> {
+    logic: Logic,
+    delayed_frees: BinaryHeap<HeapValue<Instant, State>>,
+}
+
+impl<Logic: AsyncLogic<State>, State> AsyncLogicWrapper<Logic, State> {
+    fn new(logic: Logic) -> AsyncLogicWrapper<Logic, State> {
+        AsyncLogicWrapper {
+            logic,
+            delayed_frees: BinaryHeap::new(),
+        }
+    }
+
+    async fn ready(&mut self) -> bool {
+        self.cleanup().await;
+        self.logic.is_ready().await
+    }
+
+    async fn add(&mut self, state: State) {
+        let delay = self.logic.add_for(&state).await;
+        // too long to be scheduled, never freed
+        if let Some(delayed_for) = Instant::now().checked_add(delay) {
+            self.delayed_frees.push(HeapValue((delayed_for, state)));
+        }
+    }
+
+    fn next_free(&self) -> Option<Instant> {
+        self.delayed_frees.peek().map(|HeapValue((delayed_for, _))| *delayed_for)
+    }
+
+    async fn cleanup(&mut self) {
+        let now = Instant::now();
+        while self.delayed_frees.peek().is_some_and(|HeapValue((delayed_for, _))| now >= *delayed_for) {
+            if let Some(HeapValue((_, state))) = self.delayed_frees.pop() {
+                self.logic.free(&state).await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+    use crate::logic::{FromSync, QuotaPer};
+    use super::*;
+
+    /// Quota of one, whose free takes a while, like a remote counter would.
+    struct SlowFree {
+        taken: bool,
+        frees: Arc<AtomicUsize>,
+    }
+
+    impl AsyncLogic<()> for SlowFree {
+        async fn is_ready(&self) -> bool { !self.taken }
+
+        async fn add_for(&mut self, _: &()) -> Duration {
+            self.taken = true;
+            Duration::from_millis(20)
+        }
+
+        async fn free(&mut self, _: &()) {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            self.taken = false;
+            self.frees.fetch_add(1, SeqCst);
+        }
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn async_free() {
+        let frees = Arc::new(AtomicUsize::new(0));
+        let limiter = AsyncLimiter::new(SlowFree { taken: false, frees: frees.clone() });
+        assert!(limiter.try_sync(()).await.is_ok());
+        assert!(limiter.try_sync(()).await.is_err());
+
+        // the executor keeps running other tasks while the free is awaited
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticker = {
+            let ticks = ticks.clone();
+            tokio::spawn(async move {
+                loop {
+                    tokio::time::sleep(Duration::from_millis(1)).await;
+                    ticks.fetch_add(1, SeqCst);
+                }
+            })
+        };
+
+        let started = Instant::now();
+        limiter.sync(()).await;
+        assert!(started.elapsed() >= Duration::from_millis(40));
+        assert_eq!(frees.load(SeqCst), 1);
+        assert!(ticks.load(SeqCst) > 5);
+        ticker.abort();
+    }
+
+    #[tokio::test]
+    async fn from_sync() {
+        let limiter = AsyncLimiter::new(FromSync::new(QuotaPer::new(2, Duration::from_millis(50))));
+        assert!(limiter.try_sync(1).await.is_ok());
+        assert!(limiter.try_sync(1).await.is_ok());
+        assert!(limiter.try_sync(1).await.is_err());
+
+        // both reservations are freed together
+        limiter.sync(1).await;
+        assert!(limiter.try_sync(1).await.is_ok());
+        assert!(limiter.try_sync(1).await.is_err());
+    }
+}
@@ -0,0 +1,133 @@
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use tokio::sync::Notify;
+use crate::clock::{Clock, SystemClock};
+use crate::queue::WaitQueue;
+use crate::{logic, LogicWrapper};
+
+/// `Limiter` counterpart for synchronous code, e.g. CLI tools or worker threads without a runtime.
+/// Waits block the calling thread with `std::thread::sleep`, the logics are the same as the `Limiter`'s.
+///
+/// Cloning shares the state, like for the `Limiter`.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use ratelim::blocking::BlockingLimiter;
+/// use ratelim::logic::QuotaPer;
+///
+/// let limiter = BlockingLimiter::new(QuotaPer::new(5, Duration::from_millis(500)));
+/// limiter.sync(1);
+/// ```
+pub struct BlockingLimiter<Logic: logic::Logic<State>, State> {
+    internal: Arc<Mutex<LogicWrapper<Logic, State>>>,
+    polling_timeout: Duration,
+}
+
+impl<Logic: logic::Logic<State>, State> Clone for BlockingLimiter<Logic, State> {
+    fn clone(&self) -> BlockingLimiter<Logic, State> {
+        BlockingLimiter {
+            internal: self.internal.clone(),
+            polling_timeout: self.polling_timeout,
+        }
+    }
+}
+
+impl<Logic: logic::Logic<State>, State> BlockingLimiter<Logic, State> {
+    pub fn new(logic: Logic) -> BlockingLimiter<Logic, State> {
+        BlockingLimiter::with_polling_timeout(logic, Duration::from_millis(1))
+    }
+
+    pub fn with_polling_timeout(logic: Logic, poll_timeout: Duration) -> BlockingLimiter<Logic, State> {
+        let mut limiter = BlockingLimiter::with_clock(logic, SystemClock);
+        limiter.polling_timeout = poll_timeout;
+        limiter
+    }
+
+    /// Same as `new`, but delayed frees are scheduled with the given `clock`, e.g. a `MockClock` in tests.
+    pub fn with_clock(logic: Logic, clock: impl Clock + 'static) -> BlockingLimiter<Logic, State> {
+        // nobody waits on these, waiters sleep until the next free instead
+        let notify = Arc::new(Notify::new());
+        let maybe_ready = Arc::new(AtomicBool::new(true));
+        let queue = Arc::new(Mutex::new(WaitQueue::default()));
+
+        BlockingLimiter {
+            internal: Arc::new(Mutex::new(LogicWrapper::new(logic, notify, maybe_ready, queue, Arc::new(clock)))),
+            polling_timeout: Duration::from_millis(1),
+        }
+    }
+
+    /// Blocks the current thread until the logic is ready, then reserves `state`.
+    ///
+    /// Sleeps until the next delayed free is due or `Logic::ready_in`, or for `polling_timeout` if neither is known.
+    pub fn sync(&self, state: State) {
+        loop {
+            let mut internal = self.lock();
+            if internal.ready_for(Some(&state), None) {
+                internal.add(state);
+                return;
+            }
+
+            let until_wakeup = internal.until_wakeup();
+            drop(internal);
+
+            std::thread::sleep(until_wakeup.unwrap_or(self.polling_timeout));
+        }
+    }
+
+    /// Non-blocking version of `sync`. Hands the `state` back instead of waiting if the logic is not ready.
+    pub fn try_sync(&self, state: State) -> Result<(), State> {
+        let mut internal = self.lock();
+        if !internal.ready_for(Some(&state), None) {
+            internal.reject();
+            return Err(state);
+        }
+
+        internal.add(state);
+        Ok(())
+    }
+
+    fn lock(&self) -> MutexGuard<'_, LogicWrapper<Logic, State>> {
+        self.internal.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use crate::logic::QuotaPer;
+    use super::*;
+
+    #[test]
+    fn try_sync() {
+        let limiter = BlockingLimiter::new(QuotaPer::new(2, Duration::from_secs(60)));
+        assert!(limiter.try_sync(1).is_ok());
+        assert!(limiter.try_sync(1).is_ok());
+        assert!(limiter.try_sync(1).is_err());
+    }
+
+    #[test]
+    fn threads() {
+        let limiter: BlockingLimiter<QuotaPer<u64>, u64> = BlockingLimiter::new(QuotaPer::new(4, Duration::from_millis(50)));
+
+        let started = Instant::now();
+        let threads: Vec<_> = (0..4).map(|_| {
+            let limiter = limiter.clone();
+            std::thread::spawn(move || {
+                for _ in 0..4 {
+                    limiter.sync(1);
+                }
+            })
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        // 16 grants of 4 per 50ms, the last batch goes right after the third free
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(150), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(400), "{:?}", elapsed);
+    }
+}
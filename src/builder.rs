@@ -0,0 +1,179 @@
+use std::marker::PhantomData;
+use std::sync::{Arc, PoisonError};
+use std::time::Duration;
+use crate::clock::{Clock, SystemClock};
+use crate::rng::{AtomicRng, Rng};
+use crate::sleeper::{self, Sleeper};
+use crate::{logic, Event, EventCallback, Fairness, Limiter, PollStrategy, Shared};
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+use crate::ReclaimTask;
+
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+type SpawnReclaim<Logic, State> = Box<dyn FnOnce(std::sync::Weak<Shared<Logic, State>>, Arc<dyn Sleeper>) -> ReclaimTask>;
+
+/// Configures a `Limiter`, created with `Limiter::builder`.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use ratelim::Limiter;
+/// use ratelim::clock::SystemClock;
+/// use ratelim::logic::QuotaPer;
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let limiter = Limiter::builder(QuotaPer::new(5, Duration::from_secs(1)))
+///     .poll_timeout(Duration::from_millis(10))
+///     .on_event(|event| println!("{:?}", event))
+///     .clock(SystemClock)
+///     .build();
+///
+/// limiter.sync(1).await;
+/// # }
+/// ```
+pub struct LimiterBuilder<Logic: logic::Logic<State>, State> {
+    logic: Logic,
+    poll_strategy: PollStrategy,
+    fairness: Fairness,
+    priority_aging: Option<Duration>,
+    startup_jitter: Option<Duration>,
+    rng: Rng,
+    name: Option<Arc<str>>,
+    heap_capacity: usize,
+    clock: Arc<dyn Clock>,
+    sleeper: Arc<dyn Sleeper>,
+    on_event: Option<EventCallback>,
+    #[cfg(any(feature = "tokio", feature = "async-std"))]
+    reclaim: Option<SpawnReclaim<Logic, State>>,
+    _state: PhantomData<fn(State)>,
+}
+
+impl<Logic: logic::Logic<State>, State> LimiterBuilder<Logic, State> {
+    pub(crate) fn new(logic: Logic) -> LimiterBuilder<Logic, State> {
+        LimiterBuilder {
+            logic,
+            poll_strategy: PollStrategy::default(),
+            fairness: Fairness::Unordered,
+            priority_aging: None,
+            startup_jitter: None,
+            rng: Rng::from_entropy(),
+            name: None,
+            heap_capacity: 0,
+            clock: Arc::new(SystemClock),
+            sleeper: sleeper::default_sleeper(),
+            on_event: None,
+            #[cfg(any(feature = "tokio", feature = "async-std"))]
+            reclaim: None,
+            _state: PhantomData,
+        }
+    }
+
+    /// Fallback wakeup interval for waiters when no delayed free is scheduled, instead of the adaptive default.
+    /// Same as `poll_strategy(PollStrategy::Fixed(poll_timeout))`.
+    pub fn poll_timeout(self, poll_timeout: Duration) -> LimiterBuilder<Logic, State> {
+        self.poll_strategy(PollStrategy::Fixed(poll_timeout))
+    }
+
+    /// How waiters poll when no wakeup is known, `PollStrategy::Adaptive` with a 10ms cap by default.
+    pub fn poll_strategy(mut self, poll_strategy: PollStrategy) -> LimiterBuilder<Logic, State> {
+        self.poll_strategy = poll_strategy;
+        self
+    }
+
+    /// Order in which waiters are granted, `Fairness::Unordered` by default.
+    pub fn fairness(mut self, fairness: Fairness) -> LimiterBuilder<Logic, State> {
+        self.fairness = fairness;
+        self
+    }
+
+    /// Raises the priority of waiters in `Limiter::sync_with_priority` by one for every `aging` they have waited,
+    /// so low priorities are not starved forever. Off by default.
+    pub fn priority_aging(mut self, aging: Duration) -> LimiterBuilder<Logic, State> {
+        self.priority_aging = Some(aging);
+        self
+    }
+
+    /// Holds back the first grant by a random delay of up to `window`, so instances started together,
+    /// e.g. by a rollout, don't all hit the downstream at once and stay in step. Off by default.
+    pub fn startup_jitter(mut self, window: Duration) -> LimiterBuilder<Logic, State> {
+        self.startup_jitter = Some(window);
+        self
+    }
+
+    /// Makes the startup jitter and the contention jitter of the waiters reproducible.
+    pub fn rng_seed(mut self, seed: u64) -> LimiterBuilder<Logic, State> {
+        self.rng = Rng::new(seed);
+        self
+    }
+
+    /// Tells the limiter apart in `Debug` output, and as the `limiter` label of its metrics
+    /// with the `metrics` feature: the `ratelim.granted` and `ratelim.rejected` counters
+    /// and the `ratelim.wait_seconds` histogram.
+    pub fn name(mut self, name: impl Into<Arc<str>>) -> LimiterBuilder<Logic, State> {
+        self.name = Some(name.into());
+        self
+    }
+
+    /// Reserves room for `capacity` pending delayed frees up front, so bursts don't reallocate them on the
+    /// hot path. The most a logic ever has pending is a good fit, e.g. the quota of a `QuotaPer` with unit costs,
+    /// or the quota divided by the smallest cost. Nothing is reserved by default.
+    pub fn heap_capacity(mut self, capacity: usize) -> LimiterBuilder<Logic, State> {
+        self.heap_capacity = capacity;
+        self
+    }
+
+    /// See `Limiter::on_event`.
+    pub fn on_event(mut self, callback: impl Fn(Event) + Send + Sync + 'static) -> LimiterBuilder<Logic, State> {
+        self.on_event = Some(Arc::new(callback));
+        self
+    }
+
+    /// Clock the delayed frees are scheduled with, `SystemClock` by default.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> LimiterBuilder<Logic, State> {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Timer the waiters sleep with, see `Sleeper` for the default.
+    pub fn sleeper(mut self, sleeper: impl Sleeper + 'static) -> LimiterBuilder<Logic, State> {
+        self.sleeper = Arc::new(sleeper);
+        self
+    }
+
+    pub fn build(mut self) -> Limiter<Logic, State> {
+        let mut internal = Shared::new(self.logic, self.clock);
+        internal.wrapper.get_mut().name = self.name;
+        internal.wrapper.get_mut().delayed_frees.reserve(self.heap_capacity);
+        if let Some(window) = self.startup_jitter {
+            let wrapper = internal.wrapper.get_mut();
+            wrapper.not_before = Some(wrapper.clock.now() + window.mul_f64(self.rng.next_f64()));
+            wrapper.publish_readiness();
+        }
+        internal.rng = AtomicRng::new(self.rng.next_u64());
+        let internal = Arc::new(internal);
+        internal.queue.lock().unwrap_or_else(PoisonError::into_inner).set_aging(self.priority_aging);
+        #[cfg(any(feature = "tokio", feature = "async-std"))]
+        let reclaim = self.reclaim.map(|spawn| Arc::new(spawn(Arc::downgrade(&internal), self.sleeper.clone())));
+
+        Limiter {
+            internal,
+            poll_strategy: self.poll_strategy,
+            fairness: self.fairness,
+            sleeper: self.sleeper,
+            #[cfg(any(feature = "tokio", feature = "async-std"))]
+            reclaim,
+            on_event: self.on_event,
+        }
+    }
+}
+
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+impl<Logic: logic::Logic<State> + Send + 'static, State: Send + 'static> LimiterBuilder<Logic, State> {
+    /// Spawns a task reclaiming expired reservations every `interval`, so the logic is freed on schedule
+    /// even if nobody calls `sync`. The task is aborted once the last clone of the limiter is dropped.
+    pub fn background_reclaim(mut self, interval: Duration) -> LimiterBuilder<Logic, State> {
+        self.reclaim = Some(Box::new(move |internal, sleeper| ReclaimTask::spawn(internal, interval, sleeper)));
+        self
+    }
+}
@@ -0,0 +1,38 @@
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use crate::time::Instant;
+
+/// Source of time for the `Limiter`'s bookkeeping of delayed frees.
+/// Logics reading the time on their own keep using the system clock, unless they take a clock too, like `logic::Ewma`.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> Instant;
+}
+
+/// The default, `Instant::now()`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant { Instant::now() }
+}
+
+/// Clock that only moves when told to, for deterministic tests.
+/// Clones share the same time.
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    now: Arc<Mutex<Instant>>,
+}
+
+impl MockClock {
+    pub fn new() -> MockClock { MockClock { now: Arc::new(Mutex::new(Instant::now())) } }
+
+    pub fn advance(&self, by: Duration) { *self.now.lock().unwrap_or_else(PoisonError::into_inner) += by; }
+}
+
+impl Default for MockClock {
+    fn default() -> MockClock { MockClock::new() }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant { *self.now.lock().unwrap_or_else(PoisonError::into_inner) }
+}
@@ -0,0 +1,257 @@
+//! Shim with the names of `governor`, for migrating off it with few changes at the call sites.
+//!
+//! `Quota` and `RateLimiter` map onto a `Limiter` of a `TokenBucket` holding `burst_size` cells,
+//! one cell refilled every `replenish_interval`, the same admission as governor's GCRA for the same quota.
+//!
+//! Preserved:
+//!
+//! - `Quota::per_second`, `per_minute`, `per_hour`, `with_period` and `allow_burst`,
+//! - `check` and `check_n`, failing with `NotUntil` or, for more cells than the burst, `InsufficientCapacity`,
+//! - `until_ready` and `until_n_ready` waiting, then taking the cells,
+//! - `RateLimiter::keyed` with `check_key` and `until_key_ready`, an independent quota per key.
+//!
+//! Not preserved:
+//!
+//! - `check` and its variants are async, the limiter is behind an async lock, add `.await`,
+//! - `NotUntil` is estimated from the earliest refill scheduled by the limiter rather than computed
+//!   exactly like governor does from its theoretical arrival time, a retry right then may fail again,
+//! - the bucket counts in `f64`, so exact boundaries may differ by a rounding error,
+//! - there are no clocks, middlewares, jitter or state stores, use `Limiter` directly for those,
+//! - keys idle for as long as a whole burst takes to refill are also forgotten on access, not only by `retain_recent`.
+//!
+//! ## Example
+//!
+//! ```
+//! use std::num::NonZeroU32;
+//! use ratelim::compat::{Quota, RateLimiter};
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let limiter = RateLimiter::direct(Quota::per_second(NonZeroU32::new(50).unwrap()));
+//! limiter.until_ready().await;
+//! assert!(limiter.check().await.is_ok());
+//! # }
+//! ```
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::hash::Hash;
+use std::num::NonZeroU32;
+use std::time::Duration;
+use crate::keyed::KeyedLimiter;
+use crate::logic::TokenBucket;
+use crate::time::Instant;
+use crate::Limiter;
+
+/// Burst size and replenishment interval, like governor's `Quota`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    max_burst: NonZeroU32,
+    replenish_1_per: Duration,
+}
+
+impl Quota {
+    /// `max_burst` cells at once, refilled over a second.
+    pub fn per_second(max_burst: NonZeroU32) -> Quota { Quota::over(max_burst, Duration::from_secs(1)) }
+
+    /// `max_burst` cells at once, refilled over a minute.
+    pub fn per_minute(max_burst: NonZeroU32) -> Quota { Quota::over(max_burst, Duration::from_secs(60)) }
+
+    /// `max_burst` cells at once, refilled over an hour.
+    pub fn per_hour(max_burst: NonZeroU32) -> Quota { Quota::over(max_burst, Duration::from_secs(60 * 60)) }
+
+    /// One cell every `replenish_1_per`, with a burst of 1. `None` for a zero period.
+    pub fn with_period(replenish_1_per: Duration) -> Option<Quota> {
+        (!replenish_1_per.is_zero()).then_some(Quota { max_burst: NonZeroU32::MIN, replenish_1_per })
+    }
+
+    /// Same replenishment, but up to `max_burst` cells at once.
+    pub fn allow_burst(self, max_burst: NonZeroU32) -> Quota { Quota { max_burst, ..self } }
+
+    pub fn burst_size(&self) -> NonZeroU32 { self.max_burst }
+
+    pub fn replenish_interval(&self) -> Duration { self.replenish_1_per }
+
+    // at least a nanosecond, the bucket has to refill
+    fn over(max_burst: NonZeroU32, period: Duration) -> Quota {
+        Quota { max_burst, replenish_1_per: (period / max_burst.get()).max(Duration::from_nanos(1)) }
+    }
+
+    /// Time a whole burst takes to refill, a bucket idle for that long is full again.
+    fn refill(&self) -> Duration { self.replenish_1_per.saturating_mul(self.max_burst.get()) }
+
+    fn fits(&self, n: NonZeroU32) -> Result<(), InsufficientCapacity> {
+        if n > self.max_burst {
+            return Err(InsufficientCapacity(n.get()));
+        }
+        Ok(())
+    }
+}
+
+impl From<Quota> for TokenBucket {
+    fn from(quota: Quota) -> TokenBucket { TokenBucket::new(quota.max_burst.get().into(), 1, quota.replenish_1_per) }
+}
+
+/// Returned by `check` when the quota is used up, like governor's `NotUntil`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NotUntil {
+    earliest: Instant,
+}
+
+impl NotUntil {
+    /// When a retry is expected to succeed.
+    pub fn earliest_possible(&self) -> Instant { self.earliest }
+
+    /// Time until `earliest_possible` from `from`, zero if it has passed.
+    pub fn wait_time_from(&self, from: Instant) -> Duration { self.earliest.saturating_duration_since(from) }
+}
+
+impl Display for NotUntil {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "rate limited, retry in {:?}", self.wait_time_from(Instant::now()))
+    }
+}
+
+impl Error for NotUntil {}
+
+/// Returned by `check_n` for more cells than the burst size, they would never fit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InsufficientCapacity(pub u32);
+
+impl Display for InsufficientCapacity {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result { write!(f, "{} cells exceed the burst size of the quota", self.0) }
+}
+
+impl Error for InsufficientCapacity {}
+
+/// Direct rate limiter, like governor's `DefaultDirectRateLimiter`. Clones share the quota.
+#[derive(Clone)]
+pub struct RateLimiter {
+    limiter: Limiter<TokenBucket, u64>,
+    quota: Quota,
+}
+
+impl RateLimiter {
+    pub fn direct(quota: Quota) -> RateLimiter { RateLimiter { limiter: Limiter::new(quota.into()), quota } }
+
+    pub fn keyed<K: Hash + Eq + Clone>(quota: Quota) -> KeyedRateLimiter<K> {
+        KeyedRateLimiter { limiter: KeyedLimiter::new(move || quota.into()).with_idle_ttl(quota.refill()), quota }
+    }
+
+    /// Takes a cell if there is one.
+    pub async fn check(&self) -> Result<(), NotUntil> { check(&self.limiter, 1, self.quota).await }
+
+    /// Takes `n` cells if they are all there.
+    pub async fn check_n(&self, n: NonZeroU32) -> Result<Result<(), NotUntil>, InsufficientCapacity> {
+        self.quota.fits(n)?;
+        Ok(check(&self.limiter, n.get(), self.quota).await)
+    }
+
+    /// Waits until a cell is there, then takes it.
+    pub async fn until_ready(&self) { self.limiter.sync(1).await }
+
+    /// Waits until `n` cells are there, then takes them.
+    pub async fn until_n_ready(&self, n: NonZeroU32) -> Result<(), InsufficientCapacity> {
+        self.quota.fits(n)?;
+        self.limiter.sync(n.get().into()).await;
+        Ok(())
+    }
+
+    /// The underlying limiter.
+    pub fn limiter(&self) -> &Limiter<TokenBucket, u64> { &self.limiter }
+}
+
+/// Rate limiter with a quota per key, like governor's `DefaultKeyedRateLimiter`. Clones share the keys.
+#[derive(Clone)]
+pub struct KeyedRateLimiter<K> {
+    limiter: KeyedLimiter<K, TokenBucket, u64>,
+    quota: Quota,
+}
+
+impl<K: Hash + Eq + Clone> KeyedRateLimiter<K> {
+    /// Takes a cell of `key` if there is one.
+    pub async fn check_key(&self, key: &K) -> Result<(), NotUntil> {
+        check(&self.limiter.limiter(key.clone()), 1, self.quota).await
+    }
+
+    /// Takes `n` cells of `key` if they are all there.
+    pub async fn check_key_n(&self, key: &K, n: NonZeroU32) -> Result<Result<(), NotUntil>, InsufficientCapacity> {
+        self.quota.fits(n)?;
+        Ok(check(&self.limiter.limiter(key.clone()), n.get(), self.quota).await)
+    }
+
+    /// Waits until a cell of `key` is there, then takes it.
+    pub async fn until_key_ready(&self, key: &K) { self.limiter.sync(key.clone(), 1).await }
+
+    /// Waits until `n` cells of `key` are there, then takes them.
+    pub async fn until_key_n_ready(&self, key: &K, n: NonZeroU32) -> Result<(), InsufficientCapacity> {
+        self.quota.fits(n)?;
+        self.limiter.sync(key.clone(), n.get().into()).await;
+        Ok(())
+    }
+
+    /// Forgets the keys idle for as long as a whole burst takes to refill, they would start over full anyway.
+    pub fn retain_recent(&self) { self.limiter.evict_idle() }
+
+    /// Number of tracked keys.
+    pub fn len(&self) -> usize { self.limiter.len() }
+
+    pub fn is_empty(&self) -> bool { self.limiter.is_empty() }
+
+    /// The underlying keyed limiter.
+    pub fn limiter(&self) -> &KeyedLimiter<K, TokenBucket, u64> { &self.limiter }
+}
+
+async fn check(limiter: &Limiter<TokenBucket, u64>, n: u32, quota: Quota) -> Result<(), NotUntil> {
+    limiter.try_sync_info(n.into()).await.map_err(|rejected| NotUntil {
+        earliest: Instant::now() + rejected.retry_after.unwrap_or(quota.replenish_1_per),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nonzero(n: u32) -> NonZeroU32 { NonZeroU32::new(n).unwrap() }
+
+    #[test]
+    fn quotas() {
+        let quota = Quota::per_second(nonzero(50));
+        assert_eq!((quota.burst_size(), quota.replenish_interval()), (nonzero(50), Duration::from_millis(20)));
+        let quota = Quota::per_minute(nonzero(2)).allow_burst(nonzero(5));
+        assert_eq!((quota.burst_size(), quota.replenish_interval()), (nonzero(5), Duration::from_secs(30)));
+        assert_eq!(Quota::with_period(Duration::ZERO), None);
+        // more cells per second than nanoseconds
+        assert_eq!(Quota::per_second(NonZeroU32::MAX).replenish_interval(), Duration::from_nanos(1));
+        drop(RateLimiter::direct(Quota::per_second(NonZeroU32::MAX)));
+    }
+
+    // the usual governor setup: a steady rate with a small burst, checked per request
+    #[tokio::test]
+    async fn direct() {
+        let limiter = RateLimiter::direct(Quota::per_second(nonzero(50)).allow_burst(nonzero(5)));
+        for _ in 0..5 {
+            assert!(limiter.check().await.is_ok());
+        }
+        let not_until = limiter.check().await.unwrap_err();
+        let wait = not_until.wait_time_from(Instant::now());
+        assert!(wait > Duration::from_millis(10) && wait <= Duration::from_millis(20), "{:?}", wait);
+
+        let started = Instant::now();
+        limiter.until_ready().await;
+        assert!(started.elapsed() >= Duration::from_millis(10), "{:?}", started.elapsed());
+
+        assert_eq!(limiter.check_n(nonzero(6)).await, Err(InsufficientCapacity(6)));
+        // only a cell or so refilled by now
+        assert!(limiter.check_n(nonzero(3)).await.unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn keyed() {
+        let limiter = RateLimiter::keyed(Quota::per_hour(nonzero(1)));
+        assert!(limiter.check_key(&"alice").await.is_ok());
+        assert!(limiter.check_key(&"alice").await.is_err());
+        assert!(limiter.check_key(&"bob").await.is_ok());
+        assert_eq!(limiter.len(), 2);
+    }
+}
@@ -0,0 +1,245 @@
+use std::collections::{BTreeSet, HashMap};
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use crate::{logic, Event, Limiter};
+
+/// Virtual time a grant of weight 1 advances its class by, finer weights get a fraction of it.
+const UNIT: u64 = 1 << 32;
+
+/// Shares one `Limiter` between classes of requests, e.g. "free" and "paid", in proportion to their weights
+/// rather than in arrival order. The class of a request is taken from its `State` by `class_of`,
+/// classes without a `with_weight` weigh 1.
+///
+/// Virtual-time fair queueing: every waiter is tagged with its class' virtual finish time, advanced by
+/// `1 / weight` per grant, and the smallest tag is granted next. A class that falls behind is tagged from
+/// the current virtual time on, so it neither saves up a share while idle nor is blocked by another class
+/// that queued up a backlog. Ties go to the earlier arrival.
+///
+/// The limiter itself should be `Fairness::Unordered`, callers going through it directly
+/// compete with whoever is first here.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use ratelim::Limiter;
+/// use ratelim::fair::WeightedFair;
+/// use ratelim::logic::QuotaPer;
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// // single requests get three grants for every bulk one while both compete
+/// let limiter = WeightedFair::new(Limiter::new(QuotaPer::new(100, Duration::from_secs(1))), |cost: &u64| *cost > 1)
+///     .with_weight(false, 3);
+/// limiter.sync(1).await;
+/// limiter.sync(10).await;
+/// # }
+/// ```
+pub struct WeightedFair<Logic: logic::Logic<State>, State, C, F> {
+    limiter: Limiter<Logic, State>,
+    class_of: Arc<F>,
+    queue: Arc<Mutex<FairQueue<C>>>,
+}
+
+impl<Logic: logic::Logic<State>, State, C, F> Clone for WeightedFair<Logic, State, C, F> {
+    fn clone(&self) -> WeightedFair<Logic, State, C, F> {
+        WeightedFair {
+            limiter: self.limiter.clone(),
+            class_of: self.class_of.clone(),
+            queue: self.queue.clone(),
+        }
+    }
+}
+
+struct FairQueue<C> {
+    weights: HashMap<C, u32>,
+    // virtual finish time of the last grant, and of the last tag handed out per class
+    virtual_time: u64,
+    finish: HashMap<C, u64>,
+    // waiters by tag, then arrival
+    waiting: BTreeSet<(u64, u64)>,
+    next: u64,
+}
+
+impl<C: Hash + Eq> FairQueue<C> {
+    /// Tags the next grant of `class`.
+    fn charge(&mut self, class: C) -> u64 {
+        let step = UNIT / self.weights.get(&class).copied().unwrap_or(1) as u64;
+        let finish = self.finish.entry(class).or_insert(0);
+        *finish = (*finish).max(self.virtual_time) + step;
+        *finish
+    }
+
+    fn push(&mut self, class: C) -> (u64, u64) {
+        let waiter = (self.charge(class), self.next);
+        self.next += 1;
+        self.waiting.insert(waiter);
+        waiter
+    }
+}
+
+impl<Logic, State, C, F> WeightedFair<Logic, State, C, F>
+where
+    Logic: logic::Logic<State>,
+    C: Hash + Eq,
+    F: Fn(&State) -> C,
+{
+    pub fn new(limiter: Limiter<Logic, State>, class_of: F) -> WeightedFair<Logic, State, C, F> {
+        WeightedFair {
+            limiter,
+            class_of: Arc::new(class_of),
+            queue: Arc::new(Mutex::new(FairQueue {
+                weights: HashMap::new(),
+                virtual_time: 0,
+                finish: HashMap::new(),
+                waiting: BTreeSet::new(),
+                next: 0,
+            })),
+        }
+    }
+
+    /// Share of `class` relative to the others, a weight of 0 counts as 1.
+    pub fn with_weight(self, class: C, weight: u32) -> WeightedFair<Logic, State, C, F> {
+        self.lock().weights.insert(class, weight.max(1));
+        self
+    }
+
+    /// Waits until every waiter tagged before this one is granted and the logic is ready for `state`, then reserves it.
+    /// Like `Limiter::sync`, never returns once the limiter is shut down.
+    pub async fn sync(&self, mut state: State) {
+        let waiter = FairWaiter::new(self, (self.class_of)(&state));
+        let (pending, waiter_ref) = (&mut state, &waiter);
+        // `ready` reclaims the due frees, so it runs even if it's not this waiter's turn,
+        // or the waiter would be woken by the same overdue free over and over
+        let internal = self.limiter.wait_until(None, None, move |internal| internal.ready_for(Some(pending), None) && waiter_ref.is_first()).await;
+        let Some(mut internal) = internal else {
+            // out of the queue first, so it doesn't hold up the others
+            drop(waiter);
+            return std::future::pending().await;
+        };
+        internal.add(state);
+        self.lock().virtual_time = waiter.tag.0;
+        self.limiter.unlock(internal, Event::Granted);
+    }
+
+    /// Non-blocking version of `sync`. Hands the `state` back unless nobody is waiting and the logic is ready.
+    pub async fn try_sync(&self, state: State) -> Result<(), State> {
+        let mut internal = self.limiter.internal.lock().await;
+        if !self.lock().waiting.is_empty() || !internal.ready_for(Some(&state), None) {
+            internal.reject();
+            self.limiter.unlock(internal, Event::Rejected);
+            return Err(state);
+        }
+
+        let mut queue = self.lock();
+        queue.virtual_time = queue.charge((self.class_of)(&state));
+        drop(queue);
+        internal.add(state);
+        self.limiter.unlock(internal, Event::Granted);
+        Ok(())
+    }
+
+    pub fn limiter(&self) -> &Limiter<Logic, State> { &self.limiter }
+
+    fn lock(&self) -> MutexGuard<'_, FairQueue<C>> { self.queue.lock().unwrap_or_else(PoisonError::into_inner) }
+}
+
+/// A waiter of `WeightedFair::sync`, leaves the queue once granted or given up on.
+struct FairWaiter<'a, Logic: logic::Logic<State>, State, C, F> {
+    fair: &'a WeightedFair<Logic, State, C, F>,
+    tag: (u64, u64),
+}
+
+impl<'a, Logic, State, C, F> FairWaiter<'a, Logic, State, C, F>
+where
+    Logic: logic::Logic<State>,
+    C: Hash + Eq,
+    F: Fn(&State) -> C,
+{
+    fn new(fair: &'a WeightedFair<Logic, State, C, F>, class: C) -> FairWaiter<'a, Logic, State, C, F> {
+        let tag = fair.lock().push(class);
+        FairWaiter { fair, tag }
+    }
+
+    fn is_first(&self) -> bool { self.fair.lock().waiting.first() == Some(&self.tag) }
+}
+
+impl<Logic: logic::Logic<State>, State, C, F> Drop for FairWaiter<'_, Logic, State, C, F> {
+    fn drop(&mut self) {
+        let mut queue = self.fair.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        let was_first = queue.waiting.first() == Some(&self.tag);
+        queue.waiting.remove(&self.tag);
+        drop(queue);
+
+        if was_first {
+            // it's the next one's turn
+            self.fair.limiter.internal.notify.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::time::Duration;
+    use crate::logic::{FnLogic, QuotaPer};
+    use super::*;
+
+    #[tokio::test]
+    async fn shares_follow_weights() {
+        // one grant at a time, every 2ms
+        let busy = Arc::new(AtomicBool::new(false));
+        let (ready, add, free) = (busy.clone(), busy.clone(), busy);
+        let logic = FnLogic::new(
+            move || !ready.load(Ordering::Relaxed),
+            move |_: &&str| {
+                add.store(true, Ordering::Relaxed);
+                Duration::from_millis(2)
+            },
+            move |_: &&str| free.store(false, Ordering::Relaxed),
+        );
+        let limiter = WeightedFair::new(Limiter::new(logic), |class: &&str| *class).with_weight("paid", 3);
+        let granted = Arc::new(Mutex::new(Vec::new()));
+
+        // the free class keeps more waiters queued, it still only gets its share
+        let tasks: Vec<_> = [("free", 6), ("paid", 2)].into_iter()
+            .flat_map(|(class, waiters)| std::iter::repeat_n(class, waiters))
+            .map(|class| {
+                let (limiter, granted) = (limiter.clone(), granted.clone());
+                tokio::spawn(async move {
+                    while granted.lock().unwrap().len() < 120 {
+                        limiter.sync(class).await;
+                        granted.lock().unwrap().push(class);
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // the first grants go before all waiters are queued
+        let granted = granted.lock().unwrap();
+        let paid = granted[20..100].iter().filter(|class| **class == "paid").count();
+        assert!((52..=68).contains(&paid), "{paid} of 80");
+    }
+
+    #[tokio::test]
+    async fn try_sync() {
+        let limiter = WeightedFair::new(Limiter::new(QuotaPer::new(1, Duration::from_millis(50))), |_: &u64| ());
+        assert!(limiter.try_sync(1).await.is_ok());
+        assert_eq!(limiter.try_sync(1).await, Err(1));
+
+        limiter.sync(1).await;
+        assert_eq!(limiter.limiter().stats().await.granted, 2);
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        let limiter = WeightedFair::new(Limiter::new(QuotaPer::new(1, Duration::from_millis(10))), |_: &u64| ());
+        limiter.limiter().shutdown().await;
+        // reserves nothing, so it doesn't return either
+        assert!(tokio::time::timeout(Duration::from_millis(20), limiter.sync(1)).await.is_err());
+        assert!(limiter.lock().waiting.is_empty());
+    }
+}
@@ -0,0 +1,193 @@
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use crate::{logic, Limiter};
+
+type Ready = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Caps the bandwidth of `inner`, every byte read or written consumes a unit of a `Limiter<L, u64>`,
+/// e.g. a `TokenBucket` refilling at the allowed bytes per second.
+///
+/// Bytes are reserved in chunks of at most `chunk_size` (8 KiB unless set by `with_chunk_size`)
+/// before the IO happens, what the inner IO leaves unused is kept for the next call.
+/// A chunk larger than the logic's capacity overdraws it, so the pace is only right in the long run.
+///
+/// Reads and writes share the limiter, wrap the halves of a split stream for separate caps.
+pub struct ThrottledIo<T, L: logic::Logic<u64>> {
+    inner: T,
+    limiter: Limiter<L, u64>,
+    chunk_size: u64,
+    read: Direction,
+    write: Direction,
+}
+
+#[derive(Default)]
+struct Direction {
+    // bytes reserved but not transferred yet
+    credit: u64,
+    // wakes the task once the limiter may be ready again
+    ready: Option<Ready>,
+}
+
+impl<T, L: logic::Logic<u64>> ThrottledIo<T, L> {
+    pub fn new(inner: T, limiter: Limiter<L, u64>) -> ThrottledIo<T, L> {
+        ThrottledIo {
+            inner,
+            limiter,
+            chunk_size: 8 * 1024,
+            read: Direction::default(),
+            write: Direction::default(),
+        }
+    }
+
+    pub fn with_chunk_size(mut self, chunk_size: u64) -> ThrottledIo<T, L> {
+        self.chunk_size = chunk_size.max(1);
+        self
+    }
+
+    pub fn get_ref(&self) -> &T { &self.inner }
+
+    pub fn get_mut(&mut self) -> &mut T { &mut self.inner }
+
+    pub fn into_inner(self) -> T { self.inner }
+}
+
+impl Direction {
+    /// Bytes that may be transferred now, reserving a new chunk of up to `wanted` bytes if there is no credit left.
+    /// Pending until the limiter grants, woken like any other waiter of the limiter.
+    fn poll_credit<L>(&mut self, cx: &mut Context<'_>, limiter: &Limiter<L, u64>, wanted: u64) -> Poll<u64>
+    where
+        L: logic::Logic<u64> + Send + 'static,
+    {
+        loop {
+            if self.credit > 0 {
+                return Poll::Ready(self.credit.min(wanted));
+            }
+
+            if let Some(ready) = &mut self.ready {
+                ready!(ready.as_mut().poll(cx));
+                self.ready = None;
+            }
+
+            if limiter.try_sync_now(wanted).is_ok() {
+                self.credit = wanted;
+            } else {
+                let limiter = limiter.clone();
+                // ready as it is may still be too little for the chunk
+                self.ready = Some(Box::pin(async move { limiter.until_ready_for(wanted).await }));
+            }
+        }
+    }
+}
+
+impl<T: AsyncRead + Unpin, L: logic::Logic<u64> + Send + 'static> AsyncRead for ThrottledIo<T, L> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if buf.remaining() == 0 {
+            return Poll::Ready(Ok(()));
+        }
+
+        let this = &mut *self;
+        let wanted = (buf.remaining() as u64).min(this.chunk_size);
+        let allowed = ready!(this.read.poll_credit(cx, &this.limiter, wanted)) as usize;
+
+        let mut limited = buf.take(allowed);
+        ready!(Pin::new(&mut this.inner).poll_read(cx, &mut limited))?;
+        let read = limited.filled().len();
+
+        // SAFETY: `limited` is a view of the unfilled part of `buf`, the inner read just initialized its first `read` bytes
+        unsafe { buf.assume_init(read) };
+        buf.advance(read);
+        this.read.credit -= read as u64;
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T: AsyncWrite + Unpin, L: logic::Logic<u64> + Send + 'static> AsyncWrite for ThrottledIo<T, L> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+
+        let this = &mut *self;
+        let wanted = (buf.len() as u64).min(this.chunk_size);
+        let allowed = ready!(this.write.poll_credit(cx, &this.limiter, wanted)) as usize;
+
+        let written = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed]))?;
+        this.write.credit -= written as u64;
+
+        Poll::Ready(Ok(written))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use crate::logic::{QuotaPer, TokenBucket};
+    use super::*;
+
+    #[tokio::test]
+    async fn write_rate() {
+        // 1000 bytes of burst, then 10 KB/s
+        let limiter = Limiter::new(TokenBucket::new(1000, 10_000, Duration::from_secs(1)));
+        let mut io = ThrottledIo::new(tokio::io::sink(), limiter).with_chunk_size(500);
+
+        let started = Instant::now();
+        io.write_all(&[0; 5000]).await.unwrap();
+        let elapsed = started.elapsed();
+
+        // the burst covers the first 1000 bytes, the last chunk is reserved before it refills
+        assert!(elapsed >= Duration::from_millis(300), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(600), "{:?}", elapsed);
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn waits_for_the_whole_chunk() {
+        // 60 bytes left after a chunk, not enough for the next one
+        let limiter = Limiter::new(QuotaPer::new(100, Duration::from_millis(50)));
+        let mut io = ThrottledIo::new(tokio::io::sink(), limiter).with_chunk_size(60);
+        let ticks = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let ticker = tokio::spawn({
+            let ticks = ticks.clone();
+            async move {
+                loop {
+                    tokio::time::sleep(Duration::from_millis(5)).await;
+                    ticks.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+                }
+            }
+        });
+
+        let started = Instant::now();
+        io.write_all(&[0; 240]).await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(140), "{:?}", started.elapsed());
+        // the writer sleeps meanwhile rather than spinning on the only thread
+        assert!(ticks.load(std::sync::atomic::Ordering::Relaxed) >= 10, "{:?}", ticks);
+        ticker.abort();
+    }
+
+    #[tokio::test]
+    async fn read_rate() {
+        let limiter = Limiter::new(TokenBucket::new(100, 1000, Duration::from_secs(1)));
+        let mut io = ThrottledIo::new(&[7u8; 300][..], limiter).with_chunk_size(50);
+
+        let started = Instant::now();
+        let mut read = Vec::new();
+        io.read_to_end(&mut read).await.unwrap();
+        let elapsed = started.elapsed();
+
+        assert_eq!(read, vec![7; 300]);
+        assert!(elapsed >= Duration::from_millis(150), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(400), "{:?}", elapsed);
+    }
+}
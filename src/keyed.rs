@@ -0,0 +1,546 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{HashMap, VecDeque};
+use std::hash::{BuildHasher, Hash};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+use crate::time::Instant;
+use crate::{logic, Event, Limiter};
+
+/// Keeps an independent `Limiter` per key, e.g. per API key or client IP.
+/// Every new key gets a fresh logic from the `factory`.
+///
+/// Keys are spread across shards, each behind its own lock, so unrelated keys rarely contend.
+/// The shard lock is only held to look the limiter up, never while waiting on it.
+///
+/// Keys stay tracked forever unless an idle TTL is set with `with_idle_ttl`, or the number of keys
+/// is bounded with `with_capacity`.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use ratelim::keyed::KeyedLimiter;
+/// use ratelim::logic::QuotaPer;
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let limiter = KeyedLimiter::new(|| QuotaPer::new(5, Duration::from_secs(1)));
+/// limiter.sync("10.0.0.1", 1).await;
+/// assert!(limiter.try_sync("10.0.0.2", 1).await.is_ok());
+/// # }
+/// ```
+pub struct KeyedLimiter<K, Logic: logic::Logic<State>, State> {
+    shards: Arc<[Mutex<Shard<K, Logic, State>>]>,
+    factory: Arc<dyn Fn() -> Logic + Send + Sync>,
+    hasher: RandomState,
+    idle_ttl: Option<Duration>,
+}
+
+struct Shard<K, Logic: logic::Logic<State>, State> {
+    entries: HashMap<K, Entry<Logic, State>>,
+    last_sweep: Instant,
+    max_keys: Option<usize>,
+}
+
+struct Entry<Logic: logic::Logic<State>, State> {
+    limiter: Limiter<Logic, State>,
+    touched: Instant,
+}
+
+impl<K, Logic: logic::Logic<State>, State> Shard<K, Logic, State> {
+    /// Drops the entries untouched for `idle_ttl` that hold no reservations, so they are safe to forget.
+    fn evict_idle(&mut self, idle_ttl: Duration, now: Instant) {
+        self.entries.retain(|_, entry| now.duration_since(entry.touched) < idle_ttl || !entry.limiter.is_idle());
+        self.last_sweep = now;
+    }
+
+    /// Drops the least recently used entry holding no reservations, if there is one.
+    fn evict_lru(&mut self) where K: Hash + Eq + Clone {
+        let lru = self.entries.iter()
+            .filter(|(_, entry)| entry.limiter.is_idle())
+            .min_by_key(|(_, entry)| entry.touched)
+            .map(|(key, _)| key.clone());
+
+        if let Some(key) = lru {
+            self.entries.remove(&key);
+        }
+    }
+}
+
+impl<K, Logic: logic::Logic<State>, State> Clone for KeyedLimiter<K, Logic, State> {
+    fn clone(&self) -> KeyedLimiter<K, Logic, State> {
+        KeyedLimiter {
+            shards: self.shards.clone(),
+            factory: self.factory.clone(),
+            hasher: self.hasher.clone(),
+            idle_ttl: self.idle_ttl,
+        }
+    }
+}
+
+impl<K: Hash + Eq + Clone, Logic: logic::Logic<State>, State> KeyedLimiter<K, Logic, State> {
+    pub fn new(factory: impl Fn() -> Logic + Send + Sync + 'static) -> KeyedLimiter<K, Logic, State> {
+        KeyedLimiter::with_shards(factory, 16)
+    }
+
+    pub fn with_shards(factory: impl Fn() -> Logic + Send + Sync + 'static, shards: usize) -> KeyedLimiter<K, Logic, State> {
+        KeyedLimiter {
+            shards: (0..shards.max(1)).map(|_| Mutex::new(Shard { entries: HashMap::new(), last_sweep: Instant::now(), max_keys: None })).collect(),
+            factory: Arc::new(factory),
+            hasher: RandomState::new(),
+            idle_ttl: None,
+        }
+    }
+
+    /// Same as `new`, but tracks at most `max_keys` keys, see `with_max_keys`.
+    pub fn with_capacity(factory: impl Fn() -> Logic + Send + Sync + 'static, max_keys: usize) -> KeyedLimiter<K, Logic, State> {
+        KeyedLimiter::with_shards(factory, max_keys.clamp(1, 16)).with_max_keys(max_keys)
+    }
+
+    /// Bounds the number of tracked keys, evicting the least recently used idle key to make room for a new one.
+    /// The bound is split evenly across the shards and the eviction order is LRU within a shard.
+    ///
+    /// Keys with outstanding reservations are never evicted, if every key in the shard is busy,
+    /// the new key is tracked anyway and the bound is temporarily exceeded.
+    pub fn with_max_keys(self, max_keys: usize) -> KeyedLimiter<K, Logic, State> {
+        let shards = self.shards.len();
+        for (i, shard) in self.shards.iter().enumerate() {
+            let max_keys = max_keys / shards + usize::from(i < max_keys % shards);
+            shard.lock().unwrap_or_else(PoisonError::into_inner).max_keys = Some(max_keys.max(1));
+        }
+        self
+    }
+
+    /// Evicts keys untouched for longer than `idle_ttl`. Keys with outstanding reservations are kept
+    /// until those are freed, an evicted key comes back with fresh capacity.
+    /// Shards are swept on access, at most once per `idle_ttl`, or explicitly with `evict_idle`.
+    ///
+    /// That is all the capacity the key had left for logics recovering it with the last free, like `QuotaPer`.
+    /// Logics still recovering after it come back with more than they had, e.g. a `Gcra` whose theoretical
+    /// arrival time is still ahead, or a `SlidingWindowCounter` still counting the previous window,
+    /// so `idle_ttl` should cover that recovery too.
+    pub fn with_idle_ttl(mut self, idle_ttl: Duration) -> KeyedLimiter<K, Logic, State> {
+        self.idle_ttl = Some(idle_ttl);
+        self
+    }
+
+    pub async fn sync(&self, key: K, state: State) { self.limiter(key).sync(state).await }
+
+    /// Non-blocking version of `sync`. Hands the `state` back instead of waiting if the key's logic is not ready.
+    pub async fn try_sync(&self, key: K, state: State) -> Result<(), State> { self.limiter(key).try_sync(state).await }
+
+    /// The limiter of `key`, created on first use.
+    pub fn limiter(&self, key: K) -> Limiter<Logic, State> {
+        let now = Instant::now();
+        let shard = &self.shards[self.hasher.hash_one(&key) as usize % self.shards.len()];
+        let mut shard = shard.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if let Some(idle_ttl) = self.idle_ttl {
+            if now.duration_since(shard.last_sweep) >= idle_ttl {
+                shard.evict_idle(idle_ttl, now);
+            }
+        }
+
+        if shard.max_keys.is_some_and(|max_keys| shard.entries.len() >= max_keys) && !shard.entries.contains_key(&key) {
+            shard.evict_lru();
+        }
+
+        let entry = shard.entries.entry(key).or_insert_with(|| Entry {
+            limiter: Limiter::new((self.factory)()),
+            touched: now,
+        });
+        entry.touched = now;
+        entry.limiter.clone()
+    }
+
+    /// Sweeps every shard for idle keys, see `with_idle_ttl`. Does nothing without a TTL.
+    pub fn evict_idle(&self) {
+        let Some(idle_ttl) = self.idle_ttl else { return };
+        let now = Instant::now();
+        for shard in self.shards.iter() {
+            shard.lock().unwrap_or_else(PoisonError::into_inner).evict_idle(idle_ttl, now);
+        }
+    }
+
+    /// Whether `key` is currently tracked.
+    pub fn contains(&self, key: &K) -> bool {
+        let shard = &self.shards[self.hasher.hash_one(key) as usize % self.shards.len()];
+        shard.lock().unwrap_or_else(PoisonError::into_inner).entries.contains_key(key)
+    }
+
+    /// Number of keys currently tracked.
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|shard| shard.lock().unwrap_or_else(PoisonError::into_inner).entries.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+}
+
+/// Per-key limits under a global one, e.g. 100/min per API key, but at most 10k/min for the whole service.
+/// A request is granted only if both the `global` limiter and the key's limiter admit it,
+/// and reserves the same `State` in both, each freeing it on its own schedule.
+///
+/// Both logics are checked under both locks before anything is reserved, so there is nothing to roll back:
+/// a request rejected by one never takes capacity from the other, e.g. a greedy key stuck at its own limit
+/// doesn't drain the global one.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use ratelim::Limiter;
+/// use ratelim::keyed::{Hierarchical, KeyedLimiter};
+/// use ratelim::logic::QuotaPer;
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let limiter = Hierarchical::new(
+///     Limiter::new(QuotaPer::new(10_000, Duration::from_secs(60))),
+///     KeyedLimiter::new(|| QuotaPer::new(100, Duration::from_secs(60))),
+/// );
+/// limiter.sync("api-key", 1).await;
+/// # }
+/// ```
+pub struct Hierarchical<K, Logic: logic::Logic<State>, Global: logic::Logic<State>, State> {
+    global: Limiter<Global, State>,
+    keyed: KeyedLimiter<K, Logic, State>,
+    // see `with_round_robin`
+    turns: Option<Arc<Mutex<Turns<K>>>>,
+}
+
+impl<K, Logic: logic::Logic<State>, Global: logic::Logic<State>, State> Clone for Hierarchical<K, Logic, Global, State> {
+    fn clone(&self) -> Hierarchical<K, Logic, Global, State> {
+        Hierarchical {
+            global: self.global.clone(),
+            keyed: self.keyed.clone(),
+            turns: self.turns.clone(),
+        }
+    }
+}
+
+/// Keys with waiters for the global capacity, in turn order, see `Hierarchical::with_round_robin`.
+struct Turns<K> {
+    ring: VecDeque<K>,
+    waiters: HashMap<K, usize>,
+}
+
+impl<K: Hash + Eq + Clone> Turns<K> {
+    fn join(&mut self, key: &K) {
+        let waiters = self.waiters.entry(key.clone()).or_insert(0);
+        if *waiters == 0 {
+            self.ring.push_back(key.clone());
+        }
+        *waiters += 1;
+    }
+
+    /// Removes a waiter of `key`, which goes last if it was `granted` and has more waiters.
+    /// Returns whether the turn moved on.
+    fn leave(&mut self, key: &K, granted: bool) -> bool {
+        let was_turn = self.is_turn(key);
+        let Some(waiters) = self.waiters.get_mut(key) else { return false };
+        *waiters -= 1;
+
+        if *waiters == 0 {
+            self.waiters.remove(key);
+            self.ring.retain(|queued| queued != key);
+        } else if granted && was_turn {
+            self.ring.rotate_left(1);
+        }
+        was_turn && !self.is_turn(key)
+    }
+
+    fn is_turn(&self, key: &K) -> bool { self.ring.front() == Some(key) }
+}
+
+impl<K: Hash + Eq + Clone, Logic: logic::Logic<State>, Global: logic::Logic<State>, State: Clone> Hierarchical<K, Logic, Global, State> {
+    pub fn new(global: Limiter<Global, State>, keyed: KeyedLimiter<K, Logic, State>) -> Hierarchical<K, Logic, Global, State> {
+        Hierarchical { global, keyed, turns: None }
+    }
+
+    /// Hands the global capacity to the keys with waiters in turn, rather than to whichever waiter
+    /// locks first, so under global saturation a key flooding with waiters doesn't starve the others.
+    /// Every key gets a grant per round, however many waiters it has.
+    ///
+    /// Only waiters whose key's own logic admits them take part, a key at its own limit doesn't hold up the round.
+    pub fn with_round_robin(mut self) -> Hierarchical<K, Logic, Global, State> {
+        self.turns = Some(Arc::new(Mutex::new(Turns { ring: VecDeque::new(), waiters: HashMap::new() })));
+        self
+    }
+
+    /// Waits until both the global and the key's logic admit `state`, then reserves it in both.
+    /// Like `Limiter::sync`, never returns once either limiter is shut down.
+    pub async fn sync(&self, key: K, mut state: State) {
+        if let Some(turns) = &self.turns {
+            return self.sync_in_turn(turns, key, state).await;
+        }
+
+        let limiter = self.keyed.limiter(key);
+        loop {
+            // either may be taken by someone else before both locks are held, so it's checked again
+            if limiter.wait_ready(None, Some(&mut state)).await.is_none() || self.global.wait_ready(None, Some(&mut state)).await.is_none() {
+                return std::future::pending().await;
+            }
+            // the retries of a wait aren't rejections
+            match try_both(&self.global, &limiter, state, false).await {
+                Ok(()) => return,
+                Err(rejected) => state = rejected,
+            }
+        }
+    }
+
+    async fn sync_in_turn(&self, turns: &Arc<Mutex<Turns<K>>>, key: K, mut state: State) {
+        let limiter = self.keyed.limiter(key.clone());
+        loop {
+            if limiter.wait_ready(None, Some(&mut state)).await.is_none() {
+                return std::future::pending().await;
+            }
+
+            let turn = Turn::new(self, turns, key.clone());
+            // `ready` reclaims the due frees, so it runs even if it's not this key's turn, see `WeightedFair::sync`
+            let (pending, turn_ref) = (&mut state, &turn);
+            let Some(mut global_internal) = self.global.wait_until(None, None, move |internal| internal.ready_for(Some(pending), None) && turn_ref.is_turn()).await else {
+                // out of the turns first, so the other keys go on
+                drop(turn);
+                return std::future::pending().await;
+            };
+
+            // taken by another waiter of the same key meanwhile, it waits for its own logic again
+            let mut internal = limiter.internal.lock().await;
+            if !internal.ready_for(Some(&state), None) {
+                drop(internal);
+                drop(global_internal);
+                continue;
+            }
+
+            global_internal.add(state.clone());
+            internal.add(state);
+            turn.granted();
+            limiter.unlock(internal, Event::Granted);
+            self.global.unlock(global_internal, Event::Granted);
+            return;
+        }
+    }
+
+    /// Non-blocking version of `sync`. Hands the `state` back, with nothing reserved, unless both admit it right now.
+    pub async fn try_sync(&self, key: K, state: State) -> Result<(), State> {
+        let limiter = self.keyed.limiter(key);
+        try_both(&self.global, &limiter, state, true).await
+    }
+
+    pub fn global(&self) -> &Limiter<Global, State> { &self.global }
+
+    pub fn keyed(&self) -> &KeyedLimiter<K, Logic, State> { &self.keyed }
+}
+
+/// A waiter of `Hierarchical::sync_in_turn`, leaves the turns once granted or given up on.
+struct Turn<'a, K: Hash + Eq + Clone, Logic: logic::Logic<State>, Global: logic::Logic<State>, State> {
+    hierarchical: &'a Hierarchical<K, Logic, Global, State>,
+    turns: &'a Mutex<Turns<K>>,
+    key: K,
+    granted: bool,
+}
+
+impl<'a, K: Hash + Eq + Clone, Logic: logic::Logic<State>, Global: logic::Logic<State>, State> Turn<'a, K, Logic, Global, State> {
+    fn new(hierarchical: &'a Hierarchical<K, Logic, Global, State>, turns: &'a Mutex<Turns<K>>, key: K) -> Turn<'a, K, Logic, Global, State> {
+        turns.lock().unwrap_or_else(PoisonError::into_inner).join(&key);
+        Turn { hierarchical, turns, key, granted: false }
+    }
+
+    fn is_turn(&self) -> bool { self.turns.lock().unwrap_or_else(PoisonError::into_inner).is_turn(&self.key) }
+
+    fn granted(mut self) { self.granted = true; }
+}
+
+impl<K: Hash + Eq + Clone, Logic: logic::Logic<State>, Global: logic::Logic<State>, State> Drop for Turn<'_, K, Logic, Global, State> {
+    fn drop(&mut self) {
+        let moved_on = self.turns.lock().unwrap_or_else(PoisonError::into_inner).leave(&self.key, self.granted);
+        if moved_on {
+            // it's the next key's turn
+            self.hierarchical.global.internal.notify.notify_waiters();
+        }
+    }
+}
+
+// always locks the global limiter first, so two of these never deadlock
+async fn try_both<Logic: logic::Logic<State>, Global: logic::Logic<State>, State: Clone>(
+    global: &Limiter<Global, State>,
+    limiter: &Limiter<Logic, State>,
+    state: State,
+    count_rejected: bool,
+) -> Result<(), State> {
+    let mut global_internal = global.internal.lock().await;
+    let mut internal = limiter.internal.lock().await;
+
+    let (global_ready, ready) = (global_internal.ready_for(Some(&state), None), internal.ready_for(Some(&state), None));
+    if !(global_ready && ready) {
+        if !count_rejected {
+            return Err(state);
+        }
+        // only the one turning the request away counts it as rejected
+        if !ready {
+            internal.reject();
+            limiter.unlock(internal, Event::Rejected);
+        }
+        if !global_ready {
+            global_internal.reject();
+            global.unlock(global_internal, Event::Rejected);
+        }
+        return Err(state);
+    }
+
+    global_internal.add(state.clone());
+    internal.add(state);
+    limiter.unlock(internal, Event::Granted);
+    global.unlock(global_internal, Event::Granted);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logic::QuotaPer;
+    use super::*;
+
+    #[tokio::test]
+    async fn independent_keys() {
+        let limiter = KeyedLimiter::new(|| QuotaPer::new(2, Duration::from_secs(60)));
+
+        // saturate the first key...
+        assert!(limiter.try_sync(0, 1).await.is_ok());
+        assert!(limiter.try_sync(0, 1).await.is_ok());
+        assert!(limiter.try_sync(0, 1).await.is_err());
+
+        // ...the others don't notice
+        for key in 1..100 {
+            assert!(limiter.try_sync(key, 1).await.is_ok());
+            assert!(limiter.try_sync(key, 1).await.is_ok());
+            assert!(limiter.try_sync(key, 1).await.is_err());
+        }
+        assert_eq!(limiter.len(), 100);
+    }
+
+    #[tokio::test]
+    async fn idle_eviction() {
+        let limiter = KeyedLimiter::new(|| QuotaPer::new(1, Duration::from_millis(20))).with_idle_ttl(Duration::from_millis(50));
+        let busy = KeyedLimiter::new(|| QuotaPer::new(1, Duration::from_secs(60))).with_idle_ttl(Duration::from_millis(50));
+        assert!(limiter.try_sync("idle", 1).await.is_ok());
+        assert!(limiter.try_sync("idle", 1).await.is_err());
+        assert!(busy.try_sync("busy", 1).await.is_ok());
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        limiter.evict_idle();
+        busy.evict_idle();
+        assert_eq!(limiter.len(), 0);
+        // still has a reservation to free
+        assert_eq!(busy.len(), 1);
+
+        assert!(limiter.try_sync("idle", 1).await.is_ok());
+        assert!(busy.try_sync("busy", 1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn hierarchical() {
+        let limiter = Hierarchical::new(
+            Limiter::new(QuotaPer::new(5, Duration::from_secs(60))),
+            KeyedLimiter::new(|| QuotaPer::new(2, Duration::from_secs(60))),
+        );
+
+        // the greedy key is capped by its own limit, without taking from the global one
+        assert!(limiter.try_sync("greedy", 1).await.is_ok());
+        assert!(limiter.try_sync("greedy", 1).await.is_ok());
+        for _ in 0..10 {
+            assert!(limiter.try_sync("greedy", 1).await.is_err());
+        }
+        assert_eq!(limiter.global().headroom().await, Some(3));
+
+        assert!(limiter.try_sync("a", 1).await.is_ok());
+        assert!(limiter.try_sync("a", 1).await.is_ok());
+        assert!(limiter.try_sync("b", 1).await.is_ok());
+
+        // the global limit is reached, so "b" keeps its own capacity
+        assert!(limiter.try_sync("b", 1).await.is_err());
+        assert_eq!(limiter.keyed().limiter("b").headroom().await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn hierarchical_sync() {
+        let limiter = Hierarchical::new(
+            Limiter::new(QuotaPer::new(1, Duration::from_millis(30))),
+            KeyedLimiter::new(|| QuotaPer::new(1, Duration::from_secs(60))),
+        );
+
+        let started = std::time::Instant::now();
+        limiter.sync("a", 1).await;
+        limiter.sync("b", 1).await;
+        assert!(started.elapsed() >= Duration::from_millis(30));
+        assert_eq!(limiter.global().stats().await.rejected, 0);
+        assert_eq!(limiter.keyed().limiter("b").stats().await.rejected, 0);
+
+        // a shut down limiter reserves nothing, so the wait doesn't end either, rather than spinning
+        limiter.global().shutdown().await;
+        assert!(tokio::time::timeout(Duration::from_millis(50), limiter.sync("c", 1)).await.is_err());
+        assert_eq!(limiter.keyed().limiter("c").pending().await, 0);
+    }
+
+    #[tokio::test]
+    async fn round_robin() {
+        let limiter = Hierarchical::new(
+            Limiter::new(QuotaPer::new(1, Duration::from_millis(2))),
+            KeyedLimiter::new(|| QuotaPer::new(100, Duration::from_secs(60))),
+        ).with_round_robin();
+        let granted = Arc::new(Mutex::new(Vec::new()));
+
+        // the flooding key keeps 20 waiters queued, the light ones one each
+        let flooding: Vec<_> = (0..20)
+            .map(|_| {
+                let (limiter, granted) = (limiter.clone(), granted.clone());
+                tokio::spawn(async move {
+                    while granted.lock().unwrap().len() < 60 {
+                        limiter.sync("flood", 1).await;
+                        granted.lock().unwrap().push("flood");
+                    }
+                })
+            })
+            .collect();
+        let light: Vec<_> = ["a", "b", "c"].into_iter()
+            .map(|key| {
+                let (limiter, granted) = (limiter.clone(), granted.clone());
+                tokio::spawn(async move {
+                    for _ in 0..5 {
+                        limiter.sync(key, 1).await;
+                        granted.lock().unwrap().push(key);
+                    }
+                })
+            })
+            .collect();
+        for task in light.into_iter().chain(flooding) {
+            task.await.unwrap();
+        }
+
+        // a grant per key and round, so the light keys are done after about 20 grants
+        let granted = granted.lock().unwrap();
+        let done = granted.iter().rposition(|key| *key != "flood").unwrap();
+        assert!(done < 30, "{done}: {:?}", &granted[..done + 1]);
+    }
+
+    #[tokio::test]
+    async fn lru_eviction() {
+        let limiter = KeyedLimiter::with_shards(|| QuotaPer::new(1, Duration::from_millis(1)), 1).with_max_keys(3);
+        for key in ["a", "b", "c"] {
+            limiter.sync(key, 1).await;
+        }
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        limiter.sync("a", 1).await;
+        tokio::time::sleep(Duration::from_millis(5)).await;
+        limiter.sync("d", 1).await;
+        assert!(!limiter.contains(&"b"));
+        assert!(limiter.contains(&"a") && limiter.contains(&"c") && limiter.contains(&"d"));
+
+        // nothing is safe to evict, so the bound is exceeded
+        let busy = KeyedLimiter::with_capacity(|| QuotaPer::new(1, Duration::from_secs(60)), 2);
+        for key in 0..5 {
+            busy.sync(key, 1).await;
+        }
+        assert_eq!(busy.len(), 5);
+    }
+}
@@ -1,10 +1,55 @@
+pub mod asynchronous;
+pub mod blocking;
+mod builder;
+pub mod clock;
+pub mod compat;
+pub mod fair;
+pub mod io;
+pub mod keyed;
+pub mod local;
 pub mod logic;
+pub mod presets;
+mod queue;
+#[cfg(feature = "redis")]
+pub mod redis;
+#[cfg(feature = "reqwest")]
+pub mod reqwest;
+#[cfg(feature = "serde")]
+mod relative;
+mod rng;
+mod runtime;
+pub mod sharded;
+#[cfg(feature = "sink")]
+pub mod sink;
+pub mod sleeper;
+pub mod time;
+#[cfg(feature = "stream")]
+pub mod stream;
+#[cfg(feature = "testkit")]
+pub mod testkit;
+#[cfg(feature = "tower")]
+pub mod tower;
 
-use std::cmp::Ordering;
 use std::collections::{BinaryHeap};
-use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tokio::sync::Mutex;
+use std::error::Error;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::sync::{Arc, PoisonError};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::Duration;
+use crate::time::Instant;
+use ratelim_core::HeapValue;
+use tokio::sync::{watch, Notify};
+use tokio::sync::futures::OwnedNotified;
+use crate::clock::Clock;
+use crate::queue::WaitQueue;
+use crate::rng::AtomicRng;
+use crate::runtime::{Mutex, MutexGuard};
+use crate::sleeper::{Sleep, Sleeper};
+
+pub use builder::LimiterBuilder;
 
 
 /// `Limiter` requires internal logic being provided. Check `LimiterLogic` for more details.
@@ -15,6 +60,8 @@ use tokio::sync::Mutex;
 /// use std::time::Duration;
 /// use ratelim::{Limiter, logic::Timeout};
 ///
+/// # #[tokio::main]
+/// # async fn main() {
 /// let limiter = Limiter::new(Timeout::new(Duration::from_millis(500)));
 /// for i in 0..100000 {
 ///     let limiter_clone = limiter.clone();
@@ -23,124 +70,2594 @@ use tokio::sync::Mutex;
 ///         println!("{}", i);
 ///     });
 ///  }
+/// # }
 /// ```
-#[derive(Clone)]
+///
+/// ## Clone vs fork
+///
+/// Clones share the same state: a reservation through one clone counts against all of them, which is
+/// what spreading a limiter across tasks needs. `fork` instead creates an independent limiter from the same
+/// configuration, e.g. per-tenant sub-limiters derived from a template.
+///
+/// ## Cancellation
+///
+/// The waiting futures, `sync` and its variants as well as `acquire`, are cancellation safe: something is
+/// reserved only in the same poll that completes the future, with no await in between, so dropping
+/// a future that has not completed, e.g. in a `tokio::select!` or a timeout, never consumes anything.
 pub struct Limiter<Logic: logic::Logic<State>, State> {
-    internal: Arc<Mutex<LogicWrapper<Logic, State>>>,
-    polling_timeout: Duration,
+    internal: Arc<Shared<Logic, State>>,
+    poll_strategy: PollStrategy,
+    fairness: Fairness,
+    sleeper: Arc<dyn Sleeper>,
+    #[cfg(any(feature = "tokio", feature = "async-std"))]
+    reclaim: Option<Arc<ReclaimTask>>,
+    on_event: Option<EventCallback>,
 }
 
-impl<Logic: logic::Logic<State>, State> Limiter<Logic, State> {
-    pub fn new(logic: Logic) -> Limiter<Logic, State> {
-        Limiter::with_polling_timeout(logic, Duration::from_millis(1))
+type EventCallback = Arc<dyn Fn(Event) + Send + Sync>;
+
+// not derived, so neither `Logic` nor `State` have to be `Clone`
+impl<Logic: logic::Logic<State>, State> Clone for Limiter<Logic, State> {
+    fn clone(&self) -> Limiter<Logic, State> {
+        Limiter {
+            internal: self.internal.clone(),
+            poll_strategy: self.poll_strategy,
+            fairness: self.fairness,
+            sleeper: self.sleeper.clone(),
+            #[cfg(any(feature = "tokio", feature = "async-std"))]
+            reclaim: self.reclaim.clone(),
+            on_event: self.on_event.clone(),
+        }
+    }
+}
+
+/// Most items `Limiter::sync_many` grants under a single lock.
+const SYNC_MANY_RUN: usize = 64;
+
+/// Growth of the pause before a contended waiter locks again with every lost race, see `Limiter::contention_jitter`.
+const CONTENTION_JITTER: Duration = Duration::from_micros(100);
+
+/// Longest pause before a contended waiter locks again.
+const MAX_CONTENTION_JITTER: Duration = Duration::from_millis(5);
+
+/// Cap of the default `PollStrategy::Adaptive`.
+const DEFAULT_POLL_CAP: Duration = Duration::from_millis(10);
+
+// never waits for the lock, so it is safe to print a stuck limiter
+impl<Logic: logic::Logic<State>, State> Debug for Limiter<Logic, State> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let mut debug = f.debug_struct("Limiter");
+        debug.field("poll_strategy", &self.poll_strategy).field("fairness", &self.fairness);
+        match runtime::try_lock(&self.internal.wrapper) {
+            Some(internal) => debug
+                .field("name", &internal.name)
+                .field("pending", &internal.delayed_frees.len())
+                .field("held", &internal.held)
+                .field("permanent", &internal.permanent)
+                .field("paused", &internal.paused)
+                .field("closed", &internal.closed),
+            None => debug.field("pending", &format_args!("<locked>")),
+        };
+        debug.finish_non_exhaustive()
     }
+}
+
+impl<Logic: logic::Logic<State>, State> Limiter<Logic, State> {
+    pub fn new(logic: Logic) -> Limiter<Logic, State> { Limiter::builder(logic).build() }
 
+    /// Same as `new`, but waiters poll every `poll_timeout` while no wakeup is known, see `PollStrategy::Fixed`.
     pub fn with_polling_timeout(logic: Logic, poll_timeout: Duration) -> Limiter<Logic, State> {
+        Limiter::builder(logic).poll_timeout(poll_timeout).build()
+    }
+
+    /// Same as `new`, but delayed frees are scheduled with the given `clock`, e.g. a `MockClock` in tests.
+    pub fn with_clock(logic: Logic, clock: impl Clock + 'static) -> Limiter<Logic, State> {
+        Limiter::builder(logic).clock(clock).build()
+    }
+
+    /// Configures every option of the limiter, see `LimiterBuilder`.
+    pub fn builder(logic: Logic) -> LimiterBuilder<Logic, State> { LimiterBuilder::new(logic) }
+
+    /// Creates an independent limiter with the same configuration and a reset copy of the logic,
+    /// see `Logic::reset`, so reservations through it never affect this one.
+    /// Unlike `clone`, which shares the state. A background reclaim task is not forked.
+    ///
+    /// The delayed frees pending here are freed in the copy, but for a logic without its own `reset`,
+    /// reservations held by permits stay counted, as the logic can't tell them apart.
+    pub async fn fork(&self) -> Limiter<Logic, State> where Logic: Clone {
+        let internal = self.internal.lock().await;
+        let mut logic = internal.logic.clone();
+        for HeapValue((_, state)) in internal.delayed_frees.iter() {
+            logic.free(state);
+        }
+        logic.reset();
+
+        let shared = Shared::new(logic, internal.clock.clone());
+        let aging = self.internal.queue.lock().unwrap_or_else(PoisonError::into_inner).aging();
+        shared.queue.lock().unwrap_or_else(PoisonError::into_inner).set_aging(aging);
+
         Limiter {
-            internal: Arc::new(Mutex::new(LogicWrapper::new(logic))),
-            polling_timeout: poll_timeout,
+            internal: Arc::new(shared),
+            poll_strategy: self.poll_strategy,
+            fairness: self.fairness,
+            sleeper: self.sleeper.clone(),
+            #[cfg(any(feature = "tokio", feature = "async-std"))]
+            reclaim: None,
+            on_event: self.on_event.clone(),
+        }
+    }
+
+    /// Reports what the limiter does to `callback`, e.g. to feed a metrics backend.
+    /// The callback is never invoked while the internal lock is held, so it may use the limiter itself.
+    /// `Event::Freed` is reported by the first call on the limiter after the free happened.
+    pub fn on_event(mut self, callback: impl Fn(Event) + Send + Sync + 'static) -> Limiter<Logic, State> {
+        self.on_event = Some(Arc::new(callback));
+        self
+    }
+
+    /// Waits until the logic admits `state`, then reserves it.
+    ///
+    /// Once the limiter is `shutdown` this never returns, as nothing would be reserved,
+    /// use `sync_unless_closed` to be told instead.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all, fields(wait)))]
+    pub async fn sync(&self, mut state: State) {
+        let Some(mut internal) = self.wait_ready(None, Some(&mut state)).await else {
+            return std::future::pending().await;
+        };
+        internal.add(state);
+        self.unlock(internal, Event::Granted);
+    }
+
+    /// Same as `sync`, for a `state` only known once `cost_fn` is done, e.g. an estimated payload size.
+    ///
+    /// `cost_fn` runs first, once, and only then is readiness checked, as it depends on the cost,
+    /// see `Logic::is_ready_for`. Nothing is reserved meanwhile, so the capacity may be taken while
+    /// the cost is computed, the wait that follows is the one of `sync`.
+    pub async fn sync_with<F, Fut>(&self, cost_fn: F)
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = State>,
+    {
+        let state = cost_fn().await;
+        self.sync(state).await
+    }
+
+    /// Same as `sync`, but returns how long it waited from the call until the grant, e.g. for a latency histogram.
+    pub async fn sync_timed(&self, state: State) -> Duration {
+        let started = Instant::now();
+        self.sync(state).await;
+        started.elapsed()
+    }
+
+    /// Reserves every item of `states`, each with its own cost, without locking once per item.
+    /// Whenever the logic is ready, as many of the following items as fit are granted under the same lock,
+    /// the rest waits like `sync`. `Event::Granted` is reported once per such run, not per item.
+    ///
+    /// Items are granted strictly in order, nothing is granted past one that doesn't fit yet, even if it would.
+    /// A run grants at most 64 items before the lock is released and taken again, so callers
+    /// waiting for it meanwhile are not starved by a long batch. Once the limiter is shut down, it never
+    /// returns, like `sync`, the remaining items are never granted.
+    pub async fn sync_many<I: IntoIterator<Item = State>>(&self, states: I) {
+        let mut states = states.into_iter().peekable();
+        while let Some(mut state) = states.next() {
+            let Some(mut internal) = self.wait_ready(None, Some(&mut state)).await else {
+                return std::future::pending().await;
+            };
+            internal.add(state);
+
+            let mut run = 1;
+            while run < SYNC_MANY_RUN && states.peek().is_some_and(|next| internal.ready_for(Some(next), None)) {
+                internal.add(states.next().expect("peeked"));
+                run += 1;
+            }
+            self.unlock(internal, Event::Granted);
+        }
+    }
+
+    /// Reserves `n` copies of `state` at once, either all of them are granted or none is.
+    ///
+    /// The batch is tried on a copy of the logic, which only replaces the real one if every copy fits.
+    /// Once a batch has to wait, nothing else is granted until it is, so it accumulates the capacity
+    /// instead of being starved by a stream of smaller requests. Batches waiting together are served
+    /// in no particular order.
+    ///
+    /// A batch above the whole limit is granted alone once nothing is reserved, like a single cost above it,
+    /// see `Logic::max_capacity` and `Logic::headroom`. With a logic not reporting both, a batch that can
+    /// never fit blocks the limiter for good.
+    pub async fn sync_n(&self, state: State, n: u32) where Logic: Clone, State: Clone + PartialEq {
+        let mut batch = None;
+        let internal = self.wait_until(None, None, |internal| {
+            if internal.try_add_n(&state, n) {
+                return true;
+            }
+            // from the first miss on, hold everyone else back
+            batch.get_or_insert_with(|| PendingBatch::new(internal.batches.clone(), internal.notify.clone()));
+            false
+        }).await;
+
+        if let Some(internal) = internal {
+            self.unlock(internal, Event::Granted);
+        }
+        drop(batch);
+    }
+
+    /// Non-blocking version of `sync_n`. Returns `false` if not every copy fits right now.
+    pub async fn try_sync_n(&self, state: State, n: u32) -> bool where Logic: Clone, State: Clone + PartialEq {
+        let mut internal = self.internal.lock().await;
+        if internal.batches.load(atomic::Ordering::Relaxed) > 0 || !internal.try_add_n(&state, n) {
+            internal.reject();
+            self.unlock(internal, Event::Rejected);
+            return false;
+        }
+
+        self.unlock(internal, Event::Granted);
+        true
+    }
+
+    /// Same as `sync`, but waiters with a higher `prio` are granted first, earlier arrivals breaking ties.
+    /// Callers of the other `sync` variants only go ahead once no prioritized waiter is left.
+    ///
+    /// Under saturation low priorities may starve, unless `LimiterBuilder::priority_aging` raises
+    /// their priority the longer they wait.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all, fields(wait)))]
+    pub async fn sync_with_priority(&self, mut state: State, prio: u8) {
+        let Some(mut internal) = self.wait_queued(None, prio, Some(&mut state)).await else {
+            return std::future::pending().await;
+        };
+        internal.add(state);
+        self.unlock(internal, Event::Granted);
+    }
+
+    /// Same as `sync`, but fails fast if `state` exceeds `Logic::max_capacity` and could never be admitted.
+    pub async fn sync_checked(&self, state: State) -> Result<(), NeverSatisfiable<State>> where State: PartialOrd {
+        let max_capacity = self.internal.lock().await.logic.max_capacity();
+        if max_capacity.is_some_and(|max_capacity| state > max_capacity) {
+            return Err(NeverSatisfiable(state));
+        }
+
+        self.sync(state).await;
+        Ok(())
+    }
+
+    /// Same as `sync`, but gives up after `max_wait`, handing the `state` back.
+    pub async fn sync_timeout(&self, state: State, max_wait: Duration) -> Result<(), State> {
+        self.sync_deadline(state, Instant::now() + max_wait).await
+    }
+
+    /// Same as `sync`, but gives up once `deadline` is reached, handing the `state` back.
+    /// No permit is consumed on failure.
+    ///
+    /// The waiter wakes up at `deadline` at the latest, so the deadline only slips by the time
+    /// it takes to re-acquire the internal lock.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all, fields(wait)))]
+    pub async fn sync_deadline(&self, mut state: State, deadline: Instant) -> Result<(), State> {
+        match self.wait_ready(Some(deadline), Some(&mut state)).await {
+            Some(mut internal) => {
+                internal.add(state);
+                self.unlock(internal, Event::Granted);
+                Ok(())
+            }
+            None => Err(state),
+        }
+    }
+
+    /// Same as `sync`, but gives up once `token` is cancelled, handing the `state` back.
+    /// No permit is consumed on cancellation.
+    #[cfg(feature = "tokio-util")]
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all, fields(wait)))]
+    pub async fn sync_cancellable(&self, mut state: State, token: tokio_util::sync::CancellationToken) -> Result<(), Cancelled<State>> {
+        if token.is_cancelled() {
+            return Err(Cancelled(state));
+        }
+
+        match sleeper::timeout(Box::pin(token.cancelled_owned()), self.wait_ready(None, Some(&mut state))).await.flatten() {
+            Some(mut internal) => {
+                internal.add(state);
+                self.unlock(internal, Event::Granted);
+                Ok(())
+            }
+            None => Err(Cancelled(state)),
+        }
+    }
+
+    /// Non-blocking version of `sync`. Hands the `state` back instead of waiting if the logic is not ready.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all))]
+    pub async fn try_sync(&self, state: State) -> Result<(), State> {
+        self.try_sync_info(state).await.map_err(|rejected| rejected.state)
+    }
+
+    /// Same as `try_sync`, but a rejection also tells when to try again, e.g. for a `Retry-After` header.
+    pub async fn try_sync_info(&self, state: State) -> Result<(), RejectInfo<State>> {
+        let internal = self.internal.lock().await;
+        self.try_add(internal, state)
+    }
+
+    /// `try_sync` for poll-based callers that can't wait for the lock, a taken lock counts as not ready.
+    pub(crate) fn try_sync_now(&self, state: State) -> Result<(), State> {
+        match self.internal.try_lock() {
+            Some(internal) => self.try_add(internal, state).map_err(|rejected| rejected.state),
+            None => Err(state),
+        }
+    }
+
+    fn try_add(&self, mut internal: MutexGuard<'_, LogicWrapper<Logic, State>>, state: State) -> Result<(), RejectInfo<State>> {
+        if !internal.ready_for(Some(&state), None) {
+            let retry_after = internal.until_wakeup();
+            internal.reject();
+            self.unlock(internal, Event::Rejected);
+            return Err(RejectInfo { state, retry_after });
+        }
+
+        internal.add(state);
+        self.unlock(internal, Event::Granted);
+
+        Ok(())
+    }
+
+    /// Waits like `sync`, but the reservation is held by the returned `Permit` and freed once it
+    /// is dropped, instead of after the `add_for` timeout, unless it is `Permit::commit`ted.
+    /// Once the limiter is shut down, the permit holds nothing.
+    #[cfg_attr(feature = "tracing", tracing::instrument(level = "trace", skip_all, fields(wait)))]
+    pub async fn acquire(&self, mut state: State) -> Permit<Logic, State> {
+        let (state, delay) = match self.wait_ready(None, Some(&mut state)).await {
+            Some(mut internal) => {
+                let (delay, state) = internal.hold(state);
+                self.unlock(internal, Event::Granted);
+                (Some(state), delay)
+            }
+            None => (None, Duration::ZERO),
+        };
+
+        Permit {
+            internal: self.internal.clone(),
+            state,
+            delay,
+        }
+    }
+
+    /// Poll-based readiness for manual integration, e.g. a `poll_ready` of a poll-driven framework.
+    /// `Ready` once a `try_sync` would be granted, nothing is reserved, so another caller may still be first.
+    ///
+    /// Otherwise the waker is woken on the next release or due free, or by the `PollStrategy` fallback.
+    /// A lock held elsewhere counts as not ready, the waker is then woken right away to try again.
+    pub fn poll_ready(&self, cx: &mut Context<'_>) -> Poll<()> {
+        // registered before the check, so a release right after it is not missed
+        let mut notified = Box::pin(self.internal.notify.clone().notified_owned());
+        notified.as_mut().enable();
+
+        let Some(mut internal) = self.internal.try_lock() else {
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        };
+        if internal.ready() {
+            return Poll::Ready(());
+        }
+
+        let wake_after = self.poll_strategy.wakeup(internal.until_wakeup(), self.poll_strategy.first());
+        drop(internal);
+
+        let sleep = wake_after.map(|after| self.sleeper.sleep(after));
+        self.internal.poll_waiters.lock().unwrap_or_else(PoisonError::into_inner).register(cx.waker(), notified, sleep);
+        Poll::Pending
+    }
+
+    /// Whether a `sync` would be granted right now. Does not consume anything.
+    pub async fn available(&self) -> bool { self.internal.lock().await.ready() }
+
+    /// When the limiter is expected to be ready again, i.e. now if it already is, otherwise the earliest
+    /// scheduled free or `Logic::ready_in`. `None` if it is not ready and nothing is scheduled, e.g. only permits are held.
+    pub async fn next_available_at(&self) -> Option<Instant> {
+        let mut internal = self.internal.lock().await;
+        let now = Instant::now();
+
+        if internal.ready() {
+            return Some(now);
+        }
+
+        internal.until_wakeup().map(|until_wakeup| now + until_wakeup)
+    }
+
+    /// How much more the logic could admit right now, see `Logic::headroom`.
+    pub async fn headroom(&self) -> Option<State> {
+        let mut internal = self.internal.lock().await;
+        internal.cleanup();
+        internal.logic.headroom()
+    }
+
+    /// Number of reservations waiting for their delayed free, i.e. granted by `sync` and not reclaimed yet.
+    /// For `QuotaPer` with unit weights that is the quota in use. Permits are not counted.
+    pub async fn pending(&self) -> usize {
+        let mut internal = self.internal.lock().await;
+        internal.cleanup();
+        internal.delayed_frees.len()
+    }
+
+    /// Number of waiters queued by `Fairness::Fifo` or `sync_with_priority`.
+    /// Waiters of a `Fairness::Unordered` limiter don't queue, so they are not counted.
+    pub async fn depth(&self) -> usize { self.internal.queue.lock().unwrap_or_else(PoisonError::into_inner).len() }
+
+    /// Follows whether the logic is ready, e.g. for a UI indicator, `changed` resolves on every flip.
+    /// Only transitions are sent, checked whenever something is granted or freed, or the limiter is paused,
+    /// resumed, reset or shut down. Readiness returning with time alone, e.g. a refilling `TokenBucket`,
+    /// is only seen by the next call on the limiter.
+    pub fn subscribe(&self) -> watch::Receiver<bool> { self.internal.readiness.subscribe() }
+
+    /// Snapshot of the counters since the limiter was created.
+    pub async fn stats(&self) -> Stats {
+        let internal = self.internal.lock().await;
+        Stats {
+            current_pending: internal.delayed_frees.len(),
+            saturated_time: internal.saturated_time(),
+            ..internal.stats.clone()
+        }
+    }
+
+    /// Whether nothing is reserved, nor anyone else is holding on to this limiter,
+    /// so forgetting it only loses what the logic recovers after its last free, see `KeyedLimiter::with_idle_ttl`.
+    /// Never waits for the lock.
+    pub(crate) fn is_idle(&self) -> bool {
+        if Arc::strong_count(&self.internal) > 1 {
+            return false;
+        }
+
+        match runtime::try_lock(&self.internal.wrapper) {
+            Some(mut wrapper) => {
+                for state in self.internal.take_released() {
+                    wrapper.free(&state);
+                }
+                wrapper.cleanup();
+                wrapper.delayed_frees.is_empty()
+            }
+            None => false,
         }
     }
 
-    pub async fn sync(&self, state: State) {
+    /// Passes an out-of-band `signal` to the logic, see `logic::Feedback`.
+    pub async fn feed<Signal>(&self, signal: Signal) where Logic: logic::Feedback<Signal> {
+        self.internal.lock().await.logic.observe(signal);
+        // the logic may have grown its capacity
+        self.internal.notify.notify_waiters();
+    }
+
+    /// Mutates the logic in place, e.g. to change its quota at runtime.
+    /// Pending delayed frees are kept and will still be freed on schedule, so `f` should keep the logic
+    /// able to `free` what it has already added.
+    pub async fn reconfigure<F: FnOnce(&mut Logic)>(&self, f: F) {
+        f(&mut self.internal.lock().await.logic);
+        self.internal.notify.notify_waiters();
+    }
+
+    /// Reads the logic under the lock, e.g. a logic-specific field for a custom metric.
+    /// Due delayed frees are not reclaimed first, see `available` for that.
+    pub async fn with_logic<R, F: FnOnce(&Logic) -> R>(&self, f: F) -> R { f(&self.internal.lock().await.logic) }
+
+    /// Snapshots the logic and its pending delayed frees. Deadlines are stored relative to now,
+    /// as instants are meaningless to another process. Reservations held by permits are not delayed frees,
+    /// so they stay counted in the snapshot and are never freed after an import.
+    pub async fn export_state(&self) -> LimiterState<Logic, State> where Logic: Clone, State: Clone {
+        let mut internal = self.internal.lock().await;
+        internal.cleanup();
+
+        let now = internal.clock.now();
+        let mut delayed_frees: Vec<_> = internal.delayed_frees.iter()
+            .map(|HeapValue((at, state))| (at.saturating_duration_since(now), state.clone()))
+            .collect();
+        delayed_frees.sort_by_key(|(after, _)| *after);
+
+        LimiterState { logic: internal.logic.clone(), delayed_frees }
+    }
+
+    /// Replaces the logic and the pending delayed frees with an exported snapshot, rebased on the current time.
+    /// Whatever was reserved here before is forgotten rather than freed.
+    pub async fn import_state(&self, state: LimiterState<Logic, State>) {
+        let mut internal = self.internal.lock().await;
+
+        let now = internal.clock.now();
+        internal.logic = state.logic;
+        internal.delayed_frees = state.delayed_frees.into_iter()
+            .map(|(after, state)| HeapValue((now + after, state)))
+            .collect();
+        internal.publish_readiness();
+        drop(internal);
+
+        // the next free and the capacity have both changed
+        self.internal.notify.notify_waiters();
+    }
+
+    /// Drops every pending delayed free and resets the logic, see `Logic::reset`, along with the `stats`,
+    /// so the limiter grants as if freshly constructed. Pausing and `shutdown` are kept.
+    /// Permits still held are forgotten, dropping them later frees nothing the logic still counts.
+    pub async fn reset(&self) {
+        let mut internal = self.internal.lock().await;
+        internal.logic.reset();
+        internal.delayed_frees.clear();
+        internal.held = 0;
+        internal.permanent = 0;
+        internal.stats = Stats::default();
+        internal.saturated_since = None;
+        internal.publish_readiness();
+        drop(internal);
+
+        self.internal.notify.notify_waiters();
+    }
+
+    /// Stops granting until `resume` is called. Waiters in `sync` keep waiting, `try_sync` fails,
+    /// and reserved capacity is still freed on schedule.
+    pub async fn pause(&self) {
+        let mut internal = self.internal.lock().await;
+        internal.paused = true;
+        internal.publish_readiness();
+    }
+
+    pub async fn resume(&self) {
+        let mut internal = self.internal.lock().await;
+        internal.paused = false;
+        internal.publish_readiness();
+        drop(internal);
+        self.internal.notify.notify_waiters();
+    }
+
+    /// Stops granting for good and waits until every reservation is freed, both the delayed frees
+    /// and the permits still held, e.g. to let in-flight requests complete before exiting.
+    ///
+    /// Calls made after the shutdown, or still waiting when it starts, are rejected: `sync_unless_closed`
+    /// and the deadline variants of `sync` fail, `try_sync` returns `Err`, and a plain `sync` keeps waiting for good.
+    pub async fn shutdown(&self) {
+        let mut internal = self.internal.lock().await;
+        internal.closed = true;
+        internal.publish_readiness();
+        drop(internal);
+        // the waiters have to notice they are rejected
+        self.internal.notify.notify_waiters();
+
         loop {
+            let notified = self.internal.notify.notified();
+
             let mut internal = self.internal.lock().await;
+            internal.cleanup();
+            if internal.delayed_frees.is_empty() && internal.held == 0 {
+                return;
+            }
+
+            let until_next_free = internal.next_free().map(|next_free| next_free.saturating_duration_since(internal.clock.now()));
+            drop(internal);
+
+            // dropped permits notify, delayed frees are awaited
+            let wake_at = self.poll_strategy.wakeup(until_next_free, self.poll_strategy.first()).map(|after| Instant::now() + after);
+            let _ = self.sleep_until(wake_at, notified).await;
+        }
+    }
+
+    /// Same as `sync`, but fails once the limiter is `shutdown`, handing the `state` back.
+    pub async fn sync_unless_closed(&self, mut state: State) -> Result<(), Closed<State>> {
+        match self.wait_ready(None, Some(&mut state)).await {
+            Some(mut internal) => {
+                internal.add(state);
+                self.unlock(internal, Event::Granted);
+                Ok(())
+            }
+            None => Err(Closed(state)),
+        }
+    }
 
-            if !internal.ready() {
-                tokio::time::sleep(self.polling_timeout).await;
+    /// Waits until the logic is ready for `state` without reserving anything, so another caller may take the capacity first.
+    pub(crate) async fn until_ready_for(&self, mut state: State) { let _ = self.wait_ready(None, Some(&mut state)).await; }
+
+    /// Waits until the logic is ready and returns the locked wrapper, or `None` once `deadline` passes.
+    ///
+    /// Waiters are woken whenever capacity is released, or when the next delayed free is due, or `Logic::ready_in`.
+    /// The `PollStrategy` is used as a fallback when neither is known, and caps the wait with `PollStrategy::Adaptive`.
+    ///
+    /// Polling a saturated logic would lock on every poll, so a fallback wakeup skips the lock
+    /// while the readiness last seen under it says not ready. Readiness may change with time alone,
+    /// so at most one wakeup in a row is skipped, a stale "not ready" costs a single extra interval.
+    /// Notifications always take the lock, so releases are never missed, a waiter that keeps losing
+    /// the race to others only takes it after a short random pause, see `contention_jitter`.
+    ///
+    /// The critical section is a single hold of the lock: reclaiming the due frees, the readiness check,
+    /// and, through the returned guard, the grant. Nobody can take the capacity in between, and the lock
+    /// is never held across a sleep. The wakeup is registered before the lock is taken, so a release
+    /// after the check but before the sleep still wakes the waiter. Whoever locks first once capacity
+    /// is back is granted, use `Fairness::Fifo` for arrival order.
+    ///
+    /// With a `state`, the logic has to be ready for it in particular, see `Logic::is_ready_for`.
+    // the state is borrowed mutably, only so the future stays `Send` for a `State` that is not `Sync`
+    async fn wait_ready(&self, deadline: Option<Instant>, state: Option<&mut State>) -> Option<MutexGuard<'_, LogicWrapper<Logic, State>>> {
+        match self.fairness {
+            Fairness::Unordered => self.wait_until(deadline, None, move |internal| internal.ready_for(state.as_deref(), None)).await,
+            Fairness::Fifo => self.wait_queued(deadline, 0, state).await,
+        }
+    }
+
+    /// Same as `wait_ready`, but waits in the `WaitQueue` with priority `prio`.
+    async fn wait_queued(&self, deadline: Option<Instant>, prio: u8, state: Option<&mut State>) -> Option<MutexGuard<'_, LogicWrapper<Logic, State>>> {
+        // leaves the queue once granted or given up on, letting the next one in
+        let ticket = Ticket::new(&self.internal, prio);
+        self.wait_until(deadline, Some(&ticket), move |internal| internal.ready_for(state.as_deref(), Some(ticket.ticket))).await
+    }
+
+    /// Same as `wait_ready`, with a custom readiness check run under the lock.
+    /// A waiter holding a `ticket` does not even lock until it is first in line.
+    async fn wait_until(
+        &self,
+        deadline: Option<Instant>,
+        ticket: Option<&Ticket<'_>>,
+        mut is_ready: impl FnMut(&mut LogicWrapper<Logic, State>) -> bool,
+    ) -> Option<MutexGuard<'_, LogicWrapper<Logic, State>>> {
+        let started = Instant::now();
+
+        let mut is_waiting = false;
+        // notifications after which the logic was still not ready, see `contention_jitter`
+        let mut lost = 0;
+        let mut notified_last = false;
+        // whether the last wakeup was the `poll_strategy` fallback, rather than a notification or a free
+        let mut polled = false;
+        // fallback interval of the next wakeup, if the strategy polls at all
+        let mut poll_interval = self.poll_strategy.first();
+
+        loop {
+            // created before checking readiness, so a release in between is not missed
+            let notified = self.internal.notify.notified();
+
+            if std::mem::take(&mut polled) && !self.internal.maybe_ready.load(atomic::Ordering::Relaxed) {
+                let wake_at = poll_interval.map(|interval| Instant::now() + interval);
+                poll_interval = self.poll_strategy.after(poll_interval);
+                let _ = self.sleep_until(earliest(wake_at, deadline), notified).await;
                 continue;
             }
 
-            internal.add(state);
+            let is_expired = deadline.is_some_and(|deadline| Instant::now() >= deadline);
+            if ticket.is_some_and(|ticket| !ticket.is_first()) && !is_expired {
+                if !is_waiting {
+                    is_waiting = true;
+                    self.report(0, Event::WaitStarted);
+                }
+                // whoever is first wakes everyone once it leaves the queue,
+                // but with aging the first one may change as time goes by
+                let wake_at = ticket.and_then(Ticket::aging).map(|aging| Instant::now() + aging);
+                let _ = self.sleep_until(earliest(wake_at, deadline), notified).await;
+                continue;
+            }
+
+            let mut internal = self.internal.lock().await;
+
+            if internal.closed {
+                internal.reject();
+                self.unlock(internal, Event::Rejected);
+                return None;
+            }
+
+            if is_ready(&mut internal) {
+                internal.waited(started.elapsed());
+                #[cfg(feature = "tracing")]
+                tracing::Span::current().record("wait", tracing::field::debug(started.elapsed()));
+                return Some(internal);
+            }
+
+            if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+                internal.reject();
+                self.unlock(internal, Event::Rejected);
+                return None;
+            }
+
+            if std::mem::take(&mut notified_last) {
+                lost += 1;
+            }
+            let until_wakeup = internal.until_wakeup();
+            if is_waiting {
+                drop(internal);
+            } else {
+                is_waiting = true;
+                self.unlock(internal, Event::WaitStarted);
+            }
+
+            let wake_at = self.poll_strategy.wakeup(until_wakeup, poll_interval).map(|after| Instant::now() + after + self.contention_jitter(lost));
+            notified_last = self.sleep_until(earliest(wake_at, deadline), notified).await.is_some();
+            polled = !notified_last && until_wakeup.is_none();
+            if polled {
+                poll_interval = self.poll_strategy.after(poll_interval);
+            }
+
+            let jitter = self.contention_jitter(lost);
+            if notified_last && !jitter.is_zero() {
+                let _ = self.sleep_until(earliest(Some(Instant::now() + jitter), deadline), std::future::pending::<()>()).await;
+            }
+        }
+    }
+
+    /// Random delay before a waiter that was notified `lost` times, only to find the capacity taken, locks again.
+    ///
+    /// Every release wakes all the waiters at once, and under heavy contention all but one of them lock only
+    /// to find the logic not ready, then sleep until the next free, in lockstep. Each lost race widens
+    /// the delay by `CONTENTION_JITTER`, up to `MAX_CONTENTION_JITTER`, added to the wakeup and paused
+    /// after a notification, so the waiters drift apart. Nobody listens to notifications while pausing,
+    /// so a contended waiter locks about once per pause at most, however often capacity is released.
+    /// A waiter that never lost a race, e.g. the only one, is never delayed.
+    fn contention_jitter(&self, lost: u32) -> Duration {
+        let window = CONTENTION_JITTER.saturating_mul(lost).min(MAX_CONTENTION_JITTER);
+        window.mul_f64(self.internal.rng.next_f64())
+    }
 
-            break;
+    /// Runs `future` until `deadline`, if any, with the limiter's `Sleeper`, `None` if it is not done by then.
+    async fn sleep_until<F: std::future::Future>(&self, deadline: Option<Instant>, future: F) -> Option<F::Output> {
+        match deadline {
+            Some(deadline) => sleeper::timeout(self.sleeper.sleep(deadline.saturating_duration_since(Instant::now())), future).await,
+            None => Some(future.await),
         }
     }
+
+    /// Releases the lock, then reports the frees it has seen since the last report, followed by `event`.
+    fn unlock(&self, mut internal: MutexGuard<'_, LogicWrapper<Logic, State>>, event: Event) {
+        let freed = std::mem::take(&mut internal.freed);
+        drop(internal);
+        self.report(freed, event);
+    }
+
+    /// Reports `freed` frees, followed by `event`.
+    fn report(&self, freed: usize, event: Event) {
+        #[cfg(feature = "tracing")]
+        {
+            if freed > 0 {
+                tracing::trace!(freed, "freed");
+            }
+            match event {
+                Event::Granted => tracing::trace!("granted"),
+                Event::Rejected => tracing::trace!("rejected"),
+                Event::WaitStarted => tracing::trace!("wait started"),
+                Event::Freed => tracing::trace!(freed = 1, "freed"),
+            }
+        }
+
+        if let Some(on_event) = &self.on_event {
+            for _ in 0..freed {
+                on_event(Event::Freed);
+            }
+            on_event(event);
+        }
+    }
+}
+
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+impl<Logic: logic::Logic<State> + Send + 'static, State: Send + 'static> Limiter<Logic, State> {
+    /// Same as `new`, but also spawns a task that reclaims expired reservations every `interval`,
+    /// so the logic is freed on schedule even if nobody calls `sync`.
+    /// The task is aborted once the last clone of the limiter is dropped.
+    pub fn with_background_reclaim(logic: Logic, interval: Duration) -> Limiter<Logic, State> {
+        Limiter::builder(logic).background_reclaim(interval).build()
+    }
 }
 
-struct HeapValue<T>((Instant, T));
+// only kept for stopping the task once dropped
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+struct ReclaimTask {
+    _task: runtime::Task,
+}
 
-//noinspection RsTraitImplementation -- my RustRover (beta) is being a bitch for no reason
-impl<T> Eq for HeapValue<T> {}
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+impl ReclaimTask {
+    fn spawn<Logic, State>(internal: std::sync::Weak<Shared<Logic, State>>, interval: Duration, sleeper: Arc<dyn Sleeper>) -> ReclaimTask
+    where
+        Logic: logic::Logic<State> + Send + 'static,
+        State: Send + 'static,
+    {
+        let task = runtime::Task::spawn(async move {
+            loop {
+                sleeper.sleep(interval).await;
 
-impl<T> PartialEq<Self> for HeapValue<T> { fn eq(&self, other: &Self) -> bool { self.0.0 == other.0.0 } }
+                let Some(internal) = internal.upgrade() else { break };
+                internal.lock().await.cleanup();
+            }
+        });
+        ReclaimTask { _task: task }
+    }
+}
 
-impl<T> PartialOrd<Self> for HeapValue<T> { fn partial_cmp(&self, other: &Self) -> Option<Ordering> { other.0.0.partial_cmp(&self.0.0) } }
+/// What the limiter reports to `Limiter::on_event`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Event {
+    Granted,
+    Rejected,
+    /// A `sync` found the logic not ready and started waiting.
+    WaitStarted,
+    /// A reservation was freed, either by its timeout or by a dropped `Permit`.
+    Freed,
+}
 
-impl<T> Ord for HeapValue<T> {
-    fn cmp(&self, other: &Self) -> Ordering { other.0.0.cmp(&self.0.0) }
+/// Counters returned by `Limiter::stats`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Reservations granted, including permits.
+    pub granted: u64,
+    /// Requests turned away by `try_sync`, or given up on by the deadline variants of `sync`.
+    pub rejected: u64,
+    /// Time spent waiting in the `sync` family, from the call until the grant.
+    pub total_wait: Duration,
+    /// Delayed frees not reclaimed yet.
+    pub current_pending: usize,
+    /// Distribution of the waits summed up in `total_wait`.
+    pub wait_histogram: WaitHistogram,
+    /// Time the limiter spent not ready, e.g. saturated, but also paused. Like `Limiter::subscribe`, transitions
+    /// are checked by the calls on the limiter, capacity returning with time alone is only seen by the next one.
+    pub saturated_time: Duration,
+}
 
-    fn max(self, other: Self) -> Self where Self: Sized { if self.0.0 < other.0.0 { self } else { other } }
+impl Stats {
+    /// Share of `window` spent saturated, from `0.0` to `1.0`, e.g. with the time since the limiter was created.
+    pub fn utilization(&self, window: Duration) -> f64 {
+        if window.is_zero() {
+            return 0.0;
+        }
+        (self.saturated_time.as_secs_f64() / window.as_secs_f64()).min(1.0)
+    }
 
-    fn min(self, other: Self) -> Self where Self: Sized { if self.0.0 < other.0.0 { other } else { self } }
+    /// See `WaitHistogram::percentile`.
+    pub fn wait_percentile(&self, percentile: f64) -> Duration { self.wait_histogram.percentile(percentile) }
 }
 
-struct LogicWrapper<Logic: logic::Logic<State>, State> {
-    logic: Logic,
-    // delayed_frees: VecDeque<(Instant, State)>,
-    delayed_frees: BinaryHeap<HeapValue<State>>,
+/// Counts of waits in power-of-two buckets of microseconds: the first one holds waits under 1µs,
+/// bucket `i` those from `2^(i-1)` up to `2^i` µs, and the last one everything longer, from about 18 minutes on.
+/// Fixed size, so recording a wait never allocates.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WaitHistogram {
+    buckets: [u64; WaitHistogram::BUCKETS],
 }
 
-impl<Logic: logic::Logic<State>, State> LogicWrapper<Logic, State> {
-    pub fn new(logic: Logic) -> LogicWrapper<Logic, State> {
-        LogicWrapper {
-            logic,
-            delayed_frees: BinaryHeap::new(),
+impl WaitHistogram {
+    const BUCKETS: usize = 32;
+
+    pub fn record(&mut self, wait: Duration) {
+        let micros = u64::try_from(wait.as_micros()).unwrap_or(u64::MAX);
+        let bucket = (u64::BITS - micros.leading_zeros()) as usize;
+        self.buckets[bucket.min(WaitHistogram::BUCKETS - 1)] += 1;
+    }
+
+    pub fn count(&self) -> u64 { self.buckets.iter().sum() }
+
+    /// Upper bound of the bucket holding the wait below which `percentile` of the waits fall, from `0.0` to `1.0`,
+    /// so it overestimates by up to a factor of two. Zero if nothing was recorded, `Duration::MAX` for the last bucket.
+    pub fn percentile(&self, percentile: f64) -> Duration {
+        let count = self.count();
+        if count == 0 {
+            return Duration::ZERO;
+        }
+
+        let rank = ((percentile.clamp(0.0, 1.0) * count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, waits) in self.buckets.iter().enumerate() {
+            seen += waits;
+            if seen >= rank {
+                if bucket == WaitHistogram::BUCKETS - 1 {
+                    return Duration::MAX;
+                }
+                return Duration::from_micros(1 << bucket);
+            }
         }
+        unreachable!("the rank is at most the count")
     }
+}
 
-    pub fn ready(&mut self) -> bool {
-        self.cleanup();
-        self.logic.is_ready()
+/// Snapshot of a limiter, returned by `Limiter::export_state` and restored with `Limiter::import_state`.
+/// With the `serde` feature it can be persisted, e.g. so a restart does not reset the quota.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LimiterState<Logic, State> {
+    pub logic: Logic,
+    /// Reservations to free, with the time left until they are due.
+    pub delayed_frees: Vec<(Duration, State)>,
+}
+
+/// Returned by `Limiter::sync_checked` when the `state` exceeds the logic's total capacity.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NeverSatisfiable<State>(pub State);
+
+impl<State: Debug> Display for NeverSatisfiable<State> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result { write!(f, "{:?} exceeds the total capacity of the limiter", self.0) }
+}
+
+impl<State: Debug> Error for NeverSatisfiable<State> {}
+
+/// Returned by `Limiter::try_sync_info` when the logic is not ready for the `state`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RejectInfo<State> {
+    pub state: State,
+    /// Until the next scheduled free or `Logic::ready_in`, like `Limiter::next_available_at`.
+    /// `None` if nothing is scheduled, e.g. only permits are held.
+    pub retry_after: Option<Duration>,
+}
+
+impl<State> Display for RejectInfo<State> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self.retry_after {
+            Some(retry_after) => write!(f, "rate limited, retry after {retry_after:?}"),
+            None => write!(f, "rate limited"),
+        }
     }
+}
 
-    pub fn add(&mut self, state: State) {
-        let delayed_for = Instant::now() + self.logic.add_for(&state);
-        self.delayed_frees.push(HeapValue((delayed_for, state)));
+impl<State: Debug> Error for RejectInfo<State> {}
+
+/// Returned by `Limiter::sync_unless_closed` once the limiter is shut down.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Closed<State>(pub State);
+
+impl<State> Display for Closed<State> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result { write!(f, "the limiter is shut down") }
+}
+
+impl<State: Debug> Error for Closed<State> {}
+
+/// Returned by `Limiter::sync_cancellable` once its token is cancelled.
+#[cfg(feature = "tokio-util")]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cancelled<State>(pub State);
+
+#[cfg(feature = "tokio-util")]
+impl<State> Display for Cancelled<State> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result { write!(f, "the wait was cancelled") }
+}
+
+#[cfg(feature = "tokio-util")]
+impl<State: Debug> Error for Cancelled<State> {}
+
+/// Reservation returned by `Limiter::acquire`. The logic is freed when the permit is dropped.
+///
+/// For two-phase work, reserve, do the operation, then `commit` the permit if it counts, e.g. it succeeded,
+/// or `rollback` it to give the capacity back at once. Dropping it rolls it back.
+pub struct Permit<Logic: logic::Logic<State>, State> {
+    internal: Arc<Shared<Logic, State>>,
+    state: Option<State>,
+    // returned by `add_for` when the reservation was made
+    delay: Duration,
+}
+
+impl<Logic: logic::Logic<State>, State> Permit<Logic, State> {
+    /// Keeps the reservation, it is freed like a `sync` grant, after the `add_for` delay counted from now.
+    pub async fn commit(mut self) {
+        if let Some(state) = self.state.take() {
+            let mut internal = self.internal.lock().await;
+            internal.held = internal.held.saturating_sub(1);
+            internal.delay_free(state, self.delay);
+        }
     }
 
-    fn cleanup(&mut self) {
-        let now = Instant::now();
-        while let Some(HeapValue((delayed_for, state))) = self.delayed_frees.peek() {
-            if now < *delayed_for {
-                break;
-            }
+    /// Frees the reservation right away, same as dropping the permit.
+    pub fn rollback(self) {}
+}
 
-            self.logic.free(&state);
-            self.delayed_frees.pop();
+impl<Logic: logic::Logic<State>, State> Drop for Permit<Logic, State> {
+    fn drop(&mut self) {
+        if let Some(state) = self.state.take() {
+            self.internal.release(state);
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use tokio::time::sleep;
-    use crate::logic::{QuotaPer, Timeout};
-    use super::*;
+/// State shared between the `Limiter` clones and the permits it hands out.
+struct Shared<Logic: logic::Logic<State>, State> {
+    wrapper: Mutex<LogicWrapper<Logic, State>>,
+    // permits dropped while the wrapper was locked, freed on the next `lock`
+    released: std::sync::Mutex<Vec<State>>,
+    notify: Arc<Notify>,
+    // last readiness seen under the lock, see `Limiter::wait_ready`
+    maybe_ready: Arc<AtomicBool>,
+    // waiters in arrival order, only used with `Fairness::Fifo`
+    queue: Arc<std::sync::Mutex<WaitQueue>>,
+    // see `Limiter::subscribe`
+    readiness: Arc<watch::Sender<bool>>,
+    // see `Limiter::poll_ready`
+    poll_waiters: std::sync::Mutex<PollWaiters>,
+    // see `Limiter::contention_jitter`
+    rng: AtomicRng,
+}
 
-    #[tokio::test]
-    async fn timeout() {
-        let limiter = Limiter::new(Timeout::new(Duration::from_millis(500)));
-        for i in 0..100000 {
-            let limiter_clone = limiter.clone();
-            tokio::task::spawn(async move {
-                limiter_clone.sync(()).await;
-                println!("{}", i);
-            });
+impl<Logic: logic::Logic<State>, State> Shared<Logic, State> {
+    fn new(logic: Logic, clock: Arc<dyn Clock>) -> Shared<Logic, State> {
+        let notify = Arc::new(Notify::new());
+        let maybe_ready = Arc::new(AtomicBool::new(true));
+        let queue = Arc::new(std::sync::Mutex::new(WaitQueue::default()));
+
+        let wrapper = LogicWrapper::new(logic, notify.clone(), maybe_ready.clone(), queue.clone(), clock);
+        Shared {
+            readiness: wrapper.readiness.clone(),
+            wrapper: Mutex::new(wrapper),
+            released: std::sync::Mutex::new(Vec::new()),
+            rng: AtomicRng::from_entropy(),
+            notify,
+            maybe_ready,
+            queue,
+            poll_waiters: std::sync::Mutex::new(PollWaiters::default()),
         }
-        sleep(Duration::from_secs(5)).await;
     }
 
-    #[tokio::test]
-    async fn quota() {
-        let limiter = Limiter::new(QuotaPer::new(5, Duration::from_millis(500)));
-        for i in 0..100001 {
-            let limiter_clone = limiter.clone();
-            tokio::task::spawn(async move {
-                limiter_clone.sync(1).await;
-                println!("{}", i);
-            });
+    async fn lock(&self) -> MutexGuard<'_, LogicWrapper<Logic, State>> {
+        let mut wrapper = self.wrapper.lock().await;
+        for state in self.take_released() {
+            wrapper.free(&state);
+        }
+        wrapper
+    }
+
+    fn try_lock(&self) -> Option<MutexGuard<'_, LogicWrapper<Logic, State>>> {
+        let mut wrapper = runtime::try_lock(&self.wrapper)?;
+        for state in self.take_released() {
+            wrapper.free(&state);
         }
-        sleep(Duration::from_secs(10)).await;
+        Some(wrapper)
+    }
+
+    fn release(&self, state: State) {
+        match runtime::try_lock(&self.wrapper) {
+            Some(mut wrapper) => wrapper.free(&state),
+            None => {
+                self.released.lock().unwrap_or_else(PoisonError::into_inner).push(state);
+                // wake the waiters so one of them picks the release up on its next `lock`
+                self.notify.notify_waiters();
+            }
+        }
+    }
+
+    fn take_released(&self) -> Vec<State> {
+        std::mem::take(&mut *self.released.lock().unwrap_or_else(PoisonError::into_inner))
+    }
+}
+
+/// Wakers of `Limiter::poll_ready` callers, all woken by the latest notification and wakeup they registered.
+#[derive(Default)]
+struct PollWaiters {
+    wakers: Arc<WakeAll>,
+    notified: Option<Pin<Box<OwnedNotified>>>,
+    sleep: Option<Sleep>,
+}
+
+impl PollWaiters {
+    // the wakeup is the same for every caller, so the latest one replaces the previous
+    fn register(&mut self, waker: &Waker, mut notified: Pin<Box<OwnedNotified>>, mut sleep: Option<Sleep>) {
+        self.wakers.push(waker);
+
+        let wake_all = Waker::from(self.wakers.clone());
+        let mut cx = Context::from_waker(&wake_all);
+        let slept = sleep.as_mut().is_some_and(|sleep| sleep.as_mut().poll(&mut cx).is_ready());
+        if notified.as_mut().poll(&mut cx).is_ready() || slept {
+            wake_all.wake();
+            return;
+        }
+
+        self.notified = Some(notified);
+        self.sleep = sleep;
+    }
+}
+
+#[derive(Default)]
+struct WakeAll(std::sync::Mutex<Vec<Waker>>);
+
+impl WakeAll {
+    fn push(&self, waker: &Waker) {
+        let mut wakers = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        if !wakers.iter().any(|registered| registered.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+    }
+}
+
+impl Wake for WakeAll {
+    fn wake(self: Arc<Self>) { self.wake_by_ref(); }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        for waker in std::mem::take(&mut *self.0.lock().unwrap_or_else(PoisonError::into_inner)) {
+            waker.wake();
+        }
+    }
+}
+
+/// How often waiters check the logic again while no wakeup is known, i.e. nothing is freed on a schedule
+/// and the logic has no `Logic::ready_in`, set with `LimiterBuilder::poll_strategy`.
+/// Releases and scheduled frees wake the waiters regardless.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PollStrategy {
+    /// Until the next scheduled free or `Logic::ready_in`, but never longer than `cap`, and every `cap`
+    /// while neither is known. The default, with a 10ms cap, a slow cap only delays logics that get ready
+    /// with time alone yet have no `Logic::ready_in`.
+    Adaptive { cap: Duration },
+    /// Every interval.
+    Fixed(Duration),
+    /// After `base`, doubling with every poll that is still not ready, up to `max`. Starts over for every call.
+    Exponential { base: Duration, max: Duration },
+    /// Never polls, waiters sleep until the next free, for logics that only get ready when something is freed.
+    UntilNextFree,
+}
+
+impl Default for PollStrategy {
+    fn default() -> PollStrategy { PollStrategy::Adaptive { cap: DEFAULT_POLL_CAP } }
+}
+
+impl PollStrategy {
+    /// Interval of the first poll, `None` if it never polls.
+    fn first(self) -> Option<Duration> {
+        match self {
+            PollStrategy::Adaptive { cap } => Some(cap),
+            PollStrategy::Fixed(interval) => Some(interval),
+            PollStrategy::Exponential { base, .. } => Some(base),
+            PollStrategy::UntilNextFree => None,
+        }
+    }
+
+    /// Interval of the poll following one of `interval`.
+    fn after(self, interval: Option<Duration>) -> Option<Duration> {
+        match self {
+            PollStrategy::Exponential { max, .. } => interval.map(|interval| interval.saturating_mul(2).min(max)),
+            _ => interval,
+        }
+    }
+
+    /// Time until the next wakeup, by `until_wakeup` if known, the poll of `interval` otherwise.
+    fn wakeup(self, until_wakeup: Option<Duration>, interval: Option<Duration>) -> Option<Duration> {
+        match (self, until_wakeup) {
+            (PollStrategy::Adaptive { cap }, Some(until_wakeup)) => Some(until_wakeup.min(cap)),
+            _ => until_wakeup.or(interval),
+        }
+    }
+}
+
+/// The earlier of two optional instants.
+fn earliest(a: Option<Instant>, b: Option<Instant>) -> Option<Instant> { a.into_iter().chain(b).min() }
+
+/// Order in which waiting callers are granted, set with `LimiterBuilder::fairness`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Fairness {
+    /// Whoever happens to take the lock first once capacity appears, late arrivals may overtake.
+    #[default]
+    Unordered,
+    /// In arrival order, by tickets taken on entering `sync`, at the lowest priority of `Limiter::sync_with_priority`.
+    /// Nobody is granted while someone queued before them is waiting, so `try_sync` fails while anyone waits.
+    ///
+    /// Costs throughput: a grant wakes every waiter just for the first one to go ahead, and capacity
+    /// the first waiter can't use, e.g. for a larger `State`, stays idle rather than going to the others.
+    Fifo,
+}
+
+/// Place of a waiter in the `WaitQueue`, left on drop so a cancelled wait does not block the queue.
+struct Ticket<'a> {
+    ticket: u64,
+    prio: u8,
+    queue: &'a std::sync::Mutex<WaitQueue>,
+    notify: &'a Notify,
+}
+
+impl<'a> Ticket<'a> {
+    fn new<Logic: logic::Logic<State>, State>(internal: &'a Shared<Logic, State>, prio: u8) -> Ticket<'a> {
+        let ticket = internal.queue.lock().unwrap_or_else(PoisonError::into_inner).push(prio);
+        Ticket { ticket, prio, queue: &internal.queue, notify: &internal.notify }
+    }
+
+    fn is_first(&self) -> bool { self.queue.lock().unwrap_or_else(PoisonError::into_inner).is_first(self.ticket) }
+
+    fn aging(&self) -> Option<Duration> { self.queue.lock().unwrap_or_else(PoisonError::into_inner).aging() }
+}
+
+impl Drop for Ticket<'_> {
+    fn drop(&mut self) {
+        if self.queue.lock().unwrap_or_else(PoisonError::into_inner).remove(self.prio, self.ticket) {
+            // it's the next one's turn
+            self.notify.notify_waiters();
+        }
+    }
+}
+
+/// Registers a batch waiting in `Limiter::sync_n` for as long as it is alive, so a cancelled wait
+/// does not keep holding the other callers back.
+struct PendingBatch(Arc<AtomicUsize>, Arc<Notify>);
+
+impl PendingBatch {
+    fn new(batches: Arc<AtomicUsize>, notify: Arc<Notify>) -> PendingBatch {
+        batches.fetch_add(1, atomic::Ordering::Relaxed);
+        PendingBatch(batches, notify)
+    }
+}
+
+impl Drop for PendingBatch {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, atomic::Ordering::Relaxed);
+        // the others may go ahead again
+        self.1.notify_waiters();
+    }
+}
+
+struct LogicWrapper<Logic: logic::Logic<State>, State> {
+    logic: Logic,
+    // delayed_frees: VecDeque<(Instant, State)>,
+    delayed_frees: BinaryHeap<HeapValue<Instant, State>>,
+    notify: Arc<Notify>,
+    maybe_ready: Arc<AtomicBool>,
+    queue: Arc<std::sync::Mutex<WaitQueue>>,
+    clock: Arc<dyn Clock>,
+    stats: Stats,
+    // frees not reported to `Limiter::on_event` yet
+    freed: usize,
+    // see `Limiter::pause`
+    paused: bool,
+    // see `Limiter::shutdown`
+    closed: bool,
+    // reservations held by permits, which are freed explicitly
+    held: usize,
+    // batches waiting in `Limiter::sync_n`, which hold back every other grant
+    batches: Arc<AtomicUsize>,
+    // see `Limiter::subscribe`
+    readiness: Arc<watch::Sender<bool>>,
+    // first grant held back until then, see `LimiterBuilder::startup_jitter`
+    not_before: Option<Instant>,
+    // reservations whose delay is too long to be scheduled, which are never freed
+    permanent: usize,
+    // see `LimiterBuilder::name`
+    name: Option<Arc<str>>,
+    // start of the current saturation, see `Stats::saturated_time`
+    saturated_since: Option<Instant>,
+}
+
+impl<Logic: logic::Logic<State>, State> LogicWrapper<Logic, State> {
+    pub fn new(
+        logic: Logic,
+        notify: Arc<Notify>,
+        maybe_ready: Arc<AtomicBool>,
+        queue: Arc<std::sync::Mutex<WaitQueue>>,
+        clock: Arc<dyn Clock>,
+    ) -> LogicWrapper<Logic, State> {
+        let mut wrapper = LogicWrapper {
+            readiness: Arc::new(watch::channel(logic.is_ready()).0),
+            logic,
+            delayed_frees: BinaryHeap::new(),
+            notify,
+            maybe_ready,
+            queue,
+            clock,
+            stats: Stats::default(),
+            freed: 0,
+            paused: false,
+            closed: false,
+            held: 0,
+            batches: Arc::new(AtomicUsize::new(0)),
+            not_before: None,
+            permanent: 0,
+            name: None,
+            saturated_since: None,
+        };
+        wrapper.saturated_since = (!wrapper.logic.is_ready()).then(|| wrapper.clock.now());
+        wrapper
+    }
+
+    pub fn ready(&mut self) -> bool { self.ready_for(None, None) }
+
+    /// Same as `ready`, but for `state` in particular, if any, see `Logic::is_ready_for`,
+    /// and the waiter holding `ticket` only has to wait for those queued before it.
+    pub fn ready_for(&mut self, state: Option<&State>, ticket: Option<u64>) -> bool {
+        self.cleanup();
+        // asked once, readiness may have side effects
+        let is_ready = self.is_open() && match state {
+            Some(state) => self.logic.is_ready_for(state),
+            None => self.logic.is_ready(),
+        };
+        self.maybe_ready.store(is_ready, atomic::Ordering::Relaxed);
+        self.publish(is_ready);
+
+        let queue = self.queue.lock().unwrap_or_else(PoisonError::into_inner);
+        let is_turn = ticket.map_or(queue.is_empty(), |ticket| queue.is_first(ticket));
+        drop(queue);
+        is_ready && is_turn
+    }
+
+    // not paused, not shut down, past the startup jitter and no batch waiting
+    fn is_open(&self) -> bool {
+        !self.paused && !self.closed && self.not_before.is_none() && self.batches.load(atomic::Ordering::Relaxed) == 0
+    }
+
+    /// Sends the readiness to the `Limiter::subscribe` receivers, only if it changed, so they are not woken by every grant.
+    /// Transitions are also what `Stats::saturated_time` is accounted on.
+    pub fn publish(&mut self, is_ready: bool) {
+        match (is_ready, self.saturated_since) {
+            (false, None) => self.saturated_since = Some(self.clock.now()),
+            (true, Some(since)) => {
+                self.stats.saturated_time += self.clock.now().saturating_duration_since(since);
+                self.saturated_since = None;
+            }
+            _ => {}
+        }
+        self.readiness.send_if_modified(|ready| std::mem::replace(ready, is_ready) != is_ready);
+    }
+
+    /// Same as `publish`, with the readiness checked now.
+    pub fn publish_readiness(&mut self) { self.publish(self.is_open() && self.logic.is_ready()); }
+
+    /// `Stats::saturated_time`, including the current saturation, if any.
+    fn saturated_time(&self) -> Duration {
+        let current = self.saturated_since.map(|since| self.clock.now().saturating_duration_since(since));
+        self.stats.saturated_time + current.unwrap_or_default()
+    }
+
+    pub fn add(&mut self, state: State) {
+        let (delay, state) = self.logic.reserve(state);
+        self.schedule(state, delay);
+    }
+
+    /// Adds `n` copies of `state` if all of them fit, trying them on a copy of the logic first.
+    pub fn try_add_n(&mut self, state: &State, n: u32) -> bool where Logic: Clone, State: Clone + PartialEq {
+        self.cleanup();
+        if self.paused || self.closed || self.not_before.is_some() {
+            return false;
+        }
+
+        // a batch not fitting even then never would, it goes alone like an oversized cost
+        let is_idle = matches!((self.logic.headroom(), self.logic.max_capacity()), (Some(headroom), Some(max)) if headroom == max);
+        let mut logic = self.logic.clone();
+        let mut reserved = Vec::with_capacity(n as usize);
+        for _ in 0..n {
+            if !(logic.is_ready_for(state) || is_idle && !reserved.is_empty()) {
+                return false;
+            }
+            reserved.push(logic.reserve(state.clone()));
+        }
+
+        self.logic = logic;
+        for (delay, state) in reserved {
+            self.schedule(state, delay);
+        }
+        true
+    }
+
+    /// Records a grant of `state` already added to the logic, to be freed after `delay`.
+    fn schedule(&mut self, state: State, delay: Duration) {
+        self.grant();
+        self.delay_free(state, delay);
+    }
+
+    fn grant(&mut self) {
+        self.stats.granted += 1;
+        #[cfg(feature = "metrics")]
+        self.count("ratelim.granted");
+    }
+
+    /// Counts a request turned away.
+    pub fn reject(&mut self) {
+        self.stats.rejected += 1;
+        #[cfg(feature = "metrics")]
+        self.count("ratelim.rejected");
+    }
+
+    /// Counts a wait of the `sync` family that ended in a grant.
+    pub fn waited(&mut self, wait: Duration) {
+        self.stats.total_wait += wait;
+        self.stats.wait_histogram.record(wait);
+        #[cfg(feature = "metrics")]
+        match &self.name {
+            Some(name) => metrics::histogram!("ratelim.wait_seconds", "limiter" => name.clone()).record(wait),
+            None => metrics::histogram!("ratelim.wait_seconds").record(wait),
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    fn count(&self, key: &'static str) {
+        match &self.name {
+            Some(name) => metrics::counter!(key, "limiter" => name.clone()).increment(1),
+            None => metrics::counter!(key).increment(1),
+        }
+    }
+
+    /// Frees `state` after `delay`.
+    fn delay_free(&mut self, state: State, delay: Duration) {
+        let is_ready = self.logic.is_ready();
+        self.maybe_ready.store(is_ready, atomic::Ordering::Relaxed);
+        self.publish(self.is_open() && is_ready);
+        // e.g. `Duration::MAX` for a quota consumed for good
+        let Some(delayed_for) = self.clock.now().checked_add(delay) else {
+            self.permanent += 1;
+            return;
+        };
+        let is_next = self.next_free().is_none_or(|next| delayed_for < next);
+        self.delayed_frees.push(HeapValue((delayed_for, state)));
+
+        // waiters sleep until the next free, let them reschedule if it moved closer
+        if is_next {
+            self.notify.notify_waiters();
+        }
+    }
+
+    /// Reserves `state` without scheduling a delayed free, the caller frees what is returned explicitly,
+    /// along with the logic's delay for a `Permit::commit`.
+    pub fn hold(&mut self, state: State) -> (Duration, State) {
+        self.grant();
+        self.held += 1;
+        let reserved = self.logic.reserve(state);
+        self.publish_readiness();
+        reserved
+    }
+
+    /// Frees `state` held by a permit right away and wakes up the waiters.
+    pub fn free(&mut self, state: &State) {
+        self.logic.free(state);
+        // a permit held across a `Limiter::reset` was already forgotten
+        self.held = self.held.saturating_sub(1);
+        self.freed += 1;
+        self.maybe_ready.store(true, atomic::Ordering::Relaxed);
+        self.publish_readiness();
+        self.notify.notify_waiters();
+    }
+
+    /// Instant of the earliest scheduled free, if any.
+    pub fn next_free(&self) -> Option<Instant> {
+        self.delayed_frees.peek().map(|HeapValue((delayed_for, _))| *delayed_for)
+    }
+
+    /// Time until the logic may be ready again, by the next free or by `Logic::ready_in`, whichever comes first.
+    pub fn until_wakeup(&self) -> Option<Duration> {
+        // the clock may not be the system one, so only the remaining time is meaningful
+        let now = self.clock.now();
+        let until_next_free = self.next_free().map(|next_free| next_free.saturating_duration_since(now));
+        let until_started = self.not_before.map(|not_before| not_before.saturating_duration_since(now));
+        until_next_free.into_iter().chain(until_started).chain(self.logic.ready_in()).min()
+    }
+
+    pub fn cleanup(&mut self) {
+        let now = self.clock.now();
+        if self.not_before.is_some_and(|not_before| now >= not_before) {
+            self.not_before = None;
+        }
+
+        let freed = ratelim_core::reclaim(&mut self.delayed_frees, &mut self.logic, now);
+        self.freed += freed;
+
+        if freed > 0 {
+            self.maybe_ready.store(true, atomic::Ordering::Relaxed);
+            self.publish_readiness();
+            self.notify.notify_waiters();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::sleep;
+    use crate::clock::MockClock;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+    use crate::logic::{Concurrency, FnLogic, QuotaPer, Timeout};
+    use super::*;
+
+    #[tokio::test]
+    async fn timeout() {
+        let clock = MockClock::new();
+        let limiter = Limiter::with_clock(Timeout::new(Duration::from_millis(500)), clock.clone());
+        for _ in 0..100 {
+            assert!(limiter.try_sync(()).await.is_ok());
+            assert!(limiter.try_sync(()).await.is_err());
+
+            clock.advance(Duration::from_millis(499));
+            assert!(limiter.try_sync(()).await.is_err());
+            clock.advance(Duration::from_millis(1));
+        }
+    }
+
+    #[tokio::test]
+    async fn quota() {
+        let clock = MockClock::new();
+        let limiter = Limiter::with_clock(QuotaPer::new(5, Duration::from_millis(500)), clock.clone());
+        for _ in 0..100 {
+            for _ in 0..5 {
+                assert!(limiter.try_sync(1).await.is_ok());
+            }
+            assert!(limiter.try_sync(1).await.is_err());
+
+            clock.advance(Duration::from_millis(499));
+            assert!(limiter.try_sync(1).await.is_err());
+            clock.advance(Duration::from_millis(1));
+        }
+    }
+
+    #[tokio::test]
+    async fn try_sync() {
+        let limiter = Limiter::new(QuotaPer::new(2, Duration::from_millis(100)));
+        assert!(limiter.try_sync(1).await.is_ok());
+        assert!(limiter.try_sync(1).await.is_ok());
+        assert!(limiter.try_sync(1).await.is_err());
+
+        sleep(Duration::from_millis(150)).await;
+        assert!(limiter.try_sync(1).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn try_sync_hands_back() {
+        let busy = Arc::new(AtomicBool::new(false));
+        let (ready, add, free) = (busy.clone(), busy.clone(), busy);
+        let limiter = Limiter::new(FnLogic::new(
+            move || !ready.load(atomic::Ordering::Relaxed),
+            move |_: &String| {
+                add.store(true, atomic::Ordering::Relaxed);
+                Duration::from_millis(50)
+            },
+            move |_: &String| free.store(false, atomic::Ordering::Relaxed),
+        ));
+
+        assert!(limiter.try_sync("first".to_string()).await.is_ok());
+        let rejected = limiter.try_sync("second".to_string()).await.unwrap_err();
+        assert_eq!(rejected, "second");
+
+        sleep(Duration::from_millis(100)).await;
+        assert!(limiter.try_sync(rejected).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn availability() {
+        let clock = MockClock::new();
+        let limiter = Limiter::with_clock(QuotaPer::new(2, Duration::from_secs(10)), clock.clone());
+        assert!(limiter.available().await);
+        assert_eq!(limiter.headroom().await, Some(2));
+
+        limiter.sync(1).await;
+        clock.advance(Duration::from_secs(1));
+        limiter.sync(1).await;
+        assert!(!limiter.available().await);
+        assert_eq!(limiter.headroom().await, Some(0));
+
+        // the first grant frees 9 seconds from now
+        let retry_after = limiter.next_available_at().await.unwrap() - Instant::now();
+        assert!(retry_after <= Duration::from_secs(9) && retry_after > Duration::from_secs(8), "{:?}", retry_after);
+
+        clock.advance(Duration::from_secs(9));
+        assert!(limiter.available().await);
+        assert_eq!(limiter.headroom().await, Some(1));
+    }
+
+    #[tokio::test]
+    async fn reconfigure() {
+        let limiter = Limiter::new(QuotaPer::new(2, Duration::from_secs(60)));
+        assert!(limiter.try_sync(1).await.is_ok());
+        assert!(limiter.try_sync(1).await.is_ok());
+
+        let waiter = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.sync(1).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!waiter.is_finished());
+
+        limiter.reconfigure(|logic| logic.set_quota(4)).await;
+        tokio::time::timeout(Duration::from_millis(100), waiter).await.unwrap().unwrap();
+        assert!(limiter.try_sync(1).await.is_ok());
+        assert!(limiter.try_sync(1).await.is_err());
+        assert_eq!(limiter.stats().await.current_pending, 4);
+    }
+
+    #[tokio::test]
+    async fn with_logic() {
+        let limiter = Limiter::new(QuotaPer::new(5, Duration::from_millis(50)));
+        limiter.sync(2).await;
+        let permit = limiter.acquire(1).await;
+        assert_eq!(limiter.with_logic(|logic| logic.used()).await, 3);
+
+        drop(permit);
+        assert_eq!(limiter.with_logic(QuotaPer::used).await, 2);
+    }
+
+    #[cfg(feature = "tokio-util")]
+    #[tokio::test]
+    async fn sync_cancellable() {
+        let limiter = Limiter::new(QuotaPer::new(1, Duration::from_secs(60)));
+        let token = tokio_util::sync::CancellationToken::new();
+        assert!(limiter.sync_cancellable(1, token.clone()).await.is_ok());
+
+        let waiter = tokio::spawn({
+            let (limiter, token) = (limiter.clone(), token.clone());
+            async move { limiter.sync_cancellable(1, token).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!waiter.is_finished());
+
+        token.cancel();
+        let cancelled = tokio::time::timeout(Duration::from_millis(100), waiter).await.unwrap().unwrap();
+        assert_eq!(cancelled, Err(Cancelled(1)));
+        assert_eq!(limiter.sync_cancellable(1, token).await, Err(Cancelled(1)));
+        assert_eq!(limiter.stats().await.granted, 1);
+        assert_eq!(limiter.headroom().await, Some(0));
+    }
+
+    #[tokio::test]
+    async fn startup_jitter() {
+        let window = Duration::from_millis(200);
+        let expected = window.mul_f64(crate::rng::Rng::new(42).next_f64());
+
+        let started = Instant::now();
+        let limiter = Limiter::builder(QuotaPer::new(5, Duration::from_secs(60)))
+            .startup_jitter(window)
+            .rng_seed(42)
+            .build();
+        assert!(limiter.try_sync(1).await.is_err());
+
+        limiter.sync(1).await;
+        let elapsed = started.elapsed();
+        assert!(elapsed >= expected, "{:?} < {:?}", elapsed, expected);
+        assert!(elapsed < expected + Duration::from_millis(50), "{:?} for {:?}", elapsed, expected);
+
+        // only the first grant is held back
+        assert!(limiter.try_sync(1).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn permanent_reservation() {
+        let used = Arc::new(AtomicUsize::new(0));
+        let (ready, add, free) = (used.clone(), used.clone(), used.clone());
+        let clock = MockClock::new();
+        let limiter = Limiter::with_clock(FnLogic::new(
+            move || ready.load(SeqCst) < 3,
+            move |permanent: &bool| {
+                add.fetch_add(1, SeqCst);
+                if *permanent { Duration::MAX } else { Duration::from_millis(50) }
+            },
+            move |_: &bool| { free.fetch_sub(1, SeqCst); },
+        ), clock.clone());
+
+        assert!(limiter.try_sync(true).await.is_ok());
+        assert!(limiter.try_sync(false).await.is_ok());
+        assert_eq!(limiter.pending().await, 1);
+
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(limiter.pending().await, 0);
+        assert_eq!(used.load(SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn permit_rollback() {
+        let limiter = Limiter::new(QuotaPer::new(1, Duration::from_secs(60)));
+        let permit = limiter.acquire(1).await;
+        assert!(limiter.try_sync(1).await.is_err());
+
+        // e.g. the operation failed right away, it shouldn't use up the quota
+        permit.rollback();
+        assert!(limiter.try_sync(1).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn permit_commit() {
+        let limiter = Limiter::new(QuotaPer::new(1, Duration::from_millis(50)));
+        let permit = limiter.acquire(1).await;
+        permit.commit().await;
+        assert_eq!(limiter.pending().await, 1);
+        assert!(limiter.try_sync(1).await.is_err());
+
+        sleep(Duration::from_millis(80)).await;
+        assert!(limiter.try_sync(1).await.is_ok());
+        assert_eq!(limiter.stats().await.granted, 2);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        // the recorder is only set for this thread, so is the runtime
+        metrics::with_local_recorder(&recorder, || {
+            tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap().block_on(async {
+                let limiter = Limiter::builder(QuotaPer::new(1, Duration::from_millis(20))).name("api").build();
+                assert!(limiter.try_sync(1).await.is_ok());
+                assert!(limiter.try_sync(1).await.is_err());
+                assert!(limiter.try_sync(1).await.is_err());
+                limiter.sync(1).await;
+            })
+        });
+
+        let metrics: Vec<_> = snapshotter.snapshot().into_vec().into_iter()
+            .map(|(key, _, _, value)| {
+                let key = key.key().clone();
+                assert!(key.labels().any(|label| label.key() == "limiter" && label.value() == "api"), "{:?}", key);
+                (key.name().to_string(), value)
+            })
+            .collect();
+        let value = |name: &str| metrics.iter().find(|(key, _)| key == name).map(|(_, value)| value);
+        assert_eq!(value("ratelim.granted"), Some(&DebugValue::Counter(2)));
+        assert_eq!(value("ratelim.rejected"), Some(&DebugValue::Counter(2)));
+        assert!(matches!(value("ratelim.wait_seconds"), Some(DebugValue::Histogram(waits)) if waits.len() == 1));
+    }
+
+    #[tokio::test]
+    async fn poll_ready() {
+        #[derive(Default)]
+        struct CountingWaker(AtomicUsize);
+
+        impl Wake for CountingWaker {
+            fn wake(self: Arc<Self>) { self.0.fetch_add(1, SeqCst); }
+        }
+
+        // no fallback polling, only the release may wake
+        let limiter = Limiter::builder(Concurrency::new(1)).poll_strategy(PollStrategy::UntilNextFree).build();
+        let mut cx = Context::from_waker(Waker::noop());
+        assert_eq!(limiter.poll_ready(&mut cx), Poll::Ready(()));
+
+        let permit = limiter.acquire(1).await;
+        assert_eq!(limiter.poll_ready(&mut cx), Poll::Pending);
+
+        let counting = Arc::new(CountingWaker::default());
+        let waker = Waker::from(counting.clone());
+        assert_eq!(limiter.poll_ready(&mut Context::from_waker(&waker)), Poll::Pending);
+
+        // the release wakes the waker up
+        drop(permit);
+        assert_eq!(counting.0.load(SeqCst), 1);
+        assert_eq!(limiter.poll_ready(&mut cx), Poll::Ready(()));
+    }
+
+    #[tokio::test]
+    async fn readiness_asked_once() {
+        #[derive(Default)]
+        struct Counting {
+            is_ready: Arc<AtomicUsize>,
+            is_ready_for: Arc<AtomicUsize>,
+        }
+
+        impl logic::Logic<u64> for Counting {
+            fn is_ready(&self) -> bool {
+                self.is_ready.fetch_add(1, SeqCst);
+                true
+            }
+
+            fn is_ready_for(&self, _: &u64) -> bool {
+                self.is_ready_for.fetch_add(1, SeqCst);
+                true
+            }
+
+            fn add_for(&mut self, _: &u64) -> Duration { Duration::from_secs(60) }
+
+            fn free(&mut self, _: &u64) {}
+        }
+
+        let logic = Counting::default();
+        let (is_ready, is_ready_for) = (logic.is_ready.clone(), logic.is_ready_for.clone());
+        let limiter = Limiter::new(logic);
+        let before = is_ready.load(SeqCst);
+        assert!(limiter.try_sync(1).await.is_ok());
+        // the readiness for the state decides alone, `is_ready` only republishes after the grant
+        assert_eq!(is_ready_for.load(SeqCst), 1);
+        assert_eq!(is_ready.load(SeqCst) - before, 1);
+    }
+
+    #[tokio::test]
+    async fn frees_what_was_reserved() {
+        // costs above 5 are clamped, the rest is not counted
+        struct Clamped {
+            used: u64,
+            freed: Arc<std::sync::Mutex<Vec<u64>>>,
+        }
+
+        impl logic::Logic<u64> for Clamped {
+            fn is_ready(&self) -> bool { self.used < 10 }
+
+            fn add_for(&mut self, cost: &u64) -> Duration {
+                self.used += (*cost).min(5);
+                Duration::from_millis(50)
+            }
+
+            fn free(&mut self, cost: &u64) {
+                self.used -= cost;
+                self.freed.lock().unwrap().push(*cost);
+            }
+
+            fn reserve(&mut self, cost: u64) -> (Duration, u64) { (self.add_for(&cost), cost.min(5)) }
+        }
+
+        let freed = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let clock = MockClock::new();
+        let limiter = Limiter::with_clock(Clamped { used: 0, freed: freed.clone() }, clock.clone());
+        assert!(limiter.try_sync(8).await.is_ok());
+        assert!(limiter.try_sync(2).await.is_ok());
+        drop(limiter.acquire(9).await);
+        assert_eq!(*freed.lock().unwrap(), vec![5]);
+
+        clock.advance(Duration::from_millis(50));
+        assert_eq!(limiter.pending().await, 0);
+        assert_eq!(limiter.with_logic(|logic| logic.used).await, 0);
+
+        let mut freed = freed.lock().unwrap().clone();
+        freed.sort();
+        assert_eq!(freed, vec![2, 5, 5]);
+    }
+
+    #[tokio::test]
+    async fn reject_info() {
+        let limiter = Limiter::new(QuotaPer::new(2, Duration::from_millis(100)));
+        assert!(limiter.try_sync_info(1).await.is_ok());
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(limiter.try_sync_info(1).await.is_ok());
+
+        // the first grant is freed first
+        let rejected = limiter.try_sync_info(1).await.unwrap_err();
+        assert_eq!(rejected.state, 1);
+        let retry_after = rejected.retry_after.unwrap();
+        assert!(retry_after > Duration::from_millis(50) && retry_after <= Duration::from_millis(80), "{retry_after:?}");
+        assert_eq!(limiter.stats().await.rejected, 1);
+
+        tokio::time::sleep(retry_after).await;
+        assert!(limiter.try_sync_info(1).await.is_ok());
+
+        // held by a permit, nothing to go by
+        let limiter = Limiter::new(QuotaPer::new(1, Duration::from_millis(100)));
+        let _permit = limiter.acquire(1).await;
+        assert_eq!(limiter.try_sync_info(1).await, Err(RejectInfo { state: 1, retry_after: None }));
+        assert_eq!(RejectInfo { state: 1, retry_after: None }.to_string(), "rate limited");
+    }
+
+    #[tokio::test]
+    async fn deferred_cost() {
+        let limiter = Limiter::new(QuotaPer::new(10, Duration::from_millis(50)));
+        assert!(limiter.try_sync(7).await.is_ok());
+        // only 3 left, ready as it is but not for 5
+        assert!(limiter.available().await);
+        assert_eq!(limiter.try_sync(5).await, Err(5));
+        assert!(limiter.try_sync(3).await.is_ok());
+
+        let started = Instant::now();
+        limiter.sync(5).await;
+        assert!(started.elapsed() >= Duration::from_millis(40), "{:?}", started.elapsed());
+        // never over the quota
+        assert_eq!(limiter.headroom().await, Some(5));
+    }
+
+    #[tokio::test]
+    async fn heap_capacity() {
+        let limiter = Limiter::builder(QuotaPer::new(100, Duration::from_secs(60))).heap_capacity(100).build();
+        let capacity = limiter.internal.lock().await.delayed_frees.capacity();
+        assert!(capacity >= 100, "{capacity}");
+
+        for _ in 0..100 {
+            assert!(limiter.try_sync(1).await.is_ok());
+        }
+        // not reallocated
+        assert_eq!(limiter.internal.lock().await.delayed_frees.capacity(), capacity);
+    }
+
+    #[tokio::test]
+    async fn pause() {
+        let limiter = Limiter::new(QuotaPer::new(2, Duration::from_secs(60)));
+        limiter.pause().await;
+        assert!(limiter.try_sync(1).await.is_err());
+
+        let waiter = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.sync(1).await }
+        });
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+        assert_eq!(limiter.stats().await.granted, 0);
+
+        limiter.resume().await;
+        tokio::time::timeout(Duration::from_millis(100), waiter).await.unwrap().unwrap();
+        assert!(limiter.try_sync(1).await.is_ok());
+        assert!(limiter.try_sync(1).await.is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[tokio::test]
+    async fn export_import() {
+        let clock = MockClock::new();
+        let limiter = Limiter::with_clock(QuotaPer::new(2, Duration::from_millis(200)), clock.clone());
+        assert!(limiter.try_sync(1).await.is_ok());
+        clock.advance(Duration::from_millis(100));
+        assert!(limiter.try_sync(1).await.is_ok());
+        assert!(limiter.try_sync(1).await.is_err());
+
+        let exported = serde_json::to_string(&limiter.export_state().await).unwrap();
+
+        let clock = MockClock::new();
+        let restored = Limiter::with_clock(QuotaPer::new(2, Duration::from_millis(200)), clock.clone());
+        restored.import_state(serde_json::from_str(&exported).unwrap()).await;
+        assert!(restored.try_sync(1).await.is_err());
+        assert_eq!(restored.stats().await.current_pending, 2);
+
+        clock.advance(Duration::from_millis(100));
+        assert!(restored.try_sync(1).await.is_ok());
+        assert!(restored.try_sync(1).await.is_err());
+        clock.advance(Duration::from_millis(100));
+        assert!(restored.try_sync(1).await.is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn contended_fast_path() {
+        // permits are released without delayed frees, so waiters rely on the polling fallback
+        let limiter = Limiter::new(Concurrency::new(4));
+        let in_flight = Arc::new(AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..64).map(|_| {
+            let (limiter, in_flight) = (limiter.clone(), in_flight.clone());
+            tokio::spawn(async move {
+                for _ in 0..20 {
+                    let _permit = limiter.acquire(1).await;
+                    assert!(in_flight.fetch_add(1, SeqCst) < 4);
+                    tokio::task::yield_now().await;
+                    in_flight.fetch_sub(1, SeqCst);
+                }
+            })
+        }).collect();
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            for task in tasks {
+                task.await.unwrap();
+            }
+        }).await.unwrap();
+
+        assert_eq!(limiter.stats().await.granted, 64 * 20);
+        assert_eq!(limiter.headroom().await, Some(4));
+    }
+
+    #[tokio::test]
+    async fn subscribe() {
+        let limiter = Limiter::new(Concurrency::new(2));
+        let mut readiness = limiter.subscribe();
+        assert!(*readiness.borrow_and_update());
+
+        // still ready after the first permit, only the second one flips it
+        let first = limiter.acquire(1).await;
+        assert!(!readiness.has_changed().unwrap());
+        let second = limiter.acquire(1).await;
+        assert!(readiness.has_changed().unwrap());
+        assert!(!*readiness.borrow_and_update());
+
+        let release = tokio::spawn(async move {
+            sleep(Duration::from_millis(20)).await;
+            drop(second);
+        });
+        tokio::time::timeout(Duration::from_millis(500), readiness.changed()).await.unwrap().unwrap();
+        assert!(*readiness.borrow_and_update());
+        release.await.unwrap();
+        drop(first);
+    }
+
+    #[tokio::test]
+    async fn sync_many() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let limiter = {
+            let runs = runs.clone();
+            Limiter::new(QuotaPer::new(10, Duration::from_millis(50))).on_event(move |event| {
+                if event == Event::Granted {
+                    runs.fetch_add(1, SeqCst);
+                }
+            })
+        };
+
+        let started = Instant::now();
+        limiter.sync_many(std::iter::repeat_n(1, 50)).await;
+        let elapsed = started.elapsed();
+
+        // 10 right away, then 10 per free of the previous run
+        assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(600), "{:?}", elapsed);
+        assert_eq!(limiter.stats().await.granted, 50);
+        // about one lock per run of 10, where a `sync` loop would lock at least once per item
+        assert!(runs.load(SeqCst) <= 10, "{}", runs.load(SeqCst));
+    }
+
+    #[tokio::test]
+    async fn sync_n() {
+        let limiter = Limiter::new(QuotaPer::new(5, Duration::from_millis(50)));
+        assert!(limiter.try_sync(1).await.is_ok());
+        assert!(limiter.try_sync(1).await.is_ok());
+        // 3 of 4 would fit, none is granted
+        assert!(!limiter.try_sync_n(1, 4).await);
+        assert!(limiter.try_sync_n(1, 3).await);
+        assert!(limiter.try_sync(1).await.is_err());
+        assert_eq!(limiter.stats().await.granted, 5);
+
+        // singles keep arriving, the batch still gets its turn
+        let limiter = Limiter::new(QuotaPer::new(5, Duration::from_millis(20)));
+        let singles: Vec<_> = (0..8).map(|_| {
+            let limiter = limiter.clone();
+            tokio::spawn(async move {
+                for _ in 0..20 {
+                    limiter.sync(1).await;
+                }
+            })
+        }).collect();
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        tokio::time::timeout(Duration::from_millis(200), limiter.sync_n(1, 5)).await.unwrap();
+        // granted in one go, so never more than the quota at once
+        assert_eq!(limiter.headroom().await, Some(0));
+
+        for single in singles {
+            single.await.unwrap();
+        }
+        assert_eq!(limiter.stats().await.granted, 8 * 20 + 5);
+    }
+
+    #[tokio::test]
+    async fn cancelled_sync_n() {
+        let limiter = Limiter::new(QuotaPer::new(2, Duration::from_millis(30)));
+        assert!(limiter.try_sync(1).await.is_ok());
+        assert!(tokio::time::timeout(Duration::from_millis(10), limiter.sync_n(1, 3)).await.is_err());
+
+        // the batch gave up, nobody is held back anymore
+        assert!(limiter.try_sync(1).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn oversized_sync_n() {
+        let limiter = Limiter::new(QuotaPer::new(2, Duration::from_millis(30)));
+        assert!(limiter.try_sync(1).await.is_ok());
+        assert!(!limiter.try_sync_n(1, 3).await);
+
+        // granted alone once the single is freed, rather than holding everyone back forever
+        tokio::time::timeout(Duration::from_millis(200), limiter.sync_n(1, 3)).await.unwrap();
+        assert!(limiter.try_sync(1).await.is_err());
+        assert_eq!(limiter.stats().await.granted, 4);
+    }
+
+    #[tokio::test]
+    async fn fifo() {
+        let limiter = Limiter::builder(Concurrency::new(1)).fairness(Fairness::Fifo).build();
+        let held = limiter.acquire(1).await;
+
+        let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let waiters: Vec<_> = (0..1000).map(|i| {
+            let (limiter, order) = (limiter.clone(), order.clone());
+            tokio::spawn(async move {
+                let _permit = limiter.acquire(1).await;
+                order.lock().unwrap().push(i);
+                // let the others race for the lock before the permit is released
+                tokio::task::yield_now().await;
+            })
+        }).collect();
+
+        // every waiter has taken its ticket
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(limiter.try_sync(1).await.is_err());
+        drop(held);
+
+        for waiter in waiters {
+            waiter.await.unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), (0..1000).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn fifo_cancelled() {
+        let limiter = Limiter::builder(QuotaPer::new(1, Duration::from_millis(20))).fairness(Fairness::Fifo).build();
+        assert!(limiter.try_sync(1).await.is_ok());
+        // the first in line gives up, the second must not be stuck behind it
+        assert!(limiter.sync_timeout(1, Duration::from_millis(5)).await.is_err());
+        tokio::time::timeout(Duration::from_millis(100), limiter.sync(1)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn priority() {
+        let limiter = Limiter::new(QuotaPer::new(1, Duration::from_millis(2)));
+        assert!(limiter.try_sync(1).await.is_ok());
+
+        let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let spawn = |prio| {
+            let (limiter, order) = (limiter.clone(), order.clone());
+            tokio::spawn(async move {
+                limiter.sync_with_priority(1, prio).await;
+                order.lock().unwrap().push(prio);
+            })
+        };
+        // low priorities arrive first, yet the high ones go ahead
+        let mut waiters: Vec<_> = (0..20).map(|_| spawn(0)).collect();
+        tokio::task::yield_now().await;
+        waiters.extend((0..20).map(|_| spawn(10)));
+        waiters.push(tokio::spawn({
+            let (limiter, order) = (limiter.clone(), order.clone());
+            async move {
+                limiter.sync(1).await;
+                order.lock().unwrap().push(u8::MAX);
+            }
+        }));
+
+        for waiter in waiters {
+            waiter.await.unwrap();
+        }
+        let expected: Vec<u8> = [vec![10; 20], vec![0; 20], vec![u8::MAX]].concat();
+        assert_eq!(*order.lock().unwrap(), expected);
+    }
+
+    #[tokio::test]
+    async fn priority_aging() {
+        let limiter = Limiter::builder(Concurrency::new(1)).priority_aging(Duration::from_millis(10)).build();
+        let held = limiter.acquire(1).await;
+
+        let low = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.sync_with_priority(1, 0).await }
+        });
+        // by now the low priority has aged past the high one
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        let high = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.sync_with_priority(1, 3).await }
+        });
+        tokio::time::sleep(Duration::from_millis(5)).await;
+
+        drop(held);
+        tokio::time::timeout(Duration::from_millis(100), low).await.unwrap().unwrap();
+        assert!(!high.is_finished());
+    }
+
+    #[tokio::test]
+    async fn sync_timed() {
+        let limiter = Limiter::new(QuotaPer::new(1, Duration::from_millis(30)));
+        assert!(limiter.sync_timed(1).await < Duration::from_millis(10));
+        let waited = limiter.sync_timed(1).await;
+        assert!(waited >= Duration::from_millis(25), "{:?}", waited);
+    }
+
+    #[cfg(feature = "async-std")]
+    #[async_std::test]
+    async fn async_std_runtime() {
+        let limiter = Limiter::new(QuotaPer::new(2, Duration::from_millis(50)));
+        let started = Instant::now();
+        for _ in 0..6 {
+            limiter.sync(1).await;
+        }
+        assert!(started.elapsed() >= Duration::from_millis(100), "{:?}", started.elapsed());
+        assert!(limiter.sync_timeout(3, Duration::from_millis(10)).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn stats() {
+        let limiter = Limiter::new(QuotaPer::new(2, Duration::from_millis(50)));
+        assert!(limiter.try_sync(1).await.is_ok());
+        assert!(limiter.try_sync(1).await.is_ok());
+        assert!(limiter.try_sync(1).await.is_err());
+        assert!(limiter.try_sync(1).await.is_err());
+        // saturated since the second grant, by however long the calls took
+        let stats = limiter.stats().await;
+        assert_eq!(stats, Stats {
+            granted: 2,
+            rejected: 2,
+            total_wait: Duration::ZERO,
+            current_pending: 2,
+            wait_histogram: WaitHistogram::default(),
+            saturated_time: stats.saturated_time,
+        });
+
+        limiter.sync(1).await;
+        limiter.sync(1).await;
+        assert_eq!(limiter.sync_timeout(1, Duration::from_millis(10)).await, Err(1));
+        let stats = limiter.stats().await;
+        assert_eq!((stats.granted, stats.rejected, stats.current_pending), (4, 3, 2));
+        assert!(stats.total_wait >= Duration::from_millis(40), "{:?}", stats.total_wait);
+    }
+
+    #[tokio::test]
+    async fn saturated_time() {
+        let clock = MockClock::new();
+        let limiter = Limiter::with_clock(QuotaPer::new(1, Duration::from_millis(50)), clock.clone());
+        assert!(limiter.try_sync(1).await.is_ok());
+        clock.advance(Duration::from_millis(30));
+        assert!(limiter.try_sync(1).await.is_err());
+        assert_eq!(limiter.stats().await.saturated_time, Duration::from_millis(30));
+
+        // the free ends the saturation, the grant right after starts the next one
+        clock.advance(Duration::from_millis(20));
+        assert!(limiter.try_sync(1).await.is_ok());
+        clock.advance(Duration::from_millis(20));
+        let stats = limiter.stats().await;
+        assert_eq!(stats.saturated_time, Duration::from_millis(70));
+        assert!((stats.utilization(Duration::from_millis(100)) - 0.7).abs() < 1e-9);
+        assert_eq!(stats.utilization(Duration::from_millis(10)), 1.0);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn contention_jitter() {
+        const WAITERS: usize = 50_000;
+        // every lock of a waiter checks the logic once
+        let checks = Arc::new(AtomicUsize::new(0));
+        let used = Arc::new(AtomicUsize::new(0));
+        let (counted, in_use, added, freed) = (checks.clone(), used.clone(), used.clone(), used);
+        let limiter = Limiter::new(FnLogic::new(
+            move || {
+                counted.fetch_add(1, SeqCst);
+                in_use.load(SeqCst) == 0
+            },
+            move |_: &()| {
+                added.fetch_add(1, SeqCst);
+                Duration::from_millis(2)
+            },
+            move |_: &()| {
+                freed.fetch_sub(1, SeqCst);
+            },
+        ));
+
+        let granted = Arc::new(AtomicUsize::new(0));
+        let tasks: Vec<_> = (0..WAITERS)
+            .map(|_| {
+                let limiter = limiter.clone();
+                let granted = granted.clone();
+                tokio::spawn(async move {
+                    limiter.sync(()).await;
+                    granted.fetch_add(1, SeqCst);
+                })
+            })
+            .collect();
+        sleep(Duration::from_millis(300)).await;
+
+        // in lockstep, every waiter would lock on each of the 100 frees and grants of the window
+        let (checks_before, granted_before) = (checks.load(SeqCst), granted.load(SeqCst));
+        sleep(Duration::from_millis(200)).await;
+        let checks = checks.load(SeqCst) - checks_before;
+        assert!(checks < 4 * WAITERS, "{} checks", checks);
+        assert!(granted.load(SeqCst) > granted_before);
+
+        for task in tasks {
+            task.abort();
+        }
+    }
+
+    #[test]
+    fn wait_histogram() {
+        let mut histogram = WaitHistogram::default();
+        assert_eq!(histogram.percentile(0.5), Duration::ZERO);
+
+        for micros in [0, 1, 3, 700, 1000, 1023, 1024, 5000, 90_000, 90_000] {
+            histogram.record(Duration::from_micros(micros));
+        }
+        histogram.record(Duration::from_secs(3600));
+        assert_eq!(histogram.count(), 11);
+
+        assert_eq!(histogram.percentile(0.0), Duration::from_micros(1));
+        assert_eq!(histogram.percentile(0.25), Duration::from_micros(4));
+        assert_eq!(histogram.percentile(0.5), Duration::from_micros(1024));
+        assert_eq!(histogram.percentile(0.7), Duration::from_micros(8192));
+        assert_eq!(histogram.percentile(0.9), Duration::from_micros(131_072));
+        assert_eq!(histogram.percentile(1.0), Duration::MAX);
+    }
+
+    #[tokio::test]
+    async fn wait_percentile() {
+        let limiter = Limiter::new(QuotaPer::new(10, Duration::from_millis(50)));
+        for _ in 0..10 {
+            limiter.sync(1).await;
+        }
+        limiter.sync(1).await;
+
+        // ten grants right away, one after about 50ms
+        let stats = limiter.stats().await;
+        assert_eq!(stats.wait_histogram.count(), 11);
+        assert!(stats.wait_percentile(0.5) <= Duration::from_millis(2), "{:?}", stats.wait_percentile(0.5));
+        let p99 = stats.wait_percentile(0.99);
+        assert!(p99 >= Duration::from_millis(32) && p99 <= Duration::from_millis(132), "{:?}", p99);
+    }
+
+    #[tokio::test]
+    async fn on_event() {
+        let events = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let limiter = {
+            let events = events.clone();
+            Limiter::new(QuotaPer::new(1, Duration::from_millis(20))).on_event(move |event| events.lock().unwrap().push(event))
+        };
+
+        assert!(limiter.try_sync(1).await.is_ok());
+        assert!(limiter.try_sync(1).await.is_err());
+        limiter.sync(1).await;
+        assert_eq!(*events.lock().unwrap(), [Event::Granted, Event::Rejected, Event::WaitStarted, Event::Freed, Event::Granted]);
+
+        // re-entering the limiter from the callback does not deadlock
+        let reentrant = limiter.clone();
+        let limiter = limiter.on_event(move |_| assert!(runtime::try_lock(&reentrant.internal.wrapper).is_some()));
+        assert!(limiter.try_sync(1).await.is_err());
+    }
+
+    #[cfg(feature = "tracing")]
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn tracing() {
+        let limiter = Limiter::new(QuotaPer::new(1, Duration::from_millis(20)));
+        limiter.sync(1).await;
+        assert!(logs_contain("granted"));
+        assert!(limiter.try_sync(1).await.is_err());
+        assert!(logs_contain("rejected"));
+
+        limiter.sync(1).await;
+        assert!(logs_contain("freed"));
+        assert!(logs_contain("wait="));
+    }
+
+    #[tokio::test]
+    async fn sync_timeout() {
+        let limiter = Limiter::new(QuotaPer::new(1, Duration::from_millis(200)));
+        assert_eq!(limiter.sync_timeout(1, Duration::from_millis(50)).await, Ok(()));
+        assert_eq!(limiter.sync_timeout(2, Duration::from_millis(50)).await, Err(2));
+        assert_eq!(limiter.sync_timeout(3, Duration::from_millis(300)).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn sync_checked() {
+        let limiter = Limiter::new(QuotaPer::new(5, Duration::from_secs(60)));
+        let result = tokio::time::timeout(Duration::from_secs(1), limiter.sync_checked(10)).await;
+        assert_eq!(result, Ok(Err(NeverSatisfiable(10))));
+        assert_eq!(limiter.sync_checked(5).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn sync_deadline() {
+        let limiter = Limiter::new(QuotaPer::new(1, Duration::from_millis(200)));
+        limiter.sync(1).await;
+
+        let deadline = Instant::now() + Duration::from_millis(50);
+        assert_eq!(limiter.sync_deadline(2, deadline).await, Err(2));
+        assert!(Instant::now() >= deadline);
+
+        let deadline = Instant::now() + Duration::from_millis(300);
+        assert_eq!(limiter.sync_deadline(3, deadline).await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn permit() {
+        let limiter = Limiter::new(QuotaPer::new(2, Duration::from_secs(60)));
+        let first = limiter.acquire(1).await;
+        let _second = limiter.acquire(1).await;
+        assert!(limiter.try_sync(1).await.is_err());
+
+        drop(first);
+        assert!(limiter.try_sync(1).await.is_ok());
+        assert!(limiter.try_sync(1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn pending() {
+        let limiter = Limiter::builder(QuotaPer::new(2, Duration::from_millis(50))).fairness(Fairness::Fifo).build();
+        assert_eq!(limiter.pending().await, 0);
+        limiter.sync(1).await;
+        limiter.sync(1).await;
+        assert_eq!(limiter.pending().await, 2);
+
+        let waiter = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.sync(1).await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(limiter.depth().await, 1);
+
+        waiter.await.unwrap();
+        assert_eq!(limiter.depth().await, 0);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert_eq!(limiter.pending().await, 0);
+    }
+
+    #[tokio::test]
+    async fn debug() {
+        let limiter = Limiter::new(QuotaPer::new(3, Duration::from_secs(60)));
+        limiter.sync(1).await;
+        let debug = format!("{:?}", limiter);
+        assert!(debug.contains("pending: 1"), "{}", debug);
+
+        let _locked = limiter.internal.lock().await;
+        assert!(format!("{:?}", limiter).contains("<locked>"));
+        assert_eq!(format!("{:?}", QuotaPer::new(3, Duration::from_secs(1))), "QuotaPer { quota: 3, state: 0, timeout: 1s, forgotten: 0 }");
+    }
+
+    #[tokio::test]
+    async fn reset() {
+        let limiter = Limiter::new(QuotaPer::new(3, Duration::from_secs(60)));
+        for _ in 0..3 {
+            assert!(limiter.try_sync(1).await.is_ok());
+        }
+        assert!(limiter.try_sync(1).await.is_err());
+
+        limiter.reset().await;
+        assert_eq!(limiter.stats().await, Stats::default());
+        for _ in 0..3 {
+            assert!(limiter.try_sync(1).await.is_ok());
+        }
+        assert!(limiter.try_sync(1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fork() {
+        let limiter = Limiter::new(QuotaPer::new(2, Duration::from_secs(60)));
+        assert!(limiter.try_sync(1).await.is_ok());
+
+        let fork = limiter.fork().await;
+        assert!(limiter.try_sync(1).await.is_ok());
+        assert!(limiter.try_sync(1).await.is_err());
+
+        // the reservations made here are not carried over
+        assert!(fork.try_sync(1).await.is_ok());
+        assert!(fork.try_sync(1).await.is_ok());
+        assert!(fork.try_sync(1).await.is_err());
+
+        // while a clone shares them
+        assert!(limiter.clone().try_sync(1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn shutdown() {
+        let limiter = Limiter::new(QuotaPer::new(3, Duration::from_secs(60)));
+        let first = limiter.acquire(1).await;
+        let second = limiter.acquire(1).await;
+
+        let shutdown = tokio::spawn({
+            let limiter = limiter.clone();
+            async move { limiter.shutdown().await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        assert!(limiter.try_sync(1).await.is_err());
+        assert_eq!(limiter.sync_unless_closed(1).await, Err(Closed(1)));
+        assert!(limiter.sync_timeout(1, Duration::from_secs(1)).await.is_err());
+        // reserves nothing, so it doesn't return either
+        assert!(tokio::time::timeout(Duration::from_millis(20), limiter.sync(1)).await.is_err());
+        assert!(tokio::time::timeout(Duration::from_millis(20), limiter.sync_many([1, 1])).await.is_err());
+
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!shutdown.is_finished());
+
+        drop(second);
+        tokio::time::timeout(Duration::from_millis(100), shutdown).await.unwrap().unwrap();
+    }
+
+    #[cfg(any(feature = "tokio", feature = "async-std"))]
+    #[tokio::test]
+    async fn background_reclaim() {
+        use crate::logic::Logic;
+
+        let limiter = Limiter::with_background_reclaim(Timeout::new(Duration::from_millis(50)), Duration::from_millis(10));
+        limiter.sync(()).await;
+        assert!(!limiter.internal.wrapper.lock().await.logic.is_ready());
+
+        sleep(Duration::from_millis(100)).await;
+        let internal = limiter.internal.wrapper.lock().await;
+        assert!(internal.logic.is_ready());
+        assert!(internal.delayed_frees.is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn cancellation() {
+        let limiter = Limiter::new(QuotaPer::new(5, Duration::from_millis(5)));
+        let completed = Arc::new(AtomicUsize::new(0));
+
+        // timeouts all around the grants, so some expire right as the capacity comes back
+        let tasks: Vec<_> = (0..200).map(|i| {
+            let (limiter, completed) = (limiter.clone(), completed.clone());
+            tokio::spawn(async move {
+                if tokio::time::timeout(Duration::from_micros(100 + i * 97 % 5000), limiter.sync(1)).await.is_ok() {
+                    completed.fetch_add(1, SeqCst);
+                }
+            })
+        }).collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        // every reservation made was observed by its caller
+        let stats = limiter.stats().await;
+        assert_eq!(stats.granted, completed.load(SeqCst) as u64);
+        assert!(stats.granted < 200, "{:?}", stats);
+        sleep(Duration::from_millis(10)).await;
+        assert_eq!(limiter.pending().await, 0);
+        assert!(limiter.try_sync(1).await.is_ok());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn never_over_quota() {
+        let limiter = Limiter::builder(QuotaPer::new(3, Duration::from_secs(60)))
+            .poll_strategy(PollStrategy::UntilNextFree)
+            .build();
+        let (held, max_held) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+
+        let tasks: Vec<_> = (0..2000).map(|_| {
+            let (limiter, held, max_held) = (limiter.clone(), held.clone(), max_held.clone());
+            tokio::spawn(async move {
+                let permit = limiter.acquire(1).await;
+                max_held.fetch_max(held.fetch_add(1, SeqCst) + 1, SeqCst);
+                tokio::task::yield_now().await;
+                held.fetch_sub(1, SeqCst);
+                drop(permit);
+            })
+        }).collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert!(max_held.load(SeqCst) <= 3, "{}", max_held.load(SeqCst));
+        assert_eq!(limiter.stats().await.granted, 2000);
+    }
+
+    /// Counts the sleeps, i.e. the wakeups of the waiters.
+    struct CountingSleeper(Arc<AtomicUsize>);
+
+    impl Sleeper for CountingSleeper {
+        fn sleep(&self, duration: Duration) -> sleeper::Sleep {
+            self.0.fetch_add(1, SeqCst);
+            Box::pin(sleep(duration))
+        }
+    }
+
+    #[tokio::test]
+    async fn poll_strategy() {
+        // a permit is held, so nothing is scheduled and the waiter polls until it is dropped
+        async fn wakeups(poll_strategy: PollStrategy) -> usize {
+            let sleeps = Arc::new(AtomicUsize::new(0));
+            let limiter = Limiter::builder(Concurrency::new(1))
+                .poll_strategy(poll_strategy)
+                .sleeper(CountingSleeper(sleeps.clone()))
+                .build();
+            let permit = limiter.acquire(1).await;
+
+            let waiter = tokio::spawn({
+                let limiter = limiter.clone();
+                async move { limiter.sync(1).await }
+            });
+            sleep(Duration::from_millis(200)).await;
+            drop(permit);
+            tokio::time::timeout(Duration::from_millis(100), waiter).await.unwrap().unwrap();
+            sleeps.load(SeqCst)
+        }
+
+        let fixed = wakeups(PollStrategy::Fixed(Duration::from_millis(1))).await;
+        let exponential = wakeups(PollStrategy::Exponential { base: Duration::from_millis(1), max: Duration::from_secs(1) }).await;
+        let until_next_free = wakeups(PollStrategy::UntilNextFree).await;
+        let adaptive = wakeups(PollStrategy::default()).await;
+        assert!(exponential * 5 < fixed, "exponential {}, fixed {}", exponential, fixed);
+        // every 10ms cap
+        assert!((5..=25).contains(&adaptive), "{}", adaptive);
+        assert!(exponential <= 10, "{}", exponential);
+        assert!(until_next_free <= 1, "{}", until_next_free);
+    }
+
+    #[tokio::test]
+    async fn sync_with() {
+        let sleeps = Arc::new(AtomicUsize::new(0));
+        let limiter = Limiter::builder(QuotaPer::new(10, Duration::from_millis(50)))
+            .sleeper(CountingSleeper(sleeps.clone()))
+            .build();
+        limiter.sync(8).await;
+
+        let calls = AtomicUsize::new(0);
+        let started = Instant::now();
+        limiter.sync_with(|| async {
+            calls.fetch_add(1, SeqCst);
+            tokio::task::yield_now().await;
+            5
+        }).await;
+
+        // woken a few times by the poll cap before the free, the cost was computed once
+        assert!(started.elapsed() >= Duration::from_millis(40), "{:?}", started.elapsed());
+        assert!(sleeps.load(SeqCst) > 1, "{}", sleeps.load(SeqCst));
+        assert_eq!(calls.load(SeqCst), 1);
+        assert_eq!(limiter.headroom().await, Some(5));
+    }
+
+    #[tokio::test]
+    async fn adaptive_wakeups() {
+        let sleeps = Arc::new(AtomicUsize::new(0));
+        let limiter = Limiter::builder(QuotaPer::new(1, Duration::from_millis(50)))
+            .sleeper(CountingSleeper(sleeps.clone()))
+            .build();
+        limiter.sync(1).await;
+
+        // woken at the free, checking on the way at most every 10ms rather than every 1ms
+        let started = Instant::now();
+        limiter.sync(1).await;
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(45) && elapsed < Duration::from_millis(100), "{:?}", elapsed);
+        assert!(sleeps.load(SeqCst) <= 7, "{}", sleeps.load(SeqCst));
+    }
+
+    #[tokio::test]
+    async fn wakeups() {
+        // polling alone would never make it in time
+        let limiter = Limiter::with_polling_timeout(QuotaPer::new(1, Duration::from_millis(50)), Duration::from_secs(60));
+        limiter.sync(1).await;
+
+        let started = Instant::now();
+        limiter.sync(1).await;
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let limiter = Limiter::with_polling_timeout(QuotaPer::new(1, Duration::from_secs(60)), Duration::from_secs(60));
+        let permit = limiter.acquire(1).await;
+        let waiter = {
+            let limiter = limiter.clone();
+            tokio::spawn(async move { limiter.sync(1).await })
+        };
+
+        sleep(Duration::from_millis(20)).await;
+        drop(permit);
+        tokio::time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap();
     }
 }
@@ -0,0 +1,196 @@
+//! Single-threaded limiter, for `current_thread` runtimes and `!Send` logics or states, e.g. `Rc`s.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+use ratelim_core::Core;
+use crate::logic::Logic;
+use crate::sleeper::{self, Sleeper};
+use crate::time::Instant;
+
+/// `Limiter` counterpart without the `Send` bounds, its clones share the logic through an `Rc<RefCell<_>>`,
+/// so they stay on one thread, e.g. in a `tokio::task::LocalSet`.
+///
+/// The logic is only borrowed between awaits, nothing is held while waiting, and waiters wake up
+/// at the next free or every `poll_timeout`, there is no `Notify`.
+///
+/// ## Example
+///
+/// ```
+/// use std::rc::Rc;
+/// use std::time::Duration;
+/// use ratelim::local::LocalLimiter;
+/// use ratelim::logic::Timeout;
+///
+/// # #[tokio::main(flavor = "current_thread")]
+/// # async fn main() {
+/// let limiter = LocalLimiter::new(Timeout::new(Duration::from_millis(10)));
+/// let local = tokio::task::LocalSet::new();
+/// local.run_until(async move {
+///     let request = Rc::new(());
+///     tokio::task::spawn_local(async move { limiter.sync(()).await; drop(request) }).await.unwrap();
+/// }).await;
+/// # }
+/// ```
+pub struct LocalLimiter<L: Logic<State>, State> {
+    internal: Rc<RefCell<Core<L, State, LocalClock>>>,
+    poll_timeout: Duration,
+    sleeper: Arc<dyn Sleeper>,
+    #[cfg(feature = "tokio")]
+    reclaim: Option<Rc<LocalReclaimTask>>,
+}
+
+impl<L: Logic<State>, State> Clone for LocalLimiter<L, State> {
+    fn clone(&self) -> LocalLimiter<L, State> {
+        LocalLimiter {
+            internal: self.internal.clone(),
+            poll_timeout: self.poll_timeout,
+            sleeper: self.sleeper.clone(),
+            #[cfg(feature = "tokio")]
+            reclaim: self.reclaim.clone(),
+        }
+    }
+}
+
+impl<L: Logic<State>, State> LocalLimiter<L, State> {
+    pub fn new(logic: L) -> LocalLimiter<L, State> {
+        LocalLimiter::with_poll_timeout(logic, Duration::from_millis(1))
+    }
+
+    pub fn with_poll_timeout(logic: L, poll_timeout: Duration) -> LocalLimiter<L, State> {
+        LocalLimiter {
+            internal: Rc::new(RefCell::new(Core::new(logic, LocalClock))),
+            poll_timeout,
+            sleeper: sleeper::default_sleeper(),
+            #[cfg(feature = "tokio")]
+            reclaim: None,
+        }
+    }
+
+    /// Waits with `sleeper` rather than the default `Sleeper`.
+    pub fn with_sleeper(mut self, sleeper: impl Sleeper + 'static) -> LocalLimiter<L, State> {
+        self.sleeper = Arc::new(sleeper);
+        self
+    }
+
+    pub async fn sync(&self, state: State) {
+        loop {
+            let until_next_free = {
+                let mut internal = self.internal.borrow_mut();
+                if internal.ready_for(&state) {
+                    internal.add(state);
+                    return;
+                }
+
+                internal.next_free().map(|next_free| next_free.saturating_duration_since(Instant::now()))
+            };
+
+            self.sleeper.sleep(until_next_free.unwrap_or(self.poll_timeout)).await;
+        }
+    }
+
+    /// Non-blocking version of `sync`. Hands the `state` back instead of waiting if the logic is not ready.
+    pub fn try_sync(&self, state: State) -> Result<(), State> { self.internal.borrow_mut().try_add(state) }
+
+    /// Number of reservations waiting for their delayed free.
+    pub fn pending(&self) -> usize {
+        let mut internal = self.internal.borrow_mut();
+        internal.cleanup();
+        internal.pending()
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<L: Logic<State> + 'static, State: 'static> LocalLimiter<L, State> {
+    /// Spawns a task with `tokio::task::spawn_local` reclaiming expired reservations every `interval`,
+    /// see `LimiterBuilder::background_reclaim`. Has to be called within a `LocalSet`.
+    pub fn background_reclaim(mut self, interval: Duration) -> LocalLimiter<L, State> {
+        let internal = Rc::downgrade(&self.internal);
+        let sleeper = self.sleeper.clone();
+        let task = tokio::task::spawn_local(async move {
+            loop {
+                sleeper.sleep(interval).await;
+
+                let Some(internal) = internal.upgrade() else { break };
+                internal.borrow_mut().cleanup();
+            }
+        });
+        self.reclaim = Some(Rc::new(LocalReclaimTask(task)));
+        self
+    }
+}
+
+// only kept for stopping the task once dropped
+#[cfg(feature = "tokio")]
+struct LocalReclaimTask(tokio::task::JoinHandle<()>);
+
+#[cfg(feature = "tokio")]
+impl Drop for LocalReclaimTask {
+    fn drop(&mut self) { self.0.abort(); }
+}
+
+/// `crate::time::Instant::now()` for the `Core`.
+struct LocalClock;
+
+impl ratelim_core::Clock for LocalClock {
+    type Instant = Instant;
+
+    fn now(&self) -> Instant { Instant::now() }
+
+    fn checked_add(instant: Instant, delay: Duration) -> Option<Instant> { instant.checked_add(delay) }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use crate::logic::FnLogic;
+    use super::*;
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn rc_state() {
+        // neither the logic nor the state are `Send`
+        let busy = Rc::new(Cell::new(false));
+        let (ready, add, free) = (busy.clone(), busy.clone(), busy);
+        let limiter = LocalLimiter::new(FnLogic::new(
+            move || !ready.get(),
+            move |_: &Rc<String>| {
+                add.set(true);
+                Duration::from_millis(20)
+            },
+            move |_: &Rc<String>| free.set(false),
+        ));
+
+        let local = tokio::task::LocalSet::new();
+        local.run_until(async move {
+            let started = Instant::now();
+            let tasks: Vec<_> = (0..3)
+                .map(|i| {
+                    let limiter = limiter.clone();
+                    tokio::task::spawn_local(async move { limiter.sync(Rc::new(i.to_string())).await })
+                })
+                .collect();
+            for task in tasks {
+                task.await.unwrap();
+            }
+
+            assert!(started.elapsed() >= Duration::from_millis(40), "{:?}", started.elapsed());
+            assert!(limiter.try_sync(Rc::new("late".to_string())).is_err());
+        }).await;
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(flavor = "current_thread")]
+    async fn background_reclaim() {
+        let local = tokio::task::LocalSet::new();
+        local.run_until(async {
+            let limiter = LocalLimiter::new(crate::logic::QuotaPer::new(2, Duration::from_millis(20)))
+                .background_reclaim(Duration::from_millis(5));
+            assert!(limiter.try_sync(1).is_ok());
+            assert_eq!(limiter.internal.borrow().pending(), 1);
+
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(limiter.internal.borrow().pending(), 0);
+        }).await;
+    }
+}
@@ -1,94 +1,2493 @@
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::future::Future;
+use std::hash::Hash;
 use std::time::Duration;
+use crate::time::{Instant, SystemTime, UNIX_EPOCH};
+use crate::clock::{Clock, SystemClock};
+use crate::rng::{AtomicRng, Rng};
 
-/// **LimiterLogic** requires minimum code to represent the limitations logic.
-/// Thread safety is promised by the Limiter implementation.
-/// Timeouts are specified inside of `fn add_for(...) -> Duration` and could vary,
-/// depending on the function result.
+pub use ratelim_core::{Logic, QuotaPer, Rate, Timeout, Weight};
+
+/// Async counterpart of `Logic`, for logics that need to await I/O, e.g. a shared counter kept
+/// in a database. Used by `asynchronous::AsyncLimiter`, wrap synchronous logics in `FromSync`.
+pub trait AsyncLogic<State> {
+    fn is_ready(&self) -> impl Future<Output=bool> + Send;
+
+    fn add_for(&mut self, state: &State) -> impl Future<Output=Duration> + Send;
+
+    fn free(&mut self, state: &State) -> impl Future<Output=()> + Send;
+}
+
+/// Adapts a synchronous `Logic` to `AsyncLogic`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FromSync<L> {
+    logic: L,
+}
+
+impl<L> FromSync<L> {
+    pub fn new(logic: L) -> FromSync<L> { FromSync { logic } }
+
+    pub fn into_inner(self) -> L { self.logic }
+}
+
+impl<State: Sync, L: Logic<State> + Send + Sync> AsyncLogic<State> for FromSync<L> {
+    async fn is_ready(&self) -> bool { self.logic.is_ready() }
+
+    async fn add_for(&mut self, state: &State) -> Duration { self.logic.add_for(state) }
+
+    async fn free(&mut self, state: &State) { self.logic.free(state) }
+}
+
+/// Outcome of an operation performed under the limiter, reported back with `Limiter::feed`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Success,
+    Overloaded,
+}
+
+/// Out-of-band information a logic reacts to, fed with `Limiter::feed`.
+/// `Aimd` observes `Outcome`s, but any signal works, e.g. a server's `Retry-After`.
 ///
 /// ## Example
 /// ```
-/// use std::time::Duration;
-/// use ratelim::{Limiter};
-/// use ratelim::logic::Logic;
+/// use std::time::{Duration, Instant};
+/// use ratelim::Limiter;
+/// use ratelim::logic::{Feedback, Logic};
+///
+/// struct RetryAfter(Duration);
 ///
 /// #[derive(Clone)]
-/// struct QuotaPerSecond {
-///     quota: u64,
-///     operations_counter: u64,
-/// }
+/// struct PausedUntil(Option<Instant>);
+///
+/// impl Logic<()> for PausedUntil {
+///     fn is_ready(&self) -> bool { self.0.is_none_or(|until| Instant::now() >= until) }
 ///
-/// impl Logic<u64> for QuotaPerSecond {
-///     fn is_ready(&self) -> bool { self.operations_counter > self.quota }
+///     fn add_for(&mut self, _: &()) -> Duration { Duration::ZERO }
 ///
-///     fn add_for(&mut self, state: &u64) -> Duration {
-///         self.operations_counter += state;
-///         Duration::from_secs(1)
-///     }
+///     fn free(&mut self, _: &()) {}
+/// }
 ///
-///     fn free(&mut self, state: &u64) { self.operations_counter -= 1; }
+/// impl Feedback<RetryAfter> for PausedUntil {
+///     fn observe(&mut self, signal: RetryAfter) { self.0 = Some(Instant::now() + signal.0); }
 /// }
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let limiter = Limiter::new(PausedUntil(None));
+/// limiter.feed(RetryAfter(Duration::from_millis(50))).await;
+/// assert!(limiter.try_sync(()).await.is_err());
+///
+/// let started = Instant::now();
+/// limiter.sync(()).await;
+/// assert!(started.elapsed() >= Duration::from_millis(40));
+/// # }
 /// ```
-pub trait Logic<State> {
-    fn is_ready(&self) -> bool;
+pub trait Feedback<Signal> {
+    fn observe(&mut self, signal: Signal);
+}
 
-    fn add_for(&mut self, state: &State) -> Duration;
+/// Whether a `cost` fits next to `used` within `limit`, for the `Logic::is_ready_for` of counting logics.
+/// A cost above the whole limit could never fit, it goes alone instead of waiting forever, like with `QuotaPer`.
+fn fits(used: u64, cost: u64, limit: u64) -> bool {
+    used.saturating_add(cost.max(1)) <= limit || (used == 0 && cost > limit)
+}
+
+/// Far enough to never come, but still representable by an `Instant`.
+const FOREVER: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
 
-    fn free(&mut self, state: &State);
+fn duration_from_nanos(nanos: u128) -> Duration {
+    u64::try_from(nanos / 1_000_000_000).map_or(Duration::MAX, |secs| Duration::new(secs, (nanos % 1_000_000_000) as u32))
 }
 
+/// Classic token bucket. Allows bursts up to `capacity` and refills continuously at `refill` tokens per `per`.
+/// Refill is time-based, so `free` does nothing.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct TokenBucket {
+    capacity: f64,
+    tokens: f64,
+    rate: f64, // tokens per second
+    #[cfg_attr(feature = "serde", serde(with = "crate::relative"))]
+    updated: Instant,
+}
 
-/// Simplest Logic implementation. Ensures actions has fixed timeout.
-#[derive(Clone)]
-pub struct Timeout {
-    is_timed_out: bool,
-    timeout: Duration,
+impl TokenBucket {
+    /// Panics if `refill` or `per` is zero, the bucket would never refill.
+    pub fn new(capacity: u64, refill: u64, per: Duration) -> TokenBucket {
+        assert!(refill > 0 && !per.is_zero(), "a token bucket has to refill");
+        TokenBucket {
+            capacity: capacity as f64,
+            tokens: capacity as f64,
+            rate: refill as f64 / per.as_secs_f64(),
+            updated: Instant::now(),
+        }
+    }
+
+    fn tokens_at(&self, now: Instant) -> f64 {
+        let refilled = now.saturating_duration_since(self.updated).as_secs_f64() * self.rate;
+        (self.tokens + refilled).min(self.capacity)
+    }
+}
+
+/// Holds the `Rate::normalized` count and refills it over the normalized period,
+/// so 60 per minute bursts one request rather than 60. Fails for a zero count or period, which never refills.
+impl TryFrom<Rate> for TokenBucket {
+    type Error = NeverRefills;
+
+    fn try_from(rate: Rate) -> Result<TokenBucket, NeverRefills> {
+        let normalized = rate.normalized();
+        if normalized.count() == 0 || normalized.per().is_zero() {
+            return Err(NeverRefills(rate));
+        }
+        Ok(TokenBucket::new(normalized.count(), normalized.count(), normalized.per()))
+    }
+}
+
+/// Returned by `TokenBucket::try_from` for a `Rate` of a zero count or period.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NeverRefills(pub Rate);
+
+impl Display for NeverRefills {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result { write!(f, "{:?} never refills a token bucket", self.0) }
+}
+
+impl Error for NeverRefills {}
+
+impl Logic<u64> for TokenBucket {
+    fn is_ready(&self) -> bool { self.tokens_at(Instant::now()) >= 1.0 }
+
+    // a cost above the capacity takes a full bucket, like a `QuotaPer`
+    fn is_ready_for(&self, state: &u64) -> bool {
+        let tokens = self.tokens_at(Instant::now());
+        tokens >= (*state).max(1) as f64 || tokens >= self.capacity
+    }
+
+    fn add_for(&mut self, state: &u64) -> Duration {
+        let now = Instant::now();
+        self.tokens = self.tokens_at(now) - *state as f64;
+        self.updated = now;
+        Duration::try_from_secs_f64(*state as f64 / self.rate).unwrap_or(Duration::MAX)
+    }
+
+    fn free(&mut self, _: &u64) {}
+
+    fn max_capacity(&self) -> Option<u64> { Some(self.capacity as u64) }
+
+    fn headroom(&self) -> Option<u64> { Some(self.tokens_at(Instant::now()).max(0.0) as u64) }
+
+    fn reset(&mut self) {
+        self.tokens = self.capacity;
+        self.updated = Instant::now();
+    }
+}
+
+/// Leaky bucket as a meter. Smooths the output, so no two grants happen closer than `per / rate`
+/// apart, no matter how bursty the input is. A grant of a cost of `n` takes `n` intervals before the next one.
+///
+/// Idle time accumulates no credit by default, `with_credit` lets it save up to a number of grants,
+/// which then may go right after another, but a long idle period never turns into an unbounded burst.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LeakyBucket {
+    interval: Duration,
+    // idle time that may be made up for
+    credit: Duration,
+    #[cfg_attr(feature = "serde", serde(with = "crate::relative::option"))]
+    next_allowed: Option<Instant>,
+}
+
+impl LeakyBucket {
+    /// Panics if `rate` is zero, nothing would ever leak.
+    pub fn new(rate: u64, per: Duration) -> LeakyBucket {
+        assert!(rate > 0, "a leaky bucket has to leak");
+        LeakyBucket {
+            interval: duration_from_nanos(per.as_nanos() / rate as u128),
+            credit: Duration::ZERO,
+            next_allowed: None,
+        }
+    }
+
+    /// Saves up to `grants` intervals of idle time.
+    pub fn with_credit(mut self, grants: u64) -> LeakyBucket {
+        self.credit = self.intervals(grants);
+        self
+    }
+
+    fn intervals(&self, count: u64) -> Duration { duration_from_nanos(self.interval.as_nanos().saturating_mul(count as u128)) }
+
+    fn is_ready_at(&self, now: Instant) -> bool { self.next_allowed.is_none_or(|next| now >= next) }
+
+    fn add_at(&mut self, now: Instant, state: u64) -> Duration {
+        // spacing restarts at most `credit` in the past
+        let earliest = now.checked_sub(self.credit).unwrap_or(now);
+        let base = self.next_allowed.map_or(now, |next| next.max(earliest));
+        let intervals = self.intervals(state);
+        let next = base.checked_add(intervals).unwrap_or(now + FOREVER);
+        self.next_allowed = Some(next);
+        next.saturating_duration_since(now)
+    }
+}
+
+impl Logic<u64> for LeakyBucket {
+    fn is_ready(&self) -> bool { self.is_ready_at(Instant::now()) }
+
+    fn add_for(&mut self, state: &u64) -> Duration { self.add_at(Instant::now(), *state) }
+
+    fn free(&mut self, _: &u64) {}
+
+    fn reset(&mut self) { self.next_allowed = None; }
+}
+
+/// Generic cell rate algorithm. Same pacing as a token bucket, but keeps a single
+/// theoretical arrival time instead of a token counter.
+///
+/// `rate_per` is the `(rate, per)` pair, giving the emission interval `T = per / rate`,
+/// `burst` requests are admitted instantly, giving the tolerance `τ = (burst - 1) * T`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Gcra {
+    emission_interval: Duration,
+    burst_tolerance: Duration,
+    #[cfg_attr(feature = "serde", serde(with = "crate::relative"))]
+    theoretical_arrival_time: Instant,
+}
+
+impl Gcra {
+    /// Panics if `rate` is zero.
+    pub fn new(rate_per: (u64, Duration), burst: u64) -> Gcra {
+        let (rate, per) = rate_per;
+        assert!(rate > 0, "a GCRA has to emit");
+        let emission_interval = per.as_nanos() / rate as u128;
+
+        Gcra {
+            emission_interval: duration_from_nanos(emission_interval),
+            burst_tolerance: duration_from_nanos(emission_interval.saturating_mul(burst.saturating_sub(1) as u128)),
+            theoretical_arrival_time: Instant::now(),
+        }
+    }
+
+    fn allowed_at(&self) -> Option<Instant> { self.theoretical_arrival_time.checked_sub(self.burst_tolerance) }
+
+    /// `cells` emission intervals.
+    fn emissions(&self, cells: u64) -> Duration { duration_from_nanos(self.emission_interval.as_nanos().saturating_mul(cells as u128)) }
+}
+
+impl Logic<u64> for Gcra {
+    fn is_ready(&self) -> bool { self.allowed_at().is_none_or(|allowed_at| Instant::now() >= allowed_at) }
+
+    // TAT + (cost - 1)·T - τ <= now, the first cell is the one `is_ready` checks.
+    // A cost above the burst goes alone once the TAT has passed, like a full `TokenBucket`.
+    fn is_ready_for(&self, state: &u64) -> bool {
+        let now = Instant::now();
+        let ahead = self.theoretical_arrival_time.saturating_duration_since(now);
+        ahead.saturating_add(self.emissions((*state).saturating_sub(1))) <= self.burst_tolerance || ahead.is_zero()
+    }
+
+    fn add_for(&mut self, state: &u64) -> Duration {
+        let now = Instant::now();
+        let (base, emissions) = (self.theoretical_arrival_time.max(now), self.emissions(*state));
+        // too far to represent, the cells never conform again
+        self.theoretical_arrival_time = base.checked_add(emissions).or_else(|| base.checked_add(FOREVER)).unwrap_or(base);
+        // wake up when the next request conforms again
+        self.allowed_at().map_or(Duration::ZERO, |allowed_at| allowed_at.saturating_duration_since(now))
+    }
+
+    fn free(&mut self, _: &u64) {}
+
+    fn reset(&mut self) { self.theoretical_arrival_time = Instant::now(); }
+}
+
+/// Keeps the grant times and allows at most `limit` grants within any rolling `window`.
+/// A grant exactly `window` old is no longer counted, same as the `Limiter` frees it.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SlidingWindowLog {
+    limit: usize,
+    window: Duration,
+    #[cfg_attr(feature = "serde", serde(with = "crate::relative::deque"))]
+    grants: VecDeque<Instant>,
 }
 
-impl Timeout {
-    pub fn new(timeout: Duration) -> Timeout {
-        Timeout {
-            is_timed_out: false,
-            timeout,
+impl SlidingWindowLog {
+    pub fn new(limit: usize, window: Duration) -> SlidingWindowLog {
+        SlidingWindowLog {
+            limit,
+            window,
+            grants: VecDeque::with_capacity(limit),
+        }
+    }
+
+    fn in_window(&self, now: Instant) -> usize {
+        self.grants.iter().rev().take_while(|granted| now.duration_since(**granted) < self.window).count()
+    }
+
+    fn add_at(&mut self, now: Instant) {
+        self.prune(now);
+        self.grants.push_back(now);
+    }
+
+    fn prune(&mut self, now: Instant) {
+        while self.grants.front().is_some_and(|granted| now.duration_since(*granted) >= self.window) {
+            self.grants.pop_front();
         }
     }
 }
 
-impl Logic<()> for Timeout {
-    fn is_ready(&self) -> bool { !self.is_timed_out }
+impl Logic<()> for SlidingWindowLog {
+    fn is_ready(&self) -> bool { self.in_window(Instant::now()) < self.limit }
 
     fn add_for(&mut self, _: &()) -> Duration {
-        self.is_timed_out = true;
-        self.timeout.clone()
+        self.add_at(Instant::now());
+        self.window
     }
 
-    fn free(&mut self, _: &()) { self.is_timed_out = false; }
+    fn free(&mut self, _: &()) { self.prune(Instant::now()); }
+
+    fn reset(&mut self) { self.grants.clear(); }
 }
 
-/// Simple Logic implementation. Ensures actions are performed less often than the given quota/time.
-#[derive(Clone)]
-pub struct QuotaPer {
+/// Memory-efficient approximation of `SlidingWindowLog`. Only counts grants per fixed window and
+/// estimates the rolling window as `previous * overlap + current`, where `overlap` is the part of
+/// the previous window still covered by the rolling one.
+///
+/// The estimate assumes grants were spread evenly over the previous window. The real count within
+/// the rolling window differs from the estimate by at most `previous * overlap`, so in the worst case
+/// (every previous grant clustered at one edge) up to `limit` grants may be misjudged, while for
+/// evenly spread traffic the decisions match the exact log within a couple of percent.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SlidingWindowCounter {
+    limit: u64,
+    window: Duration,
+    #[cfg_attr(feature = "serde", serde(with = "crate::relative"))]
+    window_start: Instant,
+    previous: u64,
+    current: u64,
+}
+
+impl SlidingWindowCounter {
+    /// Panics if `window` is zero.
+    pub fn new(limit: u64, window: Duration) -> SlidingWindowCounter {
+        assert!(!window.is_zero(), "a sliding window can't be empty");
+        SlidingWindowCounter {
+            limit,
+            window,
+            window_start: Instant::now(),
+            previous: 0,
+            current: 0,
+        }
+    }
+
+    /// `(previous, current, elapsed in current)` as seen at `now`, without rolling the windows.
+    fn windows_at(&self, now: Instant) -> (u64, u64, Duration) {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        match elapsed.as_nanos() / self.window.as_nanos() {
+            0 => (self.previous, self.current, elapsed),
+            1 => (self.current, 0, elapsed - self.window),
+            _ => (0, 0, duration_from_nanos(elapsed.as_nanos() % self.window.as_nanos())),
+        }
+    }
+
+    fn estimate_at(&self, now: Instant) -> f64 {
+        let (previous, current, elapsed) = self.windows_at(now);
+        let overlap = 1.0 - elapsed.as_secs_f64() / self.window.as_secs_f64();
+        previous as f64 * overlap + current as f64
+    }
+
+    fn is_ready_at(&self, now: Instant) -> bool { self.estimate_at(now) < self.limit as f64 }
+
+    fn is_ready_for_at(&self, now: Instant, cost: u64) -> bool {
+        let estimate = self.estimate_at(now);
+        estimate + cost.max(1) as f64 <= self.limit as f64 || (estimate == 0.0 && cost > self.limit)
+    }
+
+    fn add_at(&mut self, now: Instant, state: u64) -> Duration {
+        let (previous, current, elapsed) = self.windows_at(now);
+        self.previous = previous;
+        self.current = current + state;
+        self.window_start = now - elapsed;
+        self.window - elapsed
+    }
+}
+
+impl Logic<u64> for SlidingWindowCounter {
+    fn is_ready(&self) -> bool { self.is_ready_at(Instant::now()) }
+
+    fn is_ready_for(&self, state: &u64) -> bool { self.is_ready_for_at(Instant::now(), *state) }
+
+    fn add_for(&mut self, state: &u64) -> Duration { self.add_at(Instant::now(), *state) }
+
+    fn free(&mut self, _: &u64) {}
+
+    fn reset(&mut self) {
+        self.window_start = Instant::now();
+        self.previous = 0;
+        self.current = 0;
+    }
+}
+
+/// Fixed window counter. Unlike `QuotaPer`, the counter resets for everyone at once, every `window`
+/// starting from the `anchor`, the way most third-party APIs describe their limits
+/// ("1000 requests per calendar minute").
+///
+/// The tradeoff is that up to twice the `limit` may pass around a window boundary.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct FixedWindow {
+    limit: u64,
+    count: u64,
+    window: Duration,
+    #[cfg_attr(feature = "serde", serde(with = "crate::relative"))]
+    window_start: Instant,
+}
+
+impl FixedWindow {
+    /// Panics if `window` is zero.
+    pub fn new(limit: u64, window: Duration) -> FixedWindow { FixedWindow::with_anchor(limit, window, Instant::now()) }
+
+    /// Panics if `window` is zero.
+    pub fn with_anchor(limit: u64, window: Duration, anchor: Instant) -> FixedWindow {
+        assert!(!window.is_zero(), "a fixed window can't be empty");
+        FixedWindow {
+            limit,
+            count: 0,
+            window,
+            window_start: anchor,
+        }
+    }
+
+    /// Start of the window `now` belongs to.
+    fn window_at(&self, now: Instant) -> Instant {
+        let elapsed = now.saturating_duration_since(self.window_start);
+        self.window_start + (elapsed - duration_from_nanos(elapsed.as_nanos() % self.window.as_nanos()))
+    }
+
+    fn is_ready_at(&self, now: Instant) -> bool { self.count_at(now) < self.limit }
+
+    /// Grants counted in the window `now` belongs to.
+    fn count_at(&self, now: Instant) -> u64 { if self.window_at(now) == self.window_start { self.count } else { 0 } }
+
+    fn add_at(&mut self, now: Instant, state: u64) -> Duration {
+        let window_start = self.window_at(now);
+        if window_start != self.window_start {
+            self.window_start = window_start;
+            self.count = 0;
+        }
+
+        self.count += state;
+        (window_start + self.window).saturating_duration_since(now)
+    }
+}
+
+impl Logic<u64> for FixedWindow {
+    fn is_ready(&self) -> bool { self.is_ready_at(Instant::now()) }
+
+    fn is_ready_for(&self, state: &u64) -> bool { fits(self.count_at(Instant::now()), *state, self.limit) }
+
+    fn add_for(&mut self, state: &u64) -> Duration { self.add_at(Instant::now(), *state) }
+
+    fn free(&mut self, _: &u64) {}
+
+    fn max_capacity(&self) -> Option<u64> { Some(self.limit) }
+
+    fn reset(&mut self) { self.count = 0; }
+}
+
+/// Quota with a soft and a hard limit, degrading gracefully instead of hitting a cliff. Below `soft` units in use,
+/// grants are held for `window` like with `QuotaPer`. Between `soft` and `hard` they are still admitted right away,
+/// but held for `window * slowdown_factor`, so the quota refills slower the more it is used. At `hard`, nothing is admitted.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct SoftHard {
+    soft: u64,
+    hard: u64,
+    window: Duration,
+    slowdown_factor: f64,
+    used: u64,
+}
+
+impl SoftHard {
+    pub fn new(soft: u64, hard: u64, window: Duration, slowdown_factor: f64) -> SoftHard {
+        SoftHard {
+            soft: soft.min(hard),
+            hard,
+            window,
+            slowdown_factor,
+            used: 0,
+        }
+    }
+
+    pub fn used(&self) -> u64 { self.used }
+}
+
+impl Logic<u64> for SoftHard {
+    fn is_ready(&self) -> bool { self.used < self.hard }
+
+    fn is_ready_for(&self, state: &u64) -> bool { fits(self.used, *state, self.hard) }
+
+    fn add_for(&mut self, state: &u64) -> Duration {
+        let in_band = self.used >= self.soft;
+        self.used += state;
+        if in_band { self.window.mul_f64(self.slowdown_factor) } else { self.window }
+    }
+
+    fn free(&mut self, state: &u64) { self.used = self.used.saturating_sub(*state); }
+
+    fn max_capacity(&self) -> Option<u64> { Some(self.hard) }
+
+    fn headroom(&self) -> Option<u64> { Some(self.hard.saturating_sub(self.used)) }
+
+    fn reset(&mut self) { self.used = 0; }
+}
+
+/// Quota whose grants are held longer the more of it is in use, e.g. longer cooldowns for a busier backend.
+/// A grant is held from `min_timeout` on an idle quota up to `max_timeout` on a full one,
+/// scaling linearly with the share of `quota` in use before it.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LoadDependent {
     quota: u64,
-    state: u64,
-    timeout: Duration,
+    min_timeout: Duration,
+    max_timeout: Duration,
+    used: u64,
 }
 
-impl QuotaPer {
-    pub fn new(quota: u64, timeout: Duration) -> QuotaPer {
-        QuotaPer {
+impl LoadDependent {
+    pub fn new(quota: u64, min_timeout: Duration, max_timeout: Duration) -> LoadDependent {
+        LoadDependent {
             quota,
-            state: 0,
-            timeout,
+            min_timeout,
+            max_timeout: max_timeout.max(min_timeout),
+            used: 0,
+        }
+    }
+
+    pub fn used(&self) -> u64 { self.used }
+
+    fn utilization(&self) -> f64 { (self.used as f64 / self.quota as f64).min(1.0) }
+}
+
+impl Logic<u64> for LoadDependent {
+    fn is_ready(&self) -> bool { self.used < self.quota }
+
+    fn is_ready_for(&self, state: &u64) -> bool { fits(self.used, *state, self.quota) }
+
+    fn add_for(&mut self, state: &u64) -> Duration {
+        let timeout = self.min_timeout + (self.max_timeout - self.min_timeout).mul_f64(self.utilization());
+        self.used += state;
+        timeout
+    }
+
+    fn free(&mut self, state: &u64) { self.used = self.used.saturating_sub(*state); }
+
+    fn max_capacity(&self) -> Option<u64> { Some(self.quota) }
+
+    fn headroom(&self) -> Option<u64> { Some(self.quota.saturating_sub(self.used)) }
+
+    fn reset(&mut self) { self.used = 0; }
+}
+
+/// Throttles on an exponentially weighted moving average of the request rate, so bursts raise the estimate
+/// smoothly instead of hitting a hard cut like a fixed window. Grants are not admitted while the smoothed
+/// rate is at or above `target_rate` per second.
+///
+/// Every grant weighs one, and the weights decay by half every `half_life`, a steady rate `r`
+/// settles at the estimate `r`. The time is read from the `Clock`, `with_clock` takes a `MockClock` in tests.
+#[derive(Clone, Debug)]
+pub struct Ewma<C = SystemClock> {
+    target_rate: f64,
+    half_life: Duration,
+    // decayed count of grants as of `updated`
+    weight: f64,
+    updated: Instant,
+    clock: C,
+}
+
+impl Ewma {
+    pub fn new(target_rate: f64, half_life: Duration) -> Ewma {
+        Ewma {
+            target_rate,
+            half_life,
+            weight: 0.0,
+            updated: Instant::now(),
+            clock: SystemClock,
+        }
+    }
+}
+
+impl<C: Clock> Ewma<C> {
+    pub fn with_clock<T: Clock>(self, clock: T) -> Ewma<T> {
+        Ewma {
+            target_rate: self.target_rate,
+            half_life: self.half_life,
+            weight: self.weight,
+            updated: clock.now(),
+            clock,
+        }
+    }
+
+    /// The smoothed rate, per second.
+    pub fn rate(&self) -> f64 { self.weight_at(self.clock.now()) * std::f64::consts::LN_2 / self.half_life.as_secs_f64() }
+
+    fn weight_at(&self, now: Instant) -> f64 {
+        let half_lives = now.saturating_duration_since(self.updated).as_secs_f64() / self.half_life.as_secs_f64();
+        self.weight * 0.5f64.powf(half_lives)
+    }
+
+    /// Until the estimate falls below the target again, zero if it already is.
+    fn until_below(&self, now: Instant) -> Duration {
+        let target_weight = self.target_rate * self.half_life.as_secs_f64() / std::f64::consts::LN_2;
+        let weight = self.weight_at(now);
+        if weight < target_weight {
+            return Duration::ZERO;
+        }
+        Duration::try_from_secs_f64(self.half_life.as_secs_f64() * (weight / target_weight).log2()).unwrap_or(Duration::MAX)
+    }
+}
+
+impl<C: Clock> Logic<()> for Ewma<C> {
+    fn is_ready(&self) -> bool { self.rate() < self.target_rate }
+
+    // the free only wakes the waiters once the estimate has decayed enough
+    fn add_for(&mut self, _: &()) -> Duration {
+        let now = self.clock.now();
+        self.weight = self.weight_at(now) + 1.0;
+        self.updated = now;
+        self.until_below(now)
+    }
+
+    fn free(&mut self, _: &()) {}
+
+    fn ready_in(&self) -> Option<Duration> { Some(self.until_below(self.clock.now())).filter(|until_below| !until_below.is_zero()) }
+
+    fn reset(&mut self) {
+        self.weight = 0.0;
+        self.updated = self.clock.now();
+    }
+}
+
+/// Caps the number of simultaneously executing operations rather than their rate.
+/// Meant to be used with `Limiter::acquire`, so the slot is released as soon as the `Permit` drops.
+/// With `sync` the slot is released after the lease, a minute unless set by `with_lease`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Concurrency {
+    max: u64,
+    in_flight: u64,
+    lease: Duration,
+}
+
+impl Concurrency {
+    pub fn new(max: u64) -> Concurrency {
+        Concurrency {
+            max,
+            in_flight: 0,
+            lease: Duration::from_secs(60),
+        }
+    }
+
+    pub fn with_lease(mut self, lease: Duration) -> Concurrency {
+        self.lease = lease;
+        self
+    }
+}
+
+impl Logic<u64> for Concurrency {
+    fn is_ready(&self) -> bool { self.in_flight < self.max }
+
+    fn is_ready_for(&self, state: &u64) -> bool { fits(self.in_flight, *state, self.max) }
+
+    fn add_for(&mut self, state: &u64) -> Duration {
+        self.in_flight += state;
+        self.lease
+    }
+
+    // a permit held across a `reset` is freed after its slot was already forgotten
+    fn free(&mut self, state: &u64) { self.in_flight = self.in_flight.saturating_sub(*state); }
+
+    fn max_capacity(&self) -> Option<u64> { Some(self.max) }
+
+    fn headroom(&self) -> Option<u64> { Some(self.max.saturating_sub(self.in_flight)) }
+
+    fn reset(&mut self) { self.in_flight = 0; }
+}
+
+/// Caps the number of distinct keys holding a reservation at once, e.g. at most 1000 users connected,
+/// however many connections each of them has. A key already holding one takes no new slot, its slot is
+/// released once its last reservation is freed. Like `Concurrency`, meant for `Limiter::acquire`.
+///
+/// Once every slot is taken, keys already holding one are still admitted, only new keys wait.
+#[derive(Clone, Debug)]
+pub struct DistinctConcurrency<K> {
+    max: usize,
+    active: HashMap<K, usize>,
+    lease: Duration,
+}
+
+impl<K: Hash + Eq> DistinctConcurrency<K> {
+    pub fn new(max: usize) -> DistinctConcurrency<K> {
+        DistinctConcurrency {
+            max,
+            active: HashMap::new(),
+            lease: Duration::from_secs(60),
+        }
+    }
+
+    /// See `Concurrency::with_lease`.
+    pub fn with_lease(mut self, lease: Duration) -> DistinctConcurrency<K> {
+        self.lease = lease;
+        self
+    }
+
+    /// Keys holding a reservation.
+    pub fn active(&self) -> usize { self.active.len() }
+
+    /// Reservations held by `key`.
+    pub fn held_by(&self, key: &K) -> usize { self.active.get(key).copied().unwrap_or(0) }
+}
+
+impl<K: Hash + Eq + Clone> Logic<K> for DistinctConcurrency<K> {
+    fn is_ready(&self) -> bool { self.active.len() < self.max }
+
+    fn is_ready_for(&self, key: &K) -> bool { self.active.contains_key(key) || self.active.len() < self.max }
+
+    fn add_for(&mut self, key: &K) -> Duration {
+        *self.active.entry(key.clone()).or_insert(0) += 1;
+        self.lease
+    }
+
+    // a permit held across a `reset` is freed after its key was already forgotten
+    fn free(&mut self, key: &K) {
+        if let Some(count) = self.active.get_mut(key) {
+            *count -= 1;
+            if *count == 0 {
+                self.active.remove(key);
+            }
         }
     }
+
+    fn reset(&mut self) { self.active.clear(); }
+}
+
+/// Additive increase / multiplicative decrease of the allowed concurrency, for backends whose
+/// capacity is unknown. Every `Outcome::Success` grows the limit by `increase_step`, every
+/// `Outcome::Overloaded` multiplies it by `decrease_factor`, always staying within `min..=max`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Aimd {
+    limit: u64,
+    min: u64,
+    max: u64,
+    increase_step: u64,
+    decrease_factor: f64,
+    in_flight: u64,
+    lease: Duration,
+}
+
+impl Aimd {
+    pub fn new(initial: u64, min: u64, max: u64, increase_step: u64, decrease_factor: f64) -> Aimd {
+        Aimd {
+            limit: initial.clamp(min, max),
+            min,
+            max,
+            increase_step,
+            decrease_factor,
+            in_flight: 0,
+            lease: Duration::from_secs(60),
+        }
+    }
+
+    pub fn with_lease(mut self, lease: Duration) -> Aimd {
+        self.lease = lease;
+        self
+    }
+
+    pub fn limit(&self) -> u64 { self.limit }
 }
 
-impl Logic<u64> for QuotaPer {
-    fn is_ready(&self) -> bool { self.state < self.quota }
+impl Logic<u64> for Aimd {
+    fn is_ready(&self) -> bool { self.in_flight < self.limit }
+
+    fn is_ready_for(&self, state: &u64) -> bool { fits(self.in_flight, *state, self.limit) }
 
     fn add_for(&mut self, state: &u64) -> Duration {
-        self.state += state;
-        self.timeout.clone()
+        self.in_flight += state;
+        self.lease
     }
 
-    fn free(&mut self, state: &u64) { self.state -= state; }
-}
\ No newline at end of file
+    fn free(&mut self, state: &u64) { self.in_flight = self.in_flight.saturating_sub(*state); }
+
+    // the learned limit is kept, it describes the backend rather than what was admitted
+    fn reset(&mut self) { self.in_flight = 0; }
+}
+
+impl Feedback<Outcome> for Aimd {
+    fn observe(&mut self, outcome: Outcome) {
+        self.limit = match outcome {
+            Outcome::Success => self.limit.saturating_add(self.increase_step),
+            Outcome::Overloaded => (self.limit as f64 * self.decrease_factor) as u64,
+        }.clamp(self.min, self.max);
+    }
+}
+
+/// Circuit breaker. Opens and rejects everything after `failure_threshold` consecutive
+/// `Outcome::Overloaded`s, stays open for `cooldown`, then lets exactly one probe through.
+/// A successful probe closes the breaker again, a failed one re-opens it, and so does
+/// a probe no outcome was reported for within another `cooldown`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    state: BreakerState,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+enum BreakerState {
+    Closed { failures: u32 },
+    Open {
+        #[cfg_attr(feature = "serde", serde(with = "crate::relative"))]
+        until: Instant,
+    },
+    HalfOpen { probing: bool },
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker {
+            failure_threshold,
+            cooldown,
+            state: BreakerState::Closed { failures: 0 },
+        }
+    }
+
+    pub fn is_open(&self) -> bool { matches!(self.state, BreakerState::Open { .. }) }
+
+    fn open(&mut self) { self.state = BreakerState::Open { until: Instant::now() + self.cooldown }; }
+}
+
+/// Opens after 5 overloads in a row, for 30 seconds.
+impl Default for CircuitBreaker {
+    fn default() -> CircuitBreaker { CircuitBreaker::new(5, Duration::from_secs(30)) }
+}
+
+impl Logic<()> for CircuitBreaker {
+    fn is_ready(&self) -> bool {
+        match self.state {
+            BreakerState::Closed { .. } => true,
+            BreakerState::Open { until } => Instant::now() >= until,
+            BreakerState::HalfOpen { probing } => !probing,
+        }
+    }
+
+    fn add_for(&mut self, _: &()) -> Duration {
+        if matches!(self.state, BreakerState::Closed { .. }) {
+            return Duration::ZERO;
+        }
+        // the cooldown is over, this request is the probe, held until it times out
+        self.state = BreakerState::HalfOpen { probing: true };
+        self.cooldown
+    }
+
+    // a probe still unanswered is taken as failed, rather than keeping the breaker half-open forever
+    fn free(&mut self, _: &()) {
+        if matches!(self.state, BreakerState::HalfOpen { probing: true }) {
+            self.open();
+        }
+    }
+
+    // a probing breaker gets ready by the probe's free or an outcome, nothing to wait for by time
+    fn ready_in(&self) -> Option<Duration> {
+        match self.state {
+            BreakerState::Open { until } => Some(until.saturating_duration_since(Instant::now())),
+            _ => None,
+        }
+    }
+
+    fn reset(&mut self) { self.state = BreakerState::Closed { failures: 0 }; }
+}
+
+impl Feedback<Outcome> for CircuitBreaker {
+    fn observe(&mut self, outcome: Outcome) {
+        match (self.state, outcome) {
+            (BreakerState::Closed { .. }, Outcome::Success) => self.state = BreakerState::Closed { failures: 0 },
+            (BreakerState::Closed { failures }, Outcome::Overloaded) => {
+                if failures + 1 >= self.failure_threshold {
+                    self.open();
+                } else {
+                    self.state = BreakerState::Closed { failures: failures + 1 };
+                }
+            }
+            (BreakerState::HalfOpen { .. }, Outcome::Success) => self.state = BreakerState::Closed { failures: 0 },
+            (BreakerState::HalfOpen { .. }, Outcome::Overloaded) => self.open(),
+            (BreakerState::Open { .. }, _) => {}
+        }
+    }
+}
+
+/// Exponential backoff for retries. Every grant blocks the next one for `base * factor^attempt`,
+/// capped at `max`. The freed delay does not reset the progression, an `Outcome::Success` does.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Backoff {
+    base: Duration,
+    factor: f64,
+    max: Duration,
+    attempt: u32,
+    is_backing_off: bool,
+    jitter: Option<JitterKind>,
+    rng: Rng,
+    previous: Duration,
+}
+
+/// From 100ms, doubling up to 30 seconds, with full jitter.
+impl Default for Backoff {
+    fn default() -> Backoff {
+        Backoff::new(Duration::from_millis(100), 2.0, Duration::from_secs(30)).with_jitter(JitterKind::Full)
+    }
+}
+
+/// Randomization applied to the `Backoff` delays, so clients backing off together don't retry in sync.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum JitterKind {
+    /// Anywhere between zero and the delay.
+    Full,
+    /// Half of the delay, plus anywhere up to the other half.
+    Equal,
+    /// AWS-style, anywhere between `base` and three times the previous delay, capped at `max`.
+    Decorrelated,
+}
+
+impl Backoff {
+    pub fn new(base: Duration, factor: f64, max: Duration) -> Backoff {
+        Backoff {
+            base,
+            factor,
+            max,
+            attempt: 0,
+            is_backing_off: false,
+            jitter: None,
+            rng: Rng::from_entropy(),
+            previous: base,
+        }
+    }
+
+    pub fn with_jitter(mut self, jitter: JitterKind) -> Backoff {
+        self.jitter = Some(jitter);
+        self
+    }
+
+    /// Makes the jitter reproducible.
+    pub fn with_rng_seed(mut self, seed: u64) -> Backoff {
+        self.rng = Rng::new(seed);
+        self
+    }
+
+    fn delay(&self) -> Duration {
+        let delay = self.base.as_secs_f64() * self.factor.powi(self.attempt as i32);
+        Duration::try_from_secs_f64(delay).map_or(self.max, |delay| delay.min(self.max))
+    }
+
+    fn jittered(&mut self, delay: Duration) -> Duration {
+        match self.jitter {
+            None => delay,
+            Some(JitterKind::Full) => delay.mul_f64(self.rng.next_f64()),
+            Some(JitterKind::Equal) => delay / 2 + (delay / 2).mul_f64(self.rng.next_f64()),
+            Some(JitterKind::Decorrelated) => {
+                let upper = (self.previous * 3).max(self.base);
+                let delay = (self.base + (upper - self.base).mul_f64(self.rng.next_f64())).min(self.max);
+                self.previous = delay;
+                delay
+            }
+        }
+    }
+}
+
+impl Logic<()> for Backoff {
+    fn is_ready(&self) -> bool { !self.is_backing_off }
+
+    fn add_for(&mut self, _: &()) -> Duration {
+        let delay = self.delay();
+        let delay = self.jittered(delay);
+        self.attempt = self.attempt.saturating_add(1);
+        self.is_backing_off = true;
+        delay
+    }
+
+    fn free(&mut self, _: &()) { self.is_backing_off = false; }
+
+    fn reset(&mut self) {
+        self.attempt = 0;
+        self.is_backing_off = false;
+        self.previous = self.base;
+    }
+}
+
+impl Feedback<Outcome> for Backoff {
+    fn observe(&mut self, outcome: Outcome) {
+        if outcome == Outcome::Success {
+            self.attempt = 0;
+            self.previous = self.base;
+        }
+    }
+}
+
+/// Load shedding without counting: every request is admitted with probability `p`, so there is
+/// nothing to keep track of and nothing to free, which stays cheap at rates exact counting can't keep up with.
+///
+/// Every admission is a single fresh draw in `is_ready_for`, so it suits `try_sync`, a waiting `sync` is simply
+/// let through at a later poll. `is_ready` draws nothing, it only tells whether anything can be admitted at all,
+/// so the limiter asking it again does not change the odds.
+#[derive(Clone, Debug)]
+pub struct Probabilistic {
+    p: f64,
+    rng: AtomicRng,
+}
+
+impl Probabilistic {
+    pub fn new(p: f64) -> Probabilistic {
+        Probabilistic {
+            p: p.clamp(0.0, 1.0),
+            rng: AtomicRng::from_entropy(),
+        }
+    }
+
+    /// Makes the draws reproducible.
+    pub fn with_rng_seed(mut self, seed: u64) -> Probabilistic {
+        self.rng = AtomicRng::new(seed);
+        self
+    }
+
+    pub fn probability(&self) -> f64 { self.p }
+
+    pub fn set_probability(&mut self, p: f64) { self.p = p.clamp(0.0, 1.0); }
+}
+
+impl Logic<()> for Probabilistic {
+    fn is_ready(&self) -> bool { self.p > 0.0 }
+
+    fn is_ready_for(&self, _: &()) -> bool { self.rng.next_f64() < self.p }
+
+    fn add_for(&mut self, _: &()) -> Duration { Duration::ZERO }
+
+    fn free(&mut self, _: &()) {}
+}
+
+/// Requires both logics to be ready, e.g. "at most 100/sec AND at most 10 concurrent".
+///
+/// Both logics see the same `State`. There is a single delayed free per grant, so both are freed
+/// together after the longer of the two delays, which may be stricter than each logic on its own.
+/// The `max_capacity` and `headroom` are the tighter of the two, `ready_in` the later.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct And<L, R> {
+    left: L,
+    right: R,
+}
+
+impl<L, R> And<L, R> {
+    pub fn new(left: L, right: R) -> And<L, R> { And { left, right } }
+}
+
+// the smaller of two limits, a missing one doesn't limit anything
+fn tighter<State: PartialOrd>(left: Option<State>, right: Option<State>) -> Option<State> {
+    match (left, right) {
+        (Some(left), Some(right)) => Some(if right < left { right } else { left }),
+        (left, right) => left.or(right),
+    }
+}
+
+impl<State: PartialOrd, L: Logic<State>, R: Logic<State>> Logic<State> for And<L, R> {
+    fn is_ready(&self) -> bool { self.left.is_ready() && self.right.is_ready() }
+
+    fn is_ready_for(&self, state: &State) -> bool { self.left.is_ready_for(state) && self.right.is_ready_for(state) }
+
+    fn add_for(&mut self, state: &State) -> Duration { self.left.add_for(state).max(self.right.add_for(state)) }
+
+    fn free(&mut self, state: &State) {
+        self.left.free(state);
+        self.right.free(state);
+    }
+
+    fn max_capacity(&self) -> Option<State> { tighter(self.left.max_capacity(), self.right.max_capacity()) }
+
+    fn headroom(&self) -> Option<State> { tighter(self.left.headroom(), self.right.headroom()) }
+
+    // both have to get ready
+    fn ready_in(&self) -> Option<Duration> { self.left.ready_in().into_iter().chain(self.right.ready_in()).max() }
+
+    fn reset(&mut self) {
+        self.left.reset();
+        self.right.reset();
+    }
+}
+
+/// Ready if either logic is ready, e.g. "under the steady quota OR some burst credit remains".
+/// Grants are charged to the `primary` whenever it is ready, to the `fallback` otherwise.
+///
+/// The state is a `Charged`, which `Logic::reserve` tags with the logic it was charged to, so every free
+/// credits that one, in whatever order the reservations end. A wrapper calling `add_for` instead of `reserve`
+/// loses the tag, its frees credit the `primary`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Or<L, R> {
+    primary: L,
+    fallback: R,
+}
+
+impl<L, R> Or<L, R> {
+    pub fn new(primary: L, fallback: R) -> Or<L, R> { Or { primary, fallback } }
+}
+
+/// State of an `Or`, `state` along with which of its logics it was charged to. Created with `From`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Charged<State> {
+    pub state: State,
+    to_fallback: bool,
+}
+
+impl<State> From<State> for Charged<State> {
+    fn from(state: State) -> Charged<State> { Charged { state, to_fallback: false } }
+}
+
+impl<State, L: Logic<State>, R: Logic<State>> Logic<Charged<State>> for Or<L, R> {
+    fn is_ready(&self) -> bool { self.primary.is_ready() || self.fallback.is_ready() }
+
+    fn is_ready_for(&self, charged: &Charged<State>) -> bool {
+        self.primary.is_ready_for(&charged.state) || self.fallback.is_ready_for(&charged.state)
+    }
+
+    fn add_for(&mut self, charged: &Charged<State>) -> Duration {
+        if self.primary.is_ready_for(&charged.state) {
+            self.primary.add_for(&charged.state)
+        } else {
+            self.fallback.add_for(&charged.state)
+        }
+    }
+
+    fn free(&mut self, charged: &Charged<State>) {
+        if charged.to_fallback {
+            self.fallback.free(&charged.state)
+        } else {
+            self.primary.free(&charged.state)
+        }
+    }
+
+    fn reserve(&mut self, charged: Charged<State>) -> (Duration, Charged<State>) {
+        let to_fallback = !self.primary.is_ready_for(&charged.state);
+        let (delay, state) = if to_fallback { self.fallback.reserve(charged.state) } else { self.primary.reserve(charged.state) };
+        (delay, Charged { state, to_fallback })
+    }
+
+    fn reset(&mut self) {
+        self.primary.reset();
+        self.fallback.reset();
+    }
+}
+
+/// Several limits enforced at once, e.g. requests per second, bytes per second and concurrent connections,
+/// with a `State` of one cost per dimension. A grant needs every dimension to be ready, and every dimension
+/// frees its cost after its own `add_for` delay, on its own schedule, unlike `And` freeing both together.
+///
+/// The dimensions keep their own delayed frees, reclaimed whenever the readiness is checked, the `Limiter`'s
+/// single free only comes once the slowest dimension is done. Dimensions of different logics go in a `Box<dyn Logic<u64>>`.
+///
+/// ## Example
+/// ```
+/// use std::time::Duration;
+/// use ratelim::Limiter;
+/// use ratelim::logic::{Logic, MultiLimit, QuotaPer};
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let dimensions: [Box<dyn Logic<u64> + Send>; 2] = [
+///     Box::new(QuotaPer::new(100, Duration::from_secs(1))),
+///     Box::new(QuotaPer::new(1 << 20, Duration::from_secs(1))),
+/// ];
+/// let limiter = Limiter::new(MultiLimit::new(dimensions));
+/// // one request of 64 KiB
+/// limiter.sync([1, 64 << 10]).await;
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct MultiLimit<L, const N: usize> {
+    // only locked under the `Limiter`'s lock, but `is_ready` has to reclaim through `&self`
+    dimensions: std::sync::Mutex<[Dimension<L>; N]>,
+}
+
+#[derive(Clone, Debug)]
+struct Dimension<L> {
+    logic: L,
+    delayed_frees: BinaryHeap<ratelim_core::HeapValue<Instant, u64>>,
+}
+
+impl<L, const N: usize> MultiLimit<L, N> {
+    pub fn new(logics: [L; N]) -> MultiLimit<L, N> {
+        MultiLimit {
+            dimensions: std::sync::Mutex::new(logics.map(|logic| Dimension { logic, delayed_frees: BinaryHeap::new() })),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, [Dimension<L>; N]> {
+        self.dimensions.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// Reclaims the frees due in every dimension.
+    fn reclaimed(&self) -> std::sync::MutexGuard<'_, [Dimension<L>; N]> where L: Logic<u64> {
+        let mut dimensions = self.lock();
+        let now = Instant::now();
+        for dimension in dimensions.iter_mut() {
+            ratelim_core::reclaim(&mut dimension.delayed_frees, &mut dimension.logic, now);
+        }
+        dimensions
+    }
+}
+
+impl<L: Clone, const N: usize> Clone for MultiLimit<L, N> {
+    fn clone(&self) -> MultiLimit<L, N> { MultiLimit { dimensions: std::sync::Mutex::new(self.lock().clone()) } }
+}
+
+impl<L: Logic<u64>, const N: usize> Logic<[u64; N]> for MultiLimit<L, N> {
+    fn is_ready(&self) -> bool { self.reclaimed().iter().all(|dimension| dimension.logic.is_ready()) }
+
+    fn is_ready_for(&self, costs: &[u64; N]) -> bool {
+        self.reclaimed().iter().zip(costs).all(|(dimension, cost)| dimension.logic.is_ready_for(cost))
+    }
+
+    fn add_for(&mut self, costs: &[u64; N]) -> Duration {
+        let now = Instant::now();
+        let mut slowest = Duration::ZERO;
+        for (dimension, cost) in self.reclaimed().iter_mut().zip(costs) {
+            let (delay, cost) = dimension.logic.reserve(*cost);
+            if let Some(delayed_for) = now.checked_add(delay) {
+                dimension.delayed_frees.push(ratelim_core::HeapValue((delayed_for, cost)));
+            }
+            slowest = slowest.max(delay);
+        }
+        slowest
+    }
+
+    // every dimension frees on its own, by then there is nothing left
+    fn free(&mut self, _: &[u64; N]) { drop(self.reclaimed()); }
+
+    fn max_capacity(&self) -> Option<[u64; N]> {
+        let dimensions = self.lock();
+        let capacities: Option<Vec<u64>> = dimensions.iter().map(|dimension| dimension.logic.max_capacity()).collect();
+        capacities.and_then(|capacities| capacities.try_into().ok())
+    }
+
+    fn headroom(&self) -> Option<[u64; N]> {
+        let dimensions = self.reclaimed();
+        let headroom: Option<Vec<u64>> = dimensions.iter().map(|dimension| dimension.logic.headroom()).collect();
+        headroom.and_then(|headroom| headroom.try_into().ok())
+    }
+
+    // a dimension freeing early may be all that was missing
+    fn ready_in(&self) -> Option<Duration> {
+        let now = Instant::now();
+        self.lock().iter()
+            .flat_map(|dimension| {
+                let next_free = dimension.delayed_frees.peek().map(|ratelim_core::HeapValue((at, _))| at.saturating_duration_since(now));
+                next_free.into_iter().chain(dimension.logic.ready_in())
+            })
+            .min()
+    }
+
+    fn reset(&mut self) {
+        for dimension in self.lock().iter_mut() {
+            dimension.logic.reset();
+            dimension.delayed_frees.clear();
+        }
+    }
+}
+
+/// Sustained rate plus a bounded burst. Requests are served by the `inner` logic when it is ready,
+/// otherwise from a pool of `burst_capacity` credits, refilling one per `burst_refill`
+/// regardless of the inner logic's own timeouts.
+///
+/// A grant from the burst pool takes nothing of the inner logic, so `Logic::reserve` returns a zero cost for it
+/// and its free leaves the inner logic alone. A wrapper calling `add_for` instead of `reserve` frees it to the inner logic.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct WithBurst<L> {
+    inner: L,
+    // refilled by time alone, nothing is ever freed to it
+    burst: TokenBucket,
+}
+
+impl<L> WithBurst<L> {
+    pub fn new(inner: L, burst_capacity: u64, burst_refill: Duration) -> WithBurst<L> {
+        WithBurst { inner, burst: TokenBucket::new(burst_capacity, 1, burst_refill) }
+    }
+}
+
+impl<L: Logic<u64>> Logic<u64> for WithBurst<L> {
+    fn is_ready(&self) -> bool { self.inner.is_ready() || self.burst.is_ready() }
+
+    fn is_ready_for(&self, state: &u64) -> bool { self.inner.is_ready_for(state) || self.burst.is_ready_for(state) }
+
+    fn add_for(&mut self, state: &u64) -> Duration {
+        if self.inner.is_ready_for(state) {
+            self.inner.add_for(state)
+        } else {
+            self.burst.add_for(state)
+        }
+    }
+
+    fn free(&mut self, state: &u64) { self.inner.free(state) }
+
+    fn reserve(&mut self, state: u64) -> (Duration, u64) {
+        if self.inner.is_ready_for(&state) {
+            self.inner.reserve(state)
+        } else {
+            (self.burst.add_for(&state), 0)
+        }
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+        self.burst.reset();
+    }
+}
+
+/// Warms up after a cold start: the `inner` logic's `max_capacity` is scaled from `start_fraction`
+/// up to the full capacity over `warmup`, measured from construction, so cold caches downstream aren't
+/// hit by the whole quota at once. From `started + warmup` on the inner logic alone decides.
+///
+/// The ramp is linear by default, `with_curve` maps the elapsed fraction of the warmup to the fraction
+/// of the ramp done instead, e.g. `|progress| progress * progress` to start slower.
+/// Logics without a `max_capacity` are not scaled.
+#[derive(Clone, Debug)]
+pub struct RampUp<L> {
+    inner: L,
+    start_fraction: f64,
+    warmup: Duration,
+    curve: fn(f64) -> f64,
+    started: Instant,
+    // admitted and not yet freed, compared against the scaled capacity
+    used: u64,
+}
+
+impl<L> RampUp<L> {
+    pub fn new(inner: L, start_fraction: f64, warmup: Duration) -> RampUp<L> {
+        RampUp {
+            inner,
+            start_fraction: start_fraction.clamp(0.0, 1.0),
+            warmup,
+            curve: |progress| progress,
+            started: Instant::now(),
+            used: 0,
+        }
+    }
+
+    pub fn with_curve(mut self, curve: fn(f64) -> f64) -> RampUp<L> {
+        self.curve = curve;
+        self
+    }
+
+    /// Until when the capacity is still scaled down.
+    pub fn warm_at(&self) -> Instant { self.started + self.warmup }
+
+    /// Fraction of the inner capacity admitted at `now`, `1.0` once warmed up.
+    fn fraction_at(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.started);
+        if elapsed >= self.warmup {
+            return 1.0;
+        }
+
+        let done = (self.curve)(elapsed.as_secs_f64() / self.warmup.as_secs_f64()).clamp(0.0, 1.0);
+        self.start_fraction + (1.0 - self.start_fraction) * done
+    }
+
+    /// The scaled capacity, `None` once warmed up or if the inner logic has no capacity.
+    fn capacity_at(&self, now: Instant) -> Option<u64> where L: Logic<u64> {
+        let fraction = self.fraction_at(now);
+        if fraction >= 1.0 {
+            return None;
+        }
+        self.inner.max_capacity().map(|capacity| (capacity as f64 * fraction) as u64)
+    }
+}
+
+impl<L: Logic<u64>> Logic<u64> for RampUp<L> {
+    fn is_ready(&self) -> bool {
+        self.inner.is_ready() && self.capacity_at(Instant::now()).is_none_or(|capacity| self.used < capacity)
+    }
+
+    // a cost above the scaled capacity waits for the ramp, only one above the full capacity goes alone
+    fn is_ready_for(&self, state: &u64) -> bool {
+        self.inner.is_ready_for(state) && self.capacity_at(Instant::now()).is_none_or(|capacity| {
+            self.used.saturating_add((*state).max(1)) <= capacity
+                || (self.used == 0 && capacity > 0 && self.inner.max_capacity().is_some_and(|max| *state > max))
+        })
+    }
+
+    fn add_for(&mut self, state: &u64) -> Duration {
+        self.used += state;
+        self.inner.add_for(state)
+    }
+
+    fn free(&mut self, state: &u64) {
+        self.used = self.used.saturating_sub(*state);
+        self.inner.free(state)
+    }
+
+    fn max_capacity(&self) -> Option<u64> { self.inner.max_capacity() }
+
+    fn headroom(&self) -> Option<u64> {
+        let headroom = self.inner.headroom();
+        match self.capacity_at(Instant::now()) {
+            Some(capacity) => headroom.map(|headroom| headroom.min(capacity.saturating_sub(self.used))),
+            None => headroom,
+        }
+    }
+
+    // the scaled capacity grows without a free, so waiters check again about every step of it
+    fn ready_in(&self) -> Option<Duration> {
+        let now = Instant::now();
+        match (self.capacity_at(now), self.inner.max_capacity()) {
+            (Some(_), Some(capacity)) => Some(self.warmup.div_f64(capacity.max(1) as f64).min(self.warm_at().saturating_duration_since(now))),
+            _ => self.inner.ready_in(),
+        }
+    }
+
+    // the warmup is not restarted, only what was admitted is forgotten
+    fn reset(&mut self) {
+        self.inner.reset();
+        self.used = 0;
+    }
+}
+
+/// Obeys a server's `Retry-After`: once a delay is fed with `Limiter::feed`, nothing is granted until it has passed,
+/// otherwise the `inner` logic decides. Parse the header with `parse_retry_after`.
+///
+/// ## Example
+/// ```
+/// use std::time::Duration;
+/// use ratelim::Limiter;
+/// use ratelim::logic::{parse_retry_after, QuotaPer, RetryAfter};
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let limiter = Limiter::new(RetryAfter::new(QuotaPer::new(10, Duration::from_secs(1))));
+/// // the server answered 429 with `Retry-After: 1`
+/// if let Some(delay) = parse_retry_after("1") {
+///     limiter.feed(delay).await;
+/// }
+/// assert!(limiter.try_sync(1).await.is_err());
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct RetryAfter<L> {
+    inner: L,
+    #[cfg_attr(feature = "serde", serde(with = "crate::relative::option"))]
+    until: Option<Instant>,
+}
+
+impl<L> RetryAfter<L> {
+    pub fn new(inner: L) -> RetryAfter<L> { RetryAfter { inner, until: None } }
+
+    /// Until when grants are held back, if a delay is still running.
+    pub fn paused_until(&self) -> Option<Instant> { self.until.filter(|until| Instant::now() < *until) }
+}
+
+impl<State, L: Logic<State>> Logic<State> for RetryAfter<L> {
+    fn is_ready(&self) -> bool { self.paused_until().is_none() && self.inner.is_ready() }
+
+    fn is_ready_for(&self, state: &State) -> bool { self.paused_until().is_none() && self.inner.is_ready_for(state) }
+
+    fn add_for(&mut self, state: &State) -> Duration { self.inner.add_for(state) }
+
+    fn free(&mut self, state: &State) { self.inner.free(state) }
+
+    fn max_capacity(&self) -> Option<State> { self.inner.max_capacity() }
+
+    fn headroom(&self) -> Option<State> { self.inner.headroom() }
+
+    fn ready_in(&self) -> Option<Duration> {
+        self.paused_until().map(|until| until.saturating_duration_since(Instant::now())).or_else(|| self.inner.ready_in())
+    }
+
+    fn reset(&mut self) {
+        self.inner.reset();
+        self.until = None;
+    }
+}
+
+// a shorter delay must not cut a longer one short
+impl<L> Feedback<Duration> for RetryAfter<L> {
+    fn observe(&mut self, delay: Duration) {
+        let now = Instant::now();
+        // e.g. an absurd `Retry-After` from the server
+        let until = now.checked_add(delay).unwrap_or(now + FOREVER);
+        self.until = Some(self.until.map_or(until, |current| current.max(until)));
+    }
+}
+
+/// Parses a `Retry-After` header value, either delay-seconds (`120`) or an HTTP-date
+/// (`Sun, 06 Nov 1994 08:49:37 GMT`), which is converted with the system clock. A date in the past is no delay.
+pub fn parse_retry_after(value: &str) -> Option<Duration> { retry_after_at(value, SystemTime::now()) }
+
+fn retry_after_at(value: &str, now: SystemTime) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(seconds) = value.parse::<u64>() {
+        return Some(Duration::from_secs(seconds));
+    }
+
+    let date = parse_http_date(value)?;
+    Some(date.duration_since(now).unwrap_or(Duration::ZERO))
+}
+
+/// Parses the RFC 1123 form of an HTTP-date, e.g. `Sun, 06 Nov 1994 08:49:37 GMT`.
+fn parse_http_date(value: &str) -> Option<SystemTime> {
+    const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+
+    let [_weekday, day, month, year, time, "GMT"] = value.split_ascii_whitespace().collect::<Vec<_>>()[..] else { return None };
+    let day: u64 = day.parse().ok().filter(|day| (1..=31).contains(day))?;
+    let month = MONTHS.iter().position(|name| *name == month)? as u64 + 1;
+    let year: u64 = year.parse().ok().filter(|year| *year >= 1970)?;
+
+    let mut time = time.split(':').map(|part| part.parse::<u64>().ok());
+    let (Some(Some(hours)), Some(Some(minutes)), Some(Some(seconds)), None) = (time.next(), time.next(), time.next(), time.next()) else { return None };
+    if hours > 23 || minutes > 59 || seconds > 60 {
+        return None;
+    }
+
+    // days since the epoch of a proleptic Gregorian date, with the year starting in March
+    let (year, month) = if month <= 2 { (year - 1, month + 9) } else { (year, month - 3) };
+    let (era, year_of_era) = (year / 400, year % 400);
+    let day_of_year = (153 * month + 2) / 5 + day - 1;
+    let day_of_era = 365 * year_of_era + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era.checked_mul(146097)?.checked_add(day_of_era)?.checked_sub(719468)?;
+
+    let seconds = days.checked_mul(86400)?.checked_add(hours * 3600 + minutes * 60 + seconds)?;
+    UNIX_EPOCH.checked_add(Duration::from_secs(seconds))
+}
+
+/// Logic built from three closures, for prototyping a policy inline.
+/// The closures are only ever called under the `Limiter`'s lock, so `add_for` and `free`
+/// may freely mutate what they capture, state shared with `is_ready` needs interior mutability.
+///
+/// ## Example
+/// `Timeout` recreated with closures:
+/// ```
+/// use std::sync::Arc;
+/// use std::sync::atomic::{AtomicBool, Ordering};
+/// use std::time::Duration;
+/// use ratelim::Limiter;
+/// use ratelim::logic::FnLogic;
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let timed_out = Arc::new(AtomicBool::new(false));
+/// let (ready, add, free) = (timed_out.clone(), timed_out.clone(), timed_out);
+///
+/// let limiter = Limiter::new(FnLogic::new(
+///     move || !ready.load(Ordering::Relaxed),
+///     move |_: &()| {
+///         add.store(true, Ordering::Relaxed);
+///         Duration::from_millis(500)
+///     },
+///     move |_: &()| free.store(false, Ordering::Relaxed),
+/// ));
+///
+/// assert!(limiter.try_sync(()).await.is_ok());
+/// assert!(limiter.try_sync(()).await.is_err());
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct FnLogic<R, A, F> {
+    is_ready: R,
+    add_for: A,
+    free: F,
+}
+
+impl<R, A, F> FnLogic<R, A, F> {
+    pub fn new(is_ready: R, add_for: A, free: F) -> FnLogic<R, A, F> { FnLogic { is_ready, add_for, free } }
+}
+
+impl<State, R, A, F> Logic<State> for FnLogic<R, A, F>
+where
+    R: Fn() -> bool,
+    A: FnMut(&State) -> Duration,
+    F: FnMut(&State),
+{
+    fn is_ready(&self) -> bool { (self.is_ready)() }
+
+    fn add_for(&mut self, state: &State) -> Duration { (self.add_for)(state) }
+
+    fn free(&mut self, state: &State) { (self.free)(state) }
+}
+
+/// Only grants during the configured wall-clock windows, e.g. off-peak hours for batch jobs.
+/// Windows are `((start_hour, start_minute), (end_hour, end_minute))` pairs, end excluded,
+/// and may wrap around midnight.
+///
+/// The wall clock is a plain `fn() -> (hour, minute)`, `Schedule::utc` uses the system time in UTC.
+/// It only resolves minutes, so within the minute before a window opens the waiters re-check every second.
+#[cfg(feature = "schedule")]
+#[derive(Clone, Debug)]
+pub struct Schedule {
+    windows: Vec<((u32, u32), (u32, u32))>,
+    clock: fn() -> (u32, u32),
+}
+
+#[cfg(feature = "schedule")]
+impl Schedule {
+    const MINUTES_PER_DAY: u32 = 24 * 60;
+
+    pub fn new(windows: Vec<((u32, u32), (u32, u32))>, clock: fn() -> (u32, u32)) -> Schedule {
+        Schedule { windows, clock }
+    }
+
+    pub fn utc(windows: Vec<((u32, u32), (u32, u32))>) -> Schedule {
+        Schedule::new(windows, || {
+            let since_epoch = std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default();
+            let minute = (since_epoch.as_secs() / 60 % Schedule::MINUTES_PER_DAY as u64) as u32;
+            (minute / 60, minute % 60)
+        })
+    }
+
+    fn now(&self) -> u32 {
+        let (hour, minute) = (self.clock)();
+        hour * 60 + minute
+    }
+
+    fn is_open_at(&self, now: u32) -> bool {
+        self.windows.iter().any(|&((start_hour, start_minute), (end_hour, end_minute))| {
+            let (start, end) = (start_hour * 60 + start_minute, end_hour * 60 + end_minute);
+            if start <= end { (start..end).contains(&now) } else { now >= start || now < end }
+        })
+    }
+
+    /// Time until the next window opens, strictly after `now`.
+    fn until_next_window(&self, now: u32) -> Duration {
+        let minutes = self.windows.iter()
+            .map(|&((hour, minute), _)| (hour * 60 + minute + Schedule::MINUTES_PER_DAY - now - 1) % Schedule::MINUTES_PER_DAY + 1)
+            .min()
+            .unwrap_or(Schedule::MINUTES_PER_DAY);
+        Duration::from_secs(minutes as u64 * 60)
+    }
+}
+
+#[cfg(feature = "schedule")]
+impl Logic<()> for Schedule {
+    fn is_ready(&self) -> bool { self.is_open_at(self.now()) }
+
+    fn add_for(&mut self, _: &()) -> Duration { Duration::ZERO }
+
+    fn free(&mut self, _: &()) {}
+
+    // the clock's minute may be about to end, the earliest the next window may open is a minute sooner
+    fn ready_in(&self) -> Option<Duration> {
+        let now = self.now();
+        let earliest = |until: Duration| until.saturating_sub(Duration::from_secs(60)).max(Duration::from_secs(1));
+        (!self.is_open_at(now)).then(|| earliest(self.until_next_window(now)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+    use crate::{Limiter, PollStrategy};
+    use super::*;
+
+    #[tokio::test]
+    async fn token_bucket() {
+        let limiter = Limiter::new(TokenBucket::new(5, 1, Duration::from_millis(20)));
+        for _ in 0..5 {
+            assert!(limiter.try_sync(1).await.is_ok());
+        }
+        assert!(limiter.try_sync(1).await.is_err());
+
+        let started = Instant::now();
+        for _ in 0..5 {
+            limiter.sync(1).await;
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(80), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn token_bucket_bounds() {
+        // a refill too slow for a `Duration` is held forever
+        let mut bucket = TokenBucket::new(1, 1, Duration::MAX);
+        assert_eq!(bucket.add_for(&u64::MAX), Duration::MAX);
+
+        assert!(std::panic::catch_unwind(|| TokenBucket::new(1, 0, Duration::from_secs(1))).is_err());
+        assert!(std::panic::catch_unwind(|| TokenBucket::new(1, 1, Duration::ZERO)).is_err());
+    }
+
+    #[tokio::test]
+    async fn rates() {
+        for rate in [Rate::per_minute(60), Rate::per_second(1), Rate::new(5, Duration::from_secs(5))] {
+            let quota = Limiter::new(QuotaPer::from(rate));
+            assert!(quota.try_sync(1).await.is_ok());
+            assert!(quota.try_sync(1).await.is_err());
+            assert_eq!(quota.with_logic(|logic| logic.max_capacity()).await, Some(1));
+
+            let bucket = Limiter::new(TokenBucket::try_from(rate).unwrap());
+            assert!(bucket.try_sync(1).await.is_ok());
+            assert!(bucket.try_sync(1).await.is_err());
+        }
+
+        for rate in [Rate::per_second(0), Rate::new(1, Duration::ZERO)] {
+            assert_eq!(TokenBucket::try_from(rate).unwrap_err(), NeverRefills(rate));
+        }
+    }
+
+    #[tokio::test]
+    async fn leaky_bucket() {
+        // timed as the bucket grants, a waiter may only get to run a while after its grant
+        struct Timed(LeakyBucket, Arc<std::sync::Mutex<Vec<Instant>>>);
+
+        impl Logic<u64> for Timed {
+            fn is_ready(&self) -> bool { self.0.is_ready() }
+
+            fn add_for(&mut self, state: &u64) -> Duration {
+                self.1.lock().unwrap().push(Instant::now());
+                self.0.add_for(state)
+            }
+
+            fn free(&mut self, _: &u64) {}
+        }
+
+        let grants = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let limiter = Limiter::new(Timed(LeakyBucket::new(1, Duration::from_millis(10)), grants.clone()));
+        assert!(limiter.try_sync(1).await.is_ok());
+
+        for _ in 0..10000 {
+            let limiter = limiter.clone();
+            tokio::spawn(async move { limiter.sync(1).await });
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let grants = grants.lock().unwrap().clone();
+        assert!((10..=21).contains(&grants.len()), "{}", grants.len());
+        for pair in grants.windows(2) {
+            assert!(pair[1] - pair[0] >= Duration::from_millis(9), "{:?}", pair[1] - pair[0]);
+        }
+    }
+
+    #[test]
+    fn leaky_bucket_bounds() {
+        // a rate above `u32::MAX` isn't truncated
+        assert_eq!(LeakyBucket::new(1 << 32, Duration::from_secs(1 << 32)).interval, Duration::from_secs(1));
+        assert!(std::panic::catch_unwind(|| LeakyBucket::new(0, Duration::from_secs(1))).is_err());
+
+        // a cost too large for an `Instant` never lets anything through again
+        let mut logic = LeakyBucket::new(1, Duration::MAX);
+        logic.add_for(&u64::MAX);
+        assert!(!logic.is_ready());
+    }
+
+    #[test]
+    fn leaky_bucket_credit() {
+        let interval = Duration::from_millis(10);
+        let mut logic = LeakyBucket::new(1, interval).with_credit(2);
+        let started = Instant::now();
+        assert_eq!(logic.add_at(started, 1), interval);
+
+        // long idle, only the two saved up go right away, plus the one due anyway
+        let now = started + Duration::from_secs(60);
+        for _ in 0..3 {
+            assert!(logic.is_ready_at(now));
+            logic.add_at(now, 1);
+        }
+        assert!(!logic.is_ready_at(now));
+
+        // a cost of 3 holds the next grant back for 3 intervals
+        let later = now + interval;
+        assert_eq!(logic.add_at(later, 3), interval * 3);
+        assert!(!logic.is_ready_at(later + interval * 2));
+        assert!(logic.is_ready_at(later + interval * 3));
+        // combines with the other `u64` logics
+        let logic = And::new(LeakyBucket::new(1, interval).with_credit(2), Concurrency::new(1));
+        assert!(logic.is_ready_for(&1));
+    }
+
+    #[tokio::test]
+    async fn gcra() {
+        let limiter = Limiter::new(Gcra::new((1, Duration::from_millis(20)), 3));
+        for _ in 0..3 {
+            assert!(limiter.try_sync(1).await.is_ok());
+        }
+        assert!(limiter.try_sync(1).await.is_err());
+
+        let started = Instant::now();
+        for _ in 0..3 {
+            limiter.sync(1).await;
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(50), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn gcra_cost() {
+        let limiter = Limiter::new(Gcra::new((1, Duration::from_secs(60)), 3));
+        assert!(limiter.try_sync(2).await.is_ok());
+        assert_eq!(limiter.try_sync(2).await, Err(2));
+        assert!(limiter.try_sync(1).await.is_ok());
+
+        // above the burst, only with nothing outstanding
+        let limiter = Limiter::new(Gcra::new((1, Duration::from_secs(60)), 3));
+        assert!(limiter.try_sync(5).await.is_ok());
+        assert!(limiter.try_sync(1).await.is_err());
+    }
+
+    #[test]
+    fn gcra_bounds() {
+        assert_eq!(Gcra::new((1 << 32, Duration::from_secs(1 << 32)), 1).emission_interval, Duration::from_secs(1));
+
+        assert_eq!(Gcra::new((1, Duration::MAX), u64::MAX).burst_tolerance, Duration::MAX);
+
+        // a TAT too far to represent never conforms
+        let mut gcra = Gcra::new((1, Duration::MAX), 1);
+        gcra.add_for(&u64::MAX);
+        gcra.add_for(&u64::MAX);
+        assert!(!gcra.is_ready_for(&1));
+    }
+
+    #[tokio::test]
+    async fn sliding_window_log() {
+        let window = Duration::from_millis(100);
+        let limiter = Limiter::new(SlidingWindowLog::new(2, window));
+        let started = Instant::now();
+        assert!(limiter.try_sync(()).await.is_ok());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(limiter.try_sync(()).await.is_ok());
+        assert!(limiter.try_sync(()).await.is_err());
+
+        // the first grant ages out at the window boundary, the second one is still counted
+        limiter.sync(()).await;
+        assert!(started.elapsed() >= window);
+        assert!(limiter.try_sync(()).await.is_err());
+
+        tokio::time::sleep(window).await;
+        assert!(limiter.try_sync(()).await.is_ok());
+        assert!(limiter.try_sync(()).await.is_ok());
+        assert!(limiter.try_sync(()).await.is_err());
+    }
+
+    /// 3 of 4 units in use, a cost of 2 has to wait while 1 still fits.
+    async fn defers_cost<L: Logic<u64> + Send + 'static>(logic: L) {
+        let limiter = Limiter::new(logic);
+        assert!(limiter.try_sync(3).await.is_ok());
+        assert_eq!(limiter.try_sync(2).await, Err(2));
+        assert!(limiter.try_sync(1).await.is_ok());
+        assert_eq!(limiter.try_sync(1).await, Err(1));
+    }
+
+    #[tokio::test]
+    async fn soft_hard() {
+        let window = Duration::from_millis(20);
+        let mut logic = SoftHard::new(2, 4, window, 3.0);
+        let holds: Vec<_> = (0..4).map(|_| logic.add_for(&1)).collect();
+        assert_eq!(holds, [window, window, window * 3, window * 3]);
+        assert!(!logic.is_ready());
+
+        // the band is still admitted right away
+        let limiter = Limiter::new(SoftHard::new(2, 4, window, 3.0));
+        for _ in 0..4 {
+            assert!(limiter.try_sync(1).await.is_ok());
+        }
+        assert!(limiter.try_sync(1).await.is_err());
+
+        // the two slots below the soft limit come back after the window, but are taken in the band again,
+        // so the next ones wait for the slowed down frees
+        let started = Instant::now();
+        for _ in 0..3 {
+            limiter.sync(1).await;
+        }
+        let elapsed = started.elapsed();
+        assert!(elapsed >= window * 3 - Duration::from_millis(1), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+    }
+
+    #[tokio::test]
+    async fn soft_hard_cost() { defers_cost(SoftHard::new(2, 4, Duration::from_secs(60), 2.0)).await }
+
+    #[tokio::test]
+    async fn load_dependent() {
+        let ms = Duration::from_millis;
+        let mut logic = LoadDependent::new(4, ms(10), ms(50));
+        assert_eq!([logic.add_for(&2), logic.add_for(&1), logic.add_for(&1)], [ms(10), ms(30), ms(40)]);
+        assert!(!logic.is_ready());
+
+        let clock = crate::clock::MockClock::new();
+        let limiter = Limiter::with_clock(LoadDependent::new(4, ms(10), ms(50)), clock.clone());
+        assert!(limiter.try_sync(3).await.is_ok());
+        // held until 40ms
+        assert!(limiter.try_sync(1).await.is_ok());
+        clock.advance(ms(10));
+        assert_eq!(limiter.pending().await, 1);
+        // scheduled after the long one, but held until 30ms only
+        assert!(limiter.try_sync(2).await.is_ok());
+
+        clock.advance(ms(20));
+        assert_eq!(limiter.pending().await, 1);
+        assert_eq!(limiter.with_logic(LoadDependent::used).await, 1);
+        clock.advance(ms(10));
+        assert_eq!(limiter.pending().await, 0);
+        assert_eq!(limiter.with_logic(LoadDependent::used).await, 0);
+    }
+
+    #[tokio::test]
+    async fn load_dependent_cost() { defers_cost(LoadDependent::new(4, Duration::from_secs(1), Duration::from_secs(60))).await }
+
+    #[tokio::test]
+    async fn sliding_window_counter_cost() { defers_cost(SlidingWindowCounter::new(4, Duration::from_secs(60))).await }
+
+    #[test]
+    fn sliding_window_counter() {
+        let window = Duration::from_millis(100);
+        let mut log = SlidingWindowLog::new(10, window);
+        let mut counter = SlidingWindowCounter::new(10, window);
+        let started = counter.window_start;
+
+        let (mut agreed, mut total) = (0, 0);
+        for tick in 0..10_000 {
+            let now = started + Duration::from_micros(tick * 100);
+
+            let log_ready = log.in_window(now) < log.limit;
+            if log_ready {
+                log.add_at(now);
+            }
+            let counter_ready = counter.is_ready_at(now);
+            if counter_ready {
+                counter.add_at(now, 1);
+            }
+
+            agreed += (log_ready == counter_ready) as u64;
+            total += 1;
+        }
+
+        // evenly spread attempts keep the decisions within the documented couple of percent
+        assert!(agreed as f64 / total as f64 > 0.98, "{} / {}", agreed, total);
+    }
+
+    #[test]
+    fn sliding_window_counter_bounds() {
+        // more windows passed than fit in a `u32`
+        let counter = SlidingWindowCounter::new(1, Duration::from_nanos(2));
+        let now = counter.window_start + Duration::from_secs(10) + Duration::from_nanos(1);
+        assert_eq!(counter.windows_at(now), (0, 0, Duration::from_nanos(1)));
+
+        assert!(std::panic::catch_unwind(|| SlidingWindowCounter::new(1, Duration::ZERO)).is_err());
+    }
+
+    #[test]
+    fn fixed_window_bounds() {
+        // more windows passed than fit in a `u32`
+        let logic = FixedWindow::new(1, Duration::from_nanos(2));
+        let now = logic.window_start + Duration::from_secs(10) + Duration::from_nanos(1);
+        assert_eq!(logic.window_at(now), now - Duration::from_nanos(1));
+
+        assert!(std::panic::catch_unwind(|| FixedWindow::new(1, Duration::ZERO)).is_err());
+    }
+
+    #[test]
+    fn fixed_window_boundary_burst() {
+        let anchor = Instant::now();
+        let window = Duration::from_secs(60);
+        let mut logic = FixedWindow::with_anchor(5, window, anchor);
+
+        // the whole quota right before the boundary...
+        let before = anchor + window - Duration::from_millis(1);
+        for _ in 0..5 {
+            assert!(logic.is_ready_at(before));
+            logic.add_at(before, 1);
+        }
+        assert!(!logic.is_ready_at(before));
+
+        // ...and again right after it, twice the limit within 2ms
+        let after = anchor + window + Duration::from_millis(1);
+        for _ in 0..5 {
+            assert!(logic.is_ready_at(after));
+            logic.add_at(after, 1);
+        }
+        assert!(!logic.is_ready_at(after));
+        assert!(logic.is_ready_at(anchor + window * 2));
+    }
+
+    #[tokio::test]
+    async fn fixed_window_cost() { defers_cost(FixedWindow::new(4, Duration::from_secs(60))).await }
+
+    #[tokio::test]
+    async fn concurrency_cost() { defers_cost(Concurrency::new(4)).await }
+
+    #[tokio::test]
+    async fn concurrency() {
+        let limiter = Limiter::new(Concurrency::new(10));
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+
+        let mut tasks = Vec::new();
+        for i in 0..1000 {
+            let limiter = limiter.clone();
+            let in_flight = in_flight.clone();
+            let max_in_flight = max_in_flight.clone();
+            tasks.push(tokio::spawn(async move {
+                let _permit = limiter.acquire(1).await;
+                let current = in_flight.fetch_add(1, SeqCst) + 1;
+                max_in_flight.fetch_max(current, SeqCst);
+
+                tokio::time::sleep(Duration::from_micros(i * 7919 % 2000)).await;
+                in_flight.fetch_sub(1, SeqCst);
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert!(max_in_flight.load(SeqCst) <= 10);
+        assert_eq!(limiter.internal.lock().await.logic.in_flight, 0);
+    }
+
+    #[tokio::test]
+    async fn aimd_cost() { defers_cost(Aimd::new(4, 1, 10, 1, 0.5)).await }
+
+    #[tokio::test]
+    async fn aimd_converges() {
+        const THRESHOLD: u64 = 40;
+        let limiter = Limiter::new(Aimd::new(1, 1, 1000, 1, 0.5));
+
+        let mut limits = Vec::new();
+        for _ in 0..500 {
+            // the server breaks down once more than `THRESHOLD` requests are in flight
+            let mut permits = Vec::new();
+            while let Ok(permit) = tokio::time::timeout(Duration::from_millis(1), limiter.acquire(1)).await {
+                permits.push(permit);
+            }
+            let outcome = if permits.len() as u64 > THRESHOLD { Outcome::Overloaded } else { Outcome::Success };
+            drop(permits);
+
+            limiter.feed(outcome).await;
+            limits.push(limiter.internal.lock().await.logic.limit());
+        }
+
+        let settled = &limits[limits.len() - 200..];
+        assert!(settled.iter().all(|limit| (THRESHOLD / 2..=THRESHOLD + 1).contains(limit)), "{:?}", settled);
+        let average = settled.iter().sum::<u64>() as f64 / settled.len() as f64;
+        assert!(average > THRESHOLD as f64 * 0.6, "{}", average);
+    }
+
+    #[tokio::test]
+    async fn circuit_breaker() {
+        let cooldown = Duration::from_millis(50);
+        let limiter = Limiter::new(CircuitBreaker::new(3, cooldown));
+
+        // closed
+        for _ in 0..3 {
+            assert!(limiter.try_sync(()).await.is_ok());
+            limiter.feed(Outcome::Overloaded).await;
+        }
+
+        // open
+        assert!(limiter.internal.lock().await.logic.is_open());
+        assert!(limiter.try_sync(()).await.is_err());
+
+        // half-open, a failed probe re-opens
+        tokio::time::sleep(cooldown).await;
+        assert!(limiter.try_sync(()).await.is_ok());
+        assert!(limiter.try_sync(()).await.is_err());
+        limiter.feed(Outcome::Overloaded).await;
+        assert!(limiter.try_sync(()).await.is_err());
+
+        // half-open, a successful probe closes
+        tokio::time::sleep(cooldown).await;
+        assert!(limiter.try_sync(()).await.is_ok());
+        assert!(limiter.try_sync(()).await.is_err());
+        limiter.feed(Outcome::Success).await;
+        for _ in 0..10 {
+            assert!(limiter.try_sync(()).await.is_ok());
+        }
+
+        // half-open, an unanswered probe times out and re-opens
+        for _ in 0..3 {
+            limiter.feed(Outcome::Overloaded).await;
+        }
+        tokio::time::sleep(cooldown).await;
+        assert!(limiter.try_sync(()).await.is_ok());
+        tokio::time::sleep(cooldown).await;
+        assert!(limiter.try_sync(()).await.is_err());
+        assert!(limiter.internal.lock().await.logic.is_open());
+        tokio::time::sleep(cooldown).await;
+        assert!(limiter.try_sync(()).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn circuit_breaker_ready_in() {
+        let cooldown = Duration::from_millis(50);
+        let limiter = Limiter::builder(CircuitBreaker::new(1, cooldown)).poll_strategy(PollStrategy::UntilNextFree).build();
+        limiter.feed(Outcome::Overloaded).await;
+        let ready_in = limiter.internal.lock().await.logic.ready_in().unwrap();
+        assert!(ready_in > Duration::ZERO && ready_in <= cooldown, "{:?}", ready_in);
+
+        // no free to wake up for, the cooldown ending does
+        let started = Instant::now();
+        assert!(tokio::time::timeout(cooldown * 4, limiter.sync(())).await.is_ok());
+        assert!(started.elapsed() >= cooldown - Duration::from_millis(5), "{:?}", started.elapsed());
+    }
+
+    #[test]
+    fn backoff_progression() {
+        let mut logic = Backoff::new(Duration::from_millis(100), 2.0, Duration::from_secs(1));
+        let delays: Vec<_> = (0..6).map(|_| {
+            let delay = logic.add_for(&());
+            logic.free(&());
+            delay.as_millis()
+        }).collect();
+        assert_eq!(delays, [100, 200, 400, 800, 1000, 1000]);
+
+        logic.observe(Outcome::Success);
+        assert_eq!(logic.add_for(&()), Duration::from_millis(100));
+        assert!(!logic.is_ready());
+    }
+
+    #[test]
+    fn backoff_jitter() {
+        let delays = |jitter| {
+            let mut logic = Backoff::new(Duration::from_millis(100), 2.0, Duration::from_secs(1))
+                .with_jitter(jitter)
+                .with_rng_seed(42);
+            (0..6).map(|_| {
+                let delay = logic.add_for(&());
+                logic.free(&());
+                delay.as_millis()
+            }).collect::<Vec<_>>()
+        };
+
+        assert_eq!(delays(JitterKind::Full), delays(JitterKind::Full));
+        assert_eq!(delays(JitterKind::Full), [74, 31, 111, 275, 38, 868]);
+        assert_eq!(delays(JitterKind::Equal), [87, 115, 255, 537, 519, 934]);
+        assert_eq!(delays(JitterKind::Decorrelated), [248, 203, 241, 315, 132, 357]);
+    }
+
+    #[tokio::test]
+    async fn and() {
+        let limiter = Limiter::new(And::new(TokenBucket::new(3, 1, Duration::from_secs(60)), Concurrency::new(2)));
+        let blocked = || tokio::time::timeout(Duration::from_millis(10), limiter.acquire(1));
+
+        // the concurrency binds first...
+        let first = limiter.acquire(1).await;
+        let second = limiter.acquire(1).await;
+        assert!(blocked().await.is_err());
+
+        // ...then the bucket runs out of tokens
+        drop(first);
+        let third = limiter.acquire(1).await;
+        drop((second, third));
+        assert!(blocked().await.is_err());
+
+        // the tighter of the two limits
+        let mut logic = And::new(TokenBucket::new(3, 1, Duration::from_secs(60)), Concurrency::new(2));
+        assert_eq!(logic.max_capacity(), Some(2));
+        logic.add_for(&1);
+        logic.add_for(&1);
+        logic.free(&1);
+        assert_eq!(logic.headroom(), Some(1));
+        assert_eq!(And::new(Concurrency::new(2), FnLogic::new(|| true, |_: &u64| Duration::ZERO, |_: &u64| {})).max_capacity(), Some(2));
+
+        // ready once the later of the two is
+        let mut logic = And::new(RetryAfter::new(Concurrency::new(1)), RetryAfter::new(Concurrency::new(1)));
+        logic.left.observe(Duration::from_secs(1));
+        logic.right.observe(Duration::from_secs(60));
+        assert!(logic.ready_in().unwrap() > Duration::from_secs(59));
+    }
+
+    #[tokio::test]
+    async fn or() {
+        let limiter = Limiter::new(Or::new(
+            QuotaPer::new(1, Duration::from_millis(50)),
+            QuotaPer::new(1, Duration::from_secs(60)),
+        ));
+
+        assert!(limiter.try_sync(1.into()).await.is_ok());
+        // the primary is exhausted, but the fallback admits
+        assert!(limiter.try_sync(1.into()).await.is_ok());
+        assert!(limiter.try_sync(1.into()).await.is_err());
+
+        // only the primary's reservation is freed
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(limiter.try_sync(1.into()).await.is_ok());
+        assert!(limiter.try_sync(1.into()).await.is_err());
+
+        let internal = limiter.internal.lock().await;
+        assert_eq!((internal.logic.primary.headroom(), internal.logic.fallback.headroom()), (Some(0), Some(0)));
+    }
+
+    #[tokio::test]
+    async fn or_out_of_order() {
+        let limiter = Limiter::new(Or::new(Concurrency::new(1), Concurrency::new(1)));
+        let primary = limiter.acquire(1.into()).await;
+        let fallback = limiter.acquire(1.into()).await;
+
+        // the later grant ends first, it still credits the fallback
+        drop(fallback);
+        let headrooms = || limiter.with_logic(|logic| (logic.primary.headroom(), logic.fallback.headroom()));
+        assert_eq!(headrooms().await, (Some(0), Some(1)));
+        drop(primary);
+        assert_eq!(headrooms().await, (Some(1), Some(1)));
+    }
+
+    #[tokio::test]
+    async fn with_burst() {
+        let limiter = Limiter::new(WithBurst::new(QuotaPer::new(2, Duration::from_millis(50)), 3, Duration::from_secs(60)));
+        let granted = || async {
+            let mut granted = 0;
+            while limiter.try_sync(1).await.is_ok() {
+                granted += 1;
+            }
+            granted
+        };
+
+        // the steady quota plus the whole burst
+        assert_eq!(granted().await, 5);
+
+        // the burst credits stay spent, only the steady quota comes back
+        for _ in 0..3 {
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            assert_eq!(granted().await, 2);
+        }
+
+        // a burst grant freed doesn't free a slot of the inner logic
+        let mut logic = WithBurst::new(Concurrency::new(1), 1, Duration::from_secs(60));
+        let (_, steady) = logic.reserve(1);
+        let (_, burst) = logic.reserve(1);
+        logic.free(&burst);
+        assert!(!logic.is_ready_for(&1));
+        logic.free(&steady);
+        assert!(logic.is_ready_for(&1));
+    }
+
+    #[tokio::test]
+    async fn distinct_concurrency() {
+        let limiter = Limiter::new(DistinctConcurrency::new(2));
+        let alice = limiter.acquire("alice").await;
+        // reconnecting takes no new slot
+        let alice_again = limiter.acquire("alice").await;
+        let bob = limiter.acquire("bob").await;
+        assert_eq!(limiter.with_logic(|logic| (logic.active(), logic.held_by(&"alice"))).await, (2, 2));
+        assert!(limiter.try_sync("carol").await.is_err());
+        // but a key holding a slot is still let in
+        let alice_third = tokio::time::timeout(Duration::from_millis(100), limiter.acquire("alice")).await.unwrap();
+        drop(alice_third);
+
+        // alice still holds a slot
+        drop(alice);
+        assert!(limiter.try_sync("carol").await.is_err());
+        drop(alice_again);
+        assert!(limiter.try_sync("carol").await.is_ok());
+        drop(bob);
+        assert_eq!(limiter.with_logic(DistinctConcurrency::active).await, 1);
+    }
+
+    #[tokio::test]
+    async fn multi_limit() {
+        // 10 requests per second, but only 1000 bytes per 100ms
+        let limiter = Limiter::new(MultiLimit::new([
+            QuotaPer::new(10, Duration::from_secs(1)),
+            QuotaPer::new(1000, Duration::from_millis(100)),
+        ]));
+
+        assert!(limiter.try_sync([1, 600]).await.is_ok());
+        assert!(limiter.try_sync([1, 400]).await.is_ok());
+        // the bytes are used up, the requests are not
+        assert_eq!(limiter.try_sync([1, 600]).await, Err([1, 600]));
+        assert_eq!(limiter.headroom().await, Some([8, 0]));
+
+        // only the bytes are freed by now
+        let started = Instant::now();
+        limiter.sync([1, 600]).await;
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(80), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_millis(500), "{:?}", elapsed);
+        assert_eq!(limiter.headroom().await, Some([7, 400]));
+    }
+
+    #[test]
+    fn ewma() {
+        let clock = crate::clock::MockClock::new();
+        let mut ewma = Ewma::new(10.0, Duration::from_secs(1)).with_clock(clock.clone());
+        assert!(ewma.is_ready());
+
+        // a burst of 20 weighs 20 * ln 2 per second right away
+        for _ in 0..20 {
+            ewma.add_for(&());
+        }
+        let peak = ewma.rate();
+        assert!((peak - 20.0 * std::f64::consts::LN_2).abs() < 1e-9, "{peak}");
+        assert!(!ewma.is_ready());
+        assert!(ewma.ready_in().is_some());
+
+        // halved after a half-life, back under the target
+        clock.advance(Duration::from_secs(1));
+        assert!((ewma.rate() - peak / 2.0).abs() < 1e-9, "{}", ewma.rate());
+        assert!(ewma.is_ready());
+        assert_eq!(ewma.ready_in(), None);
+
+        clock.advance(Duration::from_secs(1));
+        assert!((ewma.rate() - peak / 4.0).abs() < 1e-9, "{}", ewma.rate());
+    }
+
+    #[tokio::test]
+    async fn probabilistic() {
+        async fn admitted(limiter: &Limiter<Probabilistic, ()>) -> usize {
+            let mut admitted = 0;
+            for _ in 0..100_000 {
+                admitted += usize::from(limiter.try_sync(()).await.is_ok());
+            }
+            admitted
+        }
+
+        // a single draw per admission, however often the limiter asks for readiness
+        let limiter = Limiter::new(Probabilistic::new(0.5).with_rng_seed(7));
+        let granted = admitted(&limiter).await;
+        let ratio = granted as f64 / 100_000.0;
+        assert!((ratio - 0.5).abs() < 0.01, "{ratio}");
+
+        // the same seed draws the same
+        let replay = Limiter::new(Probabilistic::new(0.5).with_rng_seed(7));
+        assert_eq!(admitted(&replay).await, granted);
+    }
+
+    #[tokio::test]
+    async fn ramp_up() {
+        let limiter = Limiter::new(RampUp::new(QuotaPer::new(10, Duration::from_secs(60)), 0.2, Duration::from_millis(200)));
+        let granted = || async {
+            let mut granted = 0;
+            while limiter.try_sync(1).await.is_ok() {
+                granted += 1;
+            }
+            granted
+        };
+
+        // a fifth of the quota right away, the rest once warmed up
+        assert_eq!(granted().await, 2);
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(granted().await, 8);
+    }
+
+    #[tokio::test]
+    async fn ramp_up_cost() {
+        // half of the quota while warming up
+        defers_cost(RampUp::new(QuotaPer::new(8, Duration::from_secs(60)), 0.5, Duration::from_secs(60))).await;
+
+        // above the scaled capacity, but not the full one, waits for the ramp
+        let limiter = Limiter::new(RampUp::new(QuotaPer::new(8, Duration::from_secs(60)), 0.5, Duration::from_secs(60)));
+        assert_eq!(limiter.try_sync(6).await, Err(6));
+        assert!(limiter.try_sync(9).await.is_ok());
+    }
+
+    #[test]
+    fn ramp_up_curve() {
+        let ramp = RampUp::new(QuotaPer::new(100, Duration::from_secs(60)), 0.0, Duration::from_secs(100))
+            .with_curve(|progress| progress * progress);
+        let (started, warm_at) = (ramp.started, ramp.warm_at());
+
+        assert_eq!(ramp.capacity_at(started), Some(0));
+        assert_eq!(ramp.capacity_at(started + Duration::from_secs(50)), Some(25));
+        assert_eq!(ramp.capacity_at(warm_at - Duration::from_millis(1)), Some(99));
+        assert_eq!(ramp.capacity_at(warm_at), None);
+    }
+
+    #[cfg(feature = "schedule")]
+    #[tokio::test]
+    async fn schedule() {
+        use std::sync::atomic::AtomicU32;
+
+        static NOW: AtomicU32 = AtomicU32::new(8 * 60 + 59);
+        let limiter = Limiter::new(Schedule::new(
+            vec![((9, 0), (17, 0)), ((22, 0), (2, 0))],
+            || (NOW.load(SeqCst) / 60, NOW.load(SeqCst) % 60),
+        ));
+        assert!(limiter.try_sync(()).await.is_err());
+        // 08:59 may well be 08:59:59
+        assert_eq!(limiter.with_logic(|logic| logic.ready_in()).await, Some(Duration::from_secs(1)));
+
+        let waiter = {
+            let limiter = limiter.clone();
+            tokio::spawn(async move { limiter.sync(()).await })
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        assert!(!waiter.is_finished());
+
+        NOW.store(9 * 60, SeqCst);
+        tokio::time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap();
+        assert_eq!(limiter.with_logic(|logic| logic.ready_in()).await, None);
+
+        let logic = &limiter.internal.lock().await.logic;
+        assert_eq!(logic.until_next_window(9 * 60), Duration::from_secs(13 * 60 * 60));
+        NOW.store(21 * 60, SeqCst);
+        assert_eq!(logic.ready_in(), Some(Duration::from_secs(59 * 60)));
+        assert!(logic.is_open_at(23 * 60) && logic.is_open_at(60) && !logic.is_open_at(2 * 60));
+    }
+
+    #[tokio::test]
+    async fn fractional_quota() {
+        let limiter = Limiter::new(QuotaPer::new(1.0, Duration::from_secs(60)));
+        assert!(limiter.try_sync(0.25).await.is_ok());
+        assert!(limiter.try_sync(0.5).await.is_ok());
+        assert!(limiter.try_sync(0.25).await.is_ok());
+        assert!(limiter.try_sync(0.125).await.is_err());
+        assert_eq!(limiter.internal.lock().await.logic.headroom(), Some(0.0));
+
+        let limiter = Limiter::new(QuotaPer::new(3u32, Duration::from_secs(60)));
+        assert!(limiter.try_sync(2).await.is_ok());
+        assert!(limiter.try_sync(1).await.is_ok());
+        assert!(limiter.try_sync(1).await.is_err());
+    }
+
+    #[test]
+    fn retry_after_parsing() {
+        let date = UNIX_EPOCH + Duration::from_secs(784111777);
+        assert_eq!(parse_http_date("Sun, 06 Nov 1994 08:49:37 GMT"), Some(date));
+        assert_eq!(parse_http_date("Thu, 29 Feb 2024 23:59:59 GMT"), Some(UNIX_EPOCH + Duration::from_secs(1709251199)));
+
+        assert_eq!(parse_retry_after(" 120 "), Some(Duration::from_secs(120)));
+        assert_eq!(retry_after_at("Sun, 06 Nov 1994 08:49:37 GMT", date - Duration::from_secs(30)), Some(Duration::from_secs(30)));
+        // already passed
+        assert_eq!(parse_retry_after("Sun, 06 Nov 1994 08:49:37 GMT"), Some(Duration::ZERO));
+
+        for invalid in ["", "-1", "soon", "Sun, 06 Nov 1994 08:49:37 UTC", "Sun, 06 Foo 1994 08:49:37 GMT", "Sun, 06 Nov 1994 08:49 GMT"] {
+            assert_eq!(parse_retry_after(invalid), None, "{}", invalid);
+        }
+        // a year too far to represent
+        assert_eq!(parse_retry_after("Sun, 06 Nov 18446744073709551615 08:49:37 GMT"), None);
+    }
+
+    #[tokio::test]
+    async fn retry_after() {
+        let limiter = Limiter::new(RetryAfter::new(QuotaPer::new(10, Duration::from_secs(1))));
+        assert!(limiter.try_sync(1).await.is_ok());
+
+        limiter.feed(Duration::from_millis(50)).await;
+        limiter.feed(Duration::from_millis(10)).await;
+        assert!(limiter.try_sync(1).await.is_err());
+
+        let started = std::time::Instant::now();
+        limiter.sync(1).await;
+        // woken by the end of the pause, not by the next free a second later
+        let elapsed = started.elapsed();
+        assert!(elapsed >= Duration::from_millis(40) && elapsed < Duration::from_millis(500), "{:?}", elapsed);
+
+        // an absurd delay pauses for good instead of overflowing
+        limiter.feed(parse_retry_after("18446744073709551615").unwrap()).await;
+        assert!(limiter.try_sync(1).await.is_err());
+        assert!(limiter.with_logic(|logic| logic.paused_until()).await.is_some());
+    }
+}
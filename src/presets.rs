@@ -0,0 +1,56 @@
+//! Limiters tuned to the documented limits of well-known APIs, as they were at the time of writing.
+//! Limits change and often depend on the plan, prefer the `X-RateLimit-*` headers where there are any.
+//!
+//! ## Example
+//!
+//! ```
+//! use ratelim::presets;
+//!
+//! # #[tokio::main]
+//! # async fn main() {
+//! let github = presets::github_rest();
+//! github.sync(1).await;
+//! # }
+//! ```
+
+use crate::Limiter;
+use crate::logic::QuotaPer;
+
+/// GitHub REST API, authenticated: 5000 requests per hour.
+pub fn github_rest() -> Limiter<QuotaPer, u64> { Limiter::new(QuotaPer::per_hour(5000)) }
+
+/// GitHub REST API, unauthenticated: 60 requests per hour.
+pub fn github_rest_unauthenticated() -> Limiter<QuotaPer, u64> { Limiter::new(QuotaPer::per_hour(60)) }
+
+/// GitHub search API, authenticated: 30 requests per minute.
+pub fn github_search() -> Limiter<QuotaPer, u64> { Limiter::new(QuotaPer::per_minute(30)) }
+
+/// Discord API, global limit per bot: 50 requests per second.
+pub fn discord_global() -> Limiter<QuotaPer, u64> { Limiter::new(QuotaPer::per_second(50)) }
+
+/// Telegram Bot API, bulk messages: 30 per second.
+pub fn telegram_bot() -> Limiter<QuotaPer, u64> { Limiter::new(QuotaPer::per_second(30)) }
+
+/// Slack Web API, by method tier: 1, 20, 50 or 100 requests per minute for tiers 1 to 4, the latter for any other tier.
+pub fn slack(tier: u8) -> Limiter<QuotaPer, u64> {
+    let per_minute = match tier {
+        1 => 1,
+        2 => 20,
+        3 => 50,
+        _ => 100,
+    };
+    Limiter::new(QuotaPer::per_minute(per_minute))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn quotas() {
+        assert_eq!(github_rest().headroom().await, Some(5000));
+        assert_eq!(github_search().headroom().await, Some(30));
+        assert_eq!(slack(2).headroom().await, Some(20));
+        assert_eq!(slack(9).headroom().await, Some(100));
+    }
+}
@@ -0,0 +1,66 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::time::Duration;
+use crate::time::Instant;
+
+/// Tickets of the queued waiters, see `Fairness::Fifo` and `Limiter::sync_with_priority`.
+/// The first one is the highest priority, earliest arrival breaking ties.
+#[derive(Default)]
+pub(crate) struct WaitQueue {
+    next: u64,
+    // waiters of every priority in arrival order, with their arrival instant
+    levels: BTreeMap<u8, VecDeque<(u64, Instant)>>,
+    aging: Option<Duration>,
+}
+
+impl WaitQueue {
+    /// Queues a new waiter of priority `prio` and returns its ticket.
+    pub fn push(&mut self, prio: u8) -> u64 {
+        let ticket = self.next;
+        self.next += 1;
+        self.levels.entry(prio).or_default().push_back((ticket, Instant::now()));
+        ticket
+    }
+
+    /// Removes `ticket` of priority `prio`, returns whether it was the first one.
+    pub fn remove(&mut self, prio: u8, ticket: u64) -> bool {
+        let is_first = self.is_first(ticket);
+        if let Some(level) = self.levels.get_mut(&prio) {
+            // tickets are increasing, so every level stays sorted
+            if let Ok(position) = level.binary_search_by_key(&ticket, |(ticket, _)| *ticket) {
+                level.remove(position);
+            }
+            if level.is_empty() {
+                self.levels.remove(&prio);
+            }
+        }
+        is_first
+    }
+
+    pub fn first(&self) -> Option<u64> {
+        let Some(aging) = self.aging else {
+            return self.levels.last_key_value().and_then(|(_, level)| level.front()).map(|(ticket, _)| *ticket);
+        };
+
+        // the oldest waiter of a level has aged the most, so only the fronts compete
+        let now = Instant::now();
+        self.levels.iter()
+            .filter_map(|(prio, level)| level.front().map(|front| (*prio, front)))
+            .map(|(prio, &(ticket, since))| {
+                let aged = now.saturating_duration_since(since).as_nanos() / aging.as_nanos().max(1);
+                (prio as u128 + aged, std::cmp::Reverse(ticket))
+            })
+            .max()
+            .map(|(_, std::cmp::Reverse(ticket))| ticket)
+    }
+
+    pub fn is_first(&self, ticket: u64) -> bool { self.first() == Some(ticket) }
+
+    pub fn is_empty(&self) -> bool { self.levels.is_empty() }
+
+    pub fn len(&self) -> usize { self.levels.values().map(VecDeque::len).sum() }
+
+    /// See `LimiterBuilder::priority_aging`.
+    pub fn aging(&self) -> Option<Duration> { self.aging }
+
+    pub fn set_aging(&mut self, aging: Option<Duration>) { self.aging = aging; }
+}
@@ -0,0 +1,170 @@
+//! Redis-backed logic for limits shared across processes, enabled with the `redis` feature.
+
+use std::sync::{Mutex, PoisonError};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::Duration;
+use ::redis::aio::ConnectionManager;
+use ::redis::Script;
+use crate::logic::AsyncLogic;
+use crate::rng::Rng;
+use crate::time::Instant;
+
+// Sliding window log in a sorted set, scored by the server time in microseconds, so the instances'
+// clocks don't have to agree. Takes a slot and returns 0 if one is left, otherwise the microseconds
+// until the oldest grant leaves the window.
+const TRY_ACQUIRE: &str = r"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000000 + tonumber(time[2])
+local window = tonumber(ARGV[1])
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', now - window)
+if redis.call('ZCARD', KEYS[1]) < tonumber(ARGV[2]) then
+    redis.call('ZADD', KEYS[1], now, now .. ':' .. ARGV[3])
+    redis.call('PEXPIRE', KEYS[1], math.ceil(window / 1000))
+    return 0
+end
+local oldest = redis.call('ZRANGE', KEYS[1], 0, 0, 'WITHSCORES')
+return tonumber(oldest[2]) + window - now
+";
+
+/// How long a failed round-trip counts as not ready, so an unreachable Redis is not hammered.
+const ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+/// At most `limit` grants within any rolling `window`, counted in Redis under `key`, so every
+/// `asynchronous::AsyncLimiter` using the same key, in any process, shares the limit.
+///
+/// The check and the reservation have to be one atomic script, or two instances could both see the last
+/// slot free, so `is_ready` already takes the slot and the following `add_for` only confirms it.
+/// A `sync` cancelled right between the two leaves the slot taken for one `window`.
+///
+/// Every round-trip that finds the window full also learns when its oldest grant expires, and `is_ready`
+/// answers "not ready" locally until then, so polling waiters don't query Redis. A failed round-trip
+/// counts as not ready.
+///
+/// ## Example
+///
+/// ```no_run
+/// use std::time::Duration;
+/// use ratelim::asynchronous::AsyncLimiter;
+/// use ratelim::redis::RedisLogic;
+///
+/// # #[tokio::main]
+/// # async fn main() -> redis::RedisResult<()> {
+/// let connection = redis::aio::ConnectionManager::new(redis::Client::open("redis://127.0.0.1/")?).await?;
+/// let limiter = AsyncLimiter::new(RedisLogic::new(connection, "api:github", 5000, Duration::from_secs(3600)));
+/// limiter.sync(()).await;
+/// # Ok(())
+/// # }
+/// ```
+pub struct RedisLogic {
+    connection: ConnectionManager,
+    key: String,
+    limit: u64,
+    window: Duration,
+    script: Script,
+    // distinguishes the grants of this instance in the sorted set
+    instance: u64,
+    sequence: AtomicU64,
+    // slot taken by `is_ready` for the next `add_for`
+    reserved: AtomicBool,
+    // known to be not ready until then, see `is_ready`
+    saturated_until: Mutex<Option<Instant>>,
+}
+
+impl RedisLogic {
+    pub fn new(connection: ConnectionManager, key: impl Into<String>, limit: u64, window: Duration) -> RedisLogic {
+        RedisLogic {
+            connection,
+            key: key.into(),
+            limit,
+            window,
+            script: Script::new(TRY_ACQUIRE),
+            instance: Rng::from_entropy().next_u64(),
+            sequence: AtomicU64::new(0),
+            reserved: AtomicBool::new(false),
+            saturated_until: Mutex::new(None),
+        }
+    }
+
+    pub fn key(&self) -> &str { &self.key }
+
+    /// Runs the script, returning whether a slot was taken.
+    async fn try_acquire(&self) -> bool {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let result = self.script
+            .key(&self.key)
+            .arg(self.window.as_micros() as u64)
+            .arg(self.limit)
+            .arg(format!("{:x}-{}", self.instance, sequence))
+            .invoke_async::<u64>(&mut self.connection.clone())
+            .await;
+
+        let saturated_for = match result {
+            Ok(0) => return true,
+            Ok(micros) => Duration::from_micros(micros),
+            Err(_) => ERROR_BACKOFF,
+        };
+        *self.saturated_until.lock().unwrap_or_else(PoisonError::into_inner) = Some(Instant::now() + saturated_for);
+        false
+    }
+}
+
+impl AsyncLogic<()> for RedisLogic {
+    async fn is_ready(&self) -> bool {
+        if self.reserved.load(Ordering::Relaxed) {
+            return true;
+        }
+
+        let saturated = self.saturated_until.lock().unwrap_or_else(PoisonError::into_inner)
+            .is_some_and(|until| Instant::now() < until);
+        if saturated || !self.try_acquire().await {
+            return false;
+        }
+
+        self.reserved.store(true, Ordering::Relaxed);
+        true
+    }
+
+    async fn add_for(&mut self, _: &()) -> Duration {
+        // only if called without `is_ready`, then the slot is taken even if the window is full
+        if !self.reserved.swap(false, Ordering::Relaxed) {
+            self.try_acquire().await;
+        }
+        self.window
+    }
+
+    // grants leave the window in Redis by themselves
+    async fn free(&mut self, _: &()) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::asynchronous::AsyncLimiter;
+    use super::*;
+
+    // needs a server, e.g. `REDIS_URL=redis://127.0.0.1/ cargo test --features redis`, skipped otherwise
+    #[tokio::test]
+    async fn shared_key() {
+        let Ok(url) = std::env::var("REDIS_URL") else { return };
+        let key = format!("ratelim:test:{:x}", Rng::from_entropy().next_u64());
+
+        let mut limiters = Vec::new();
+        for _ in 0..2 {
+            let connection = ConnectionManager::new(::redis::Client::open(url.as_str()).unwrap()).await.unwrap();
+            limiters.push(AsyncLimiter::new(RedisLogic::new(connection, key.clone(), 5, Duration::from_millis(500))));
+        }
+
+        let mut granted = 0;
+        for i in 0..20 {
+            if limiters[i % 2].try_sync(()).await.is_ok() {
+                granted += 1;
+            }
+        }
+        assert_eq!(granted, 5);
+
+        // both wait for the window to move on
+        let started = std::time::Instant::now();
+        limiters[0].sync(()).await;
+        limiters[1].sync(()).await;
+        assert!(started.elapsed() >= Duration::from_millis(400), "{:?}", started.elapsed());
+    }
+}
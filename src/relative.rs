@@ -0,0 +1,58 @@
+//! Serde adapters storing `Instant`s relative to now, as an absolute `Instant` means nothing to another process.
+//! Offsets are signed seconds, negative for instants in the past.
+
+use std::collections::VecDeque;
+use std::time::Duration;
+use crate::time::Instant;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+fn to_offset(instant: Instant, now: Instant) -> f64 {
+    match instant.checked_duration_since(now) {
+        Some(ahead) => ahead.as_secs_f64(),
+        None => -now.duration_since(instant).as_secs_f64(),
+    }
+}
+
+fn from_offset(offset: f64, now: Instant) -> Instant {
+    if offset >= 0.0 {
+        now + Duration::from_secs_f64(offset)
+    } else {
+        // further in the past than the platform can represent, now is just as good
+        now.checked_sub(Duration::from_secs_f64(-offset)).unwrap_or(now)
+    }
+}
+
+pub fn serialize<S: Serializer>(instant: &Instant, serializer: S) -> Result<S::Ok, S::Error> {
+    to_offset(*instant, Instant::now()).serialize(serializer)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Instant, D::Error> {
+    Ok(from_offset(f64::deserialize(deserializer)?, Instant::now()))
+}
+
+pub mod option {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(instant: &Option<Instant>, serializer: S) -> Result<S::Ok, S::Error> {
+        instant.map(|instant| to_offset(instant, Instant::now())).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Instant>, D::Error> {
+        let now = Instant::now();
+        Ok(Option::<f64>::deserialize(deserializer)?.map(|offset| from_offset(offset, now)))
+    }
+}
+
+pub mod deque {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(instants: &VecDeque<Instant>, serializer: S) -> Result<S::Ok, S::Error> {
+        let now = Instant::now();
+        serializer.collect_seq(instants.iter().map(|&instant| to_offset(instant, now)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<VecDeque<Instant>, D::Error> {
+        let now = Instant::now();
+        Ok(Vec::<f64>::deserialize(deserializer)?.into_iter().map(|offset| from_offset(offset, now)).collect())
+    }
+}
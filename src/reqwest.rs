@@ -0,0 +1,100 @@
+//! `reqwest` integration, enabled with the `reqwest` feature.
+
+use std::time::Duration;
+use http::Extensions;
+use reqwest_middleware::reqwest::{header, Request, Response, StatusCode};
+use reqwest_middleware::{Middleware, Next, Result};
+use crate::logic::{self, parse_retry_after, Feedback};
+use crate::Limiter;
+
+/// `reqwest_middleware` middleware waiting on a `Limiter` before every request.
+///
+/// Each request reserves `state_fn(&request)`, e.g. a cost depending on the host. A `429 Too Many Requests`
+/// with a `Retry-After` header is fed back to the limiter, so wrap the logic in `logic::RetryAfter`
+/// for the following requests to wait it out. Failed requests are not retried.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use ratelim::Limiter;
+/// use ratelim::logic::{QuotaPer, RetryAfter};
+/// use ratelim::reqwest::RateLimitMiddleware;
+/// use reqwest_middleware::{reqwest, ClientBuilder};
+///
+/// let limiter = Limiter::new(RetryAfter::new(QuotaPer::new(10, Duration::from_secs(1))));
+/// let client = ClientBuilder::new(reqwest::Client::new())
+///     .with(RateLimitMiddleware::new(limiter, |_: &reqwest::Request| 1))
+///     .build();
+/// ```
+pub struct RateLimitMiddleware<Logic: logic::Logic<State>, State, F> {
+    limiter: Limiter<Logic, State>,
+    state_fn: F,
+}
+
+impl<Logic: logic::Logic<State>, State, F> RateLimitMiddleware<Logic, State, F> {
+    /// Every request reserves `state_fn(&request)` from `limiter`.
+    pub fn new(limiter: Limiter<Logic, State>, state_fn: F) -> RateLimitMiddleware<Logic, State, F> {
+        RateLimitMiddleware { limiter, state_fn }
+    }
+}
+
+#[async_trait::async_trait]
+impl<Logic, State, F> Middleware for RateLimitMiddleware<Logic, State, F>
+where
+    Logic: logic::Logic<State> + Feedback<Duration> + Send + 'static,
+    State: Send + 'static,
+    F: Fn(&Request) -> State + Send + Sync + 'static,
+{
+    async fn handle(&self, request: Request, extensions: &mut Extensions, next: Next<'_>) -> Result<Response> {
+        self.limiter.sync((self.state_fn)(&request)).await;
+        let response = next.run(request, extensions).await?;
+
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            let retry_after = response.headers().get(header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_retry_after);
+            if let Some(retry_after) = retry_after {
+                self.limiter.feed(retry_after).await;
+            }
+        }
+
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use reqwest_middleware::ClientBuilder;
+    use reqwest_middleware::reqwest::Client;
+    use wiremock::matchers::method;
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+    use crate::logic::{QuotaPer, RetryAfter};
+    use super::*;
+
+    #[tokio::test]
+    async fn retry_after() {
+        let server = MockServer::start().await;
+        Mock::given(method("GET"))
+            .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "1"))
+            .up_to_n_times(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("GET")).respond_with(ResponseTemplate::new(200)).mount(&server).await;
+
+        let limiter = Limiter::new(RetryAfter::new(QuotaPer::new(10, Duration::from_secs(1))));
+        let client = ClientBuilder::new(Client::new())
+            .with(RateLimitMiddleware::new(limiter.clone(), |_: &Request| 1))
+            .build();
+
+        let started = Instant::now();
+        assert_eq!(client.get(server.uri()).send().await.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(started.elapsed() < Duration::from_millis(500));
+
+        // the retry waits for the server
+        assert_eq!(client.get(server.uri()).send().await.unwrap().status(), StatusCode::OK);
+        assert!(started.elapsed() >= Duration::from_millis(900), "{:?}", started.elapsed());
+        assert_eq!(limiter.stats().await.granted, 2);
+    }
+}
@@ -0,0 +1,50 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// Tiny seedable PRNG (SplitMix64), so jittering logics stay reproducible in tests
+/// without pulling in `rand`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub fn new(seed: u64) -> Rng { Rng(seed) }
+
+    pub fn from_entropy() -> Rng { Rng(RandomState::new().build_hasher().finish()) }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(GAMMA);
+        mix(self.0)
+    }
+
+    /// Uniform in `[0, 1)`.
+    pub fn next_f64(&mut self) -> f64 { to_f64(self.next_u64()) }
+}
+
+/// Same generator behind a shared reference, the state only ever advances by a `fetch_add`.
+#[derive(Debug)]
+pub(crate) struct AtomicRng(AtomicU64);
+
+impl AtomicRng {
+    pub fn new(seed: u64) -> AtomicRng { AtomicRng(AtomicU64::new(seed)) }
+
+    pub fn from_entropy() -> AtomicRng { AtomicRng::new(Rng::from_entropy().0) }
+
+    /// Uniform in `[0, 1)`.
+    pub fn next_f64(&self) -> f64 { to_f64(mix(self.0.fetch_add(GAMMA, Ordering::Relaxed).wrapping_add(GAMMA))) }
+}
+
+impl Clone for AtomicRng {
+    fn clone(&self) -> AtomicRng { AtomicRng::new(self.0.load(Ordering::Relaxed)) }
+}
+
+const GAMMA: u64 = 0x9e3779b97f4a7c15;
+
+fn mix(mut z: u64) -> u64 {
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+fn to_f64(value: u64) -> f64 { (value >> 11) as f64 / (1u64 << 53) as f64 }
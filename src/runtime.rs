@@ -0,0 +1,43 @@
+//! Runtime primitives, tokio's unless the `async-std` feature swaps in async-std's.
+//! Neither the mutex nor `tokio::sync::Notify` need a tokio runtime, timers come from a `Sleeper`,
+//! so only spawning the background reclaim task depends on a runtime.
+
+#[cfg(not(feature = "async-std"))]
+pub(crate) use tokio::sync::{Mutex, MutexGuard};
+#[cfg(feature = "async-std")]
+pub(crate) use async_std::sync::{Mutex, MutexGuard};
+
+pub(crate) fn try_lock<T>(mutex: &Mutex<T>) -> Option<MutexGuard<'_, T>> {
+    #[cfg(not(feature = "async-std"))]
+    return mutex.try_lock().ok();
+    #[cfg(feature = "async-std")]
+    return mutex.try_lock();
+}
+
+/// Background task, stopped on drop.
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+pub(crate) struct Task(
+    #[cfg(not(feature = "async-std"))] tokio::task::JoinHandle<()>,
+    #[cfg(feature = "async-std")] Option<async_std::task::JoinHandle<()>>,
+);
+
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+impl Task {
+    pub fn spawn(future: impl std::future::Future<Output = ()> + Send + 'static) -> Task {
+        #[cfg(not(feature = "async-std"))]
+        return Task(tokio::task::spawn(future));
+        #[cfg(feature = "async-std")]
+        return Task(Some(async_std::task::spawn(future)));
+    }
+}
+
+#[cfg(any(feature = "tokio", feature = "async-std"))]
+impl Drop for Task {
+    fn drop(&mut self) {
+        #[cfg(not(feature = "async-std"))]
+        self.0.abort();
+        // cancelling awaits the task, so it is detached instead and exits on its own
+        #[cfg(feature = "async-std")]
+        drop(self.0.take());
+    }
+}
@@ -0,0 +1,125 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::{logic, Limiter};
+
+/// Splits the capacity across `N` independent `Limiter`s, so concurrent calls contend on `N` locks
+/// instead of one. Calls are spread round-robin, or by hash with `shard_for`.
+///
+/// Every shard gets its own logic from the `factory`, so for a total quota it should be configured
+/// with `total / N`. The shards don't share capacity: under skewed load, e.g. callers sticking to one
+/// key with `shard_for`, a shard may reject while others sit idle, admitting less than the total.
+/// Only worth it on hot paths where the single lock shows up in profiles.
+/// Work-stealing between shards, falling back to a neighbour's capacity, may come as a follow-up.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use ratelim::logic::QuotaPer;
+/// use ratelim::sharded::ShardedLimiter;
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// // 1000/sec in total
+/// let limiter = ShardedLimiter::new(4, || QuotaPer::new(250, Duration::from_secs(1)));
+/// limiter.sync(1).await;
+/// # }
+/// ```
+pub struct ShardedLimiter<Logic: logic::Logic<State>, State> {
+    shards: Arc<[Limiter<Logic, State>]>,
+    next: Arc<AtomicUsize>,
+    hasher: RandomState,
+}
+
+impl<Logic: logic::Logic<State>, State> Clone for ShardedLimiter<Logic, State> {
+    fn clone(&self) -> ShardedLimiter<Logic, State> {
+        ShardedLimiter {
+            shards: self.shards.clone(),
+            next: self.next.clone(),
+            hasher: self.hasher.clone(),
+        }
+    }
+}
+
+impl<Logic: logic::Logic<State>, State> ShardedLimiter<Logic, State> {
+    pub fn new(shards: usize, factory: impl Fn() -> Logic) -> ShardedLimiter<Logic, State> {
+        ShardedLimiter {
+            shards: (0..shards.max(1)).map(|_| Limiter::new(factory())).collect(),
+            next: Arc::new(AtomicUsize::new(0)),
+            hasher: RandomState::new(),
+        }
+    }
+
+    /// Waits on the next shard in round-robin order.
+    pub async fn sync(&self, state: State) { self.shard().sync(state).await }
+
+    /// Non-blocking version of `sync`. Only the next shard is tried, even if others could grant.
+    pub async fn try_sync(&self, state: State) -> Result<(), State> { self.shard().try_sync(state).await }
+
+    /// The next shard in round-robin order.
+    pub fn shard(&self) -> &Limiter<Logic, State> {
+        &self.shards[self.next.fetch_add(1, Ordering::Relaxed) % self.shards.len()]
+    }
+
+    /// The shard `key` hashes to, the same one for every call with an equal key.
+    pub fn shard_for(&self, key: &impl Hash) -> &Limiter<Logic, State> {
+        &self.shards[self.hasher.hash_one(key) as usize % self.shards.len()]
+    }
+
+    pub fn shards(&self) -> &[Limiter<Logic, State>] { &self.shards }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use crate::logic::{FnLogic, QuotaPer};
+    use super::*;
+
+    #[tokio::test]
+    async fn split_capacity() {
+        let limiter = ShardedLimiter::new(4, || QuotaPer::new(2, Duration::from_secs(60)));
+        for _ in 0..8 {
+            assert!(limiter.try_sync(1).await.is_ok());
+        }
+        assert!(limiter.try_sync(1).await.is_err());
+
+        // skewed load only sees its own shard
+        let limiter = ShardedLimiter::new(4, || QuotaPer::new(2, Duration::from_secs(60)));
+        assert!(limiter.shard_for(&"key").try_sync(1).await.is_ok());
+        assert!(limiter.shard_for(&"key").try_sync(1).await.is_ok());
+        assert!(limiter.shard_for(&"key").try_sync(1).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn less_contention() {
+        // a logic slow enough for the lock to be the bottleneck
+        let slow = || FnLogic::new(|| true, |_: &()| {
+            std::thread::sleep(Duration::from_millis(1));
+            Duration::ZERO
+        }, |_: &()| {});
+
+        // callers on their own threads, so they really do run in parallel
+        fn run<Logic: logic::Logic<()> + Send + 'static>(limiter: ShardedLimiter<Logic, ()>) -> Duration {
+            let runtime = tokio::runtime::Handle::current();
+            let started = Instant::now();
+            let threads: Vec<_> = (0..4).map(|_| {
+                let (limiter, runtime) = (limiter.clone(), runtime.clone());
+                std::thread::spawn(move || runtime.block_on(async {
+                    for _ in 0..25 {
+                        limiter.sync(()).await;
+                    }
+                }))
+            }).collect();
+            for thread in threads {
+                thread.join().unwrap();
+            }
+            started.elapsed()
+        }
+
+        let single = run(ShardedLimiter::new(1, slow));
+        let sharded = run(ShardedLimiter::new(4, slow));
+        assert!(sharded * 2 < single, "sharded {:?}, single {:?}", sharded, single);
+    }
+}
@@ -0,0 +1,147 @@
+//! Sink throttling, enabled with the `sink` feature.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use futures_sink::Sink;
+use crate::{logic, Limiter};
+
+type Granted = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Paces the items sent into a `Sink` with a limiter, every item waits for `state_fn(&item)` to be granted
+/// before it is passed on, e.g. for a downstream with a publish rate cap. Counterpart of `stream::Throttled`.
+///
+/// The cost is only known once an item is sent, so each item is held here until granted and
+/// `poll_ready` is pending meanwhile, woken by the limiter. `poll_flush` and `poll_close` pass the held
+/// item on first. Dropping the sink while an item waits drops the item, nothing has been reserved for it yet.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use futures::SinkExt;
+/// use ratelim::Limiter;
+/// use ratelim::logic::QuotaPer;
+/// use ratelim::sink::ThrottledSink;
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let limiter = Limiter::new(QuotaPer::new(100, Duration::from_secs(1)));
+/// let mut sink = ThrottledSink::new(Vec::new(), limiter, |_: &u32| 1);
+/// for i in 0..10 {
+///     sink.send(i).await.unwrap();
+/// }
+/// assert_eq!(sink.into_inner(), (0..10).collect::<Vec<_>>());
+/// # }
+/// ```
+pub struct ThrottledSink<S, Item, Logic: logic::Logic<State>, State, F> {
+    sink: Pin<Box<S>>,
+    limiter: Limiter<Logic, State>,
+    state_fn: F,
+    // item waiting to be passed on, along with the wait, `None` once granted
+    pending: Option<(Item, Option<Granted>)>,
+}
+
+// nothing is pinned in place
+impl<S, Item, Logic: logic::Logic<State>, State, F> Unpin for ThrottledSink<S, Item, Logic, State, F> {}
+
+impl<S, Item, Logic, State, F> ThrottledSink<S, Item, Logic, State, F>
+where
+    S: Sink<Item>,
+    Logic: logic::Logic<State> + Send + 'static,
+    State: Send + 'static,
+    F: Fn(&Item) -> State,
+{
+    pub fn new(sink: S, limiter: Limiter<Logic, State>, state_fn: F) -> ThrottledSink<S, Item, Logic, State, F> {
+        ThrottledSink {
+            sink: Box::pin(sink),
+            limiter,
+            state_fn,
+            pending: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &S { &self.sink }
+
+    /// The wrapped sink, an item still waiting for the limiter is dropped.
+    pub fn into_inner(self) -> S where S: Unpin { *Pin::into_inner(self.sink) }
+
+    /// Waits for the held item, if any, to be granted, then passes it on.
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        let Some((_, granted)) = &mut self.pending else { return Poll::Ready(Ok(())) };
+        if let Some(wait) = granted {
+            ready!(wait.as_mut().poll(cx));
+            *granted = None;
+        }
+
+        ready!(self.sink.as_mut().poll_ready(cx))?;
+        let (item, _) = self.pending.take().expect("checked above");
+        Poll::Ready(self.sink.as_mut().start_send(item))
+    }
+}
+
+impl<S, Item, Logic, State, F> Sink<Item> for ThrottledSink<S, Item, Logic, State, F>
+where
+    S: Sink<Item>,
+    Logic: logic::Logic<State> + Send + 'static,
+    State: Send + 'static,
+    F: Fn(&Item) -> State,
+{
+    type Error = S::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        ready!(self.poll_pending(cx))?;
+        self.sink.as_mut().poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Item) -> Result<(), S::Error> {
+        let state = (self.state_fn)(&item);
+        let limiter = self.limiter.clone();
+        self.pending = Some((item, Some(Box::pin(async move { limiter.sync(state).await }))));
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        ready!(self.poll_pending(cx))?;
+        self.sink.as_mut().poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+        ready!(self.poll_pending(cx))?;
+        self.sink.as_mut().poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use futures::{SinkExt, StreamExt};
+    use crate::logic::QuotaPer;
+    use super::*;
+
+    #[tokio::test]
+    async fn paced() {
+        let limiter = Limiter::new(QuotaPer::new(2, Duration::from_millis(50)));
+        let (sender, receiver) = futures::channel::mpsc::unbounded();
+        let mut sink = ThrottledSink::new(sender, limiter, |_: &u32| 1);
+
+        // the producer never waits on its own, only the limiter holds it back
+        let started = Instant::now();
+        sink.send_all(&mut futures::stream::iter(0..6).map(Ok)).await.unwrap();
+        sink.close().await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100), "{:?}", started.elapsed());
+
+        assert_eq!(receiver.collect::<Vec<_>>().await, vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    #[tokio::test]
+    async fn flush_passes_on_the_held_item() {
+        let limiter = Limiter::new(QuotaPer::new(1, Duration::from_secs(60)));
+        let mut sink = ThrottledSink::new(Vec::new(), limiter, |_: &u32| 1);
+
+        sink.feed(7).await.unwrap();
+        assert!(sink.get_ref().is_empty());
+        sink.flush().await.unwrap();
+        assert_eq!(sink.get_ref(), &[7]);
+    }
+}
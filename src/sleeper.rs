@@ -0,0 +1,165 @@
+//! Timers the limiters wait with, so the crate itself is not tied to a runtime.
+
+use std::future::{poll_fn, Future};
+use std::pin::{pin, Pin};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+pub type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Source of timers, set with `LimiterBuilder::sleeper`.
+///
+/// By default `WasmSleeper` with the `wasm` feature on `wasm32`, otherwise `AsyncStdSleeper` with
+/// the `async-std` feature, otherwise `TokioSleeper` with the default `tokio` feature, otherwise
+/// `ThreadSleeper`. Any executor's timer may be plugged in instead.
+pub trait Sleeper: Send + Sync {
+    fn sleep(&self, duration: Duration) -> Sleep;
+}
+
+#[cfg(feature = "tokio")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TokioSleeper;
+
+#[cfg(feature = "tokio")]
+impl Sleeper for TokioSleeper {
+    fn sleep(&self, duration: Duration) -> Sleep { Box::pin(tokio::time::sleep(duration)) }
+}
+
+#[cfg(feature = "async-std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AsyncStdSleeper;
+
+#[cfg(feature = "async-std")]
+impl Sleeper for AsyncStdSleeper {
+    fn sleep(&self, duration: Duration) -> Sleep { Box::pin(async_std::task::sleep(duration)) }
+}
+
+/// Browser timers, through `setTimeout`.
+///
+/// Nothing else has to change for the browser: the mutex and `Notify` don't need a runtime,
+/// and the lock is never contended from other threads, so it is as cheap as a `RefCell` there.
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct WasmSleeper;
+
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+impl Sleeper for WasmSleeper {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        let millis = duration.as_millis().min(u32::MAX as u128) as u32;
+        Box::pin(SingleThreaded(gloo_timers::future::TimeoutFuture::new(millis)))
+    }
+}
+
+/// JS futures are not `Send`, which only matters if there are threads to send them to.
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+struct SingleThreaded<F>(F);
+
+// SAFETY: without the `atomics` target feature wasm32 has a single thread, nothing is ever sent to another one
+#[cfg(all(feature = "wasm", target_arch = "wasm32", not(target_feature = "atomics")))]
+unsafe impl<F> Send for SingleThreaded<F> {}
+
+#[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+impl<F: Future + Unpin> Future for SingleThreaded<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> { Pin::new(&mut self.0).poll(cx) }
+}
+
+/// Sleeps on a thread of its own, so it works under any executor, or none at all.
+/// A thread per sleep is costly though, prefer the executor's own timer where there is one.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ThreadSleeper;
+
+impl Sleeper for ThreadSleeper {
+    fn sleep(&self, duration: Duration) -> Sleep {
+        let shared = Arc::new(Mutex::new(ThreadSleep { is_done: false, waker: None }));
+        let mut spawned = false;
+
+        Box::pin(poll_fn(move |cx: &mut Context<'_>| {
+            let mut sleep = shared.lock().unwrap_or_else(PoisonError::into_inner);
+            if sleep.is_done {
+                return Poll::Ready(());
+            }
+            sleep.waker = Some(cx.waker().clone());
+
+            if !spawned {
+                spawned = true;
+                let shared = shared.clone();
+                std::thread::spawn(move || {
+                    std::thread::sleep(duration);
+                    let mut sleep = shared.lock().unwrap_or_else(PoisonError::into_inner);
+                    sleep.is_done = true;
+                    if let Some(waker) = sleep.waker.take() {
+                        waker.wake();
+                    }
+                });
+            }
+            Poll::Pending
+        }))
+    }
+}
+
+struct ThreadSleep {
+    is_done: bool,
+    waker: Option<Waker>,
+}
+
+pub(crate) fn default_sleeper() -> Arc<dyn Sleeper> {
+    #[cfg(all(feature = "wasm", target_arch = "wasm32"))]
+    return Arc::new(WasmSleeper);
+    #[cfg(all(feature = "async-std", not(all(feature = "wasm", target_arch = "wasm32"))))]
+    return Arc::new(AsyncStdSleeper);
+    #[cfg(all(feature = "tokio", not(feature = "async-std"), not(all(feature = "wasm", target_arch = "wasm32"))))]
+    return Arc::new(TokioSleeper);
+    #[cfg(not(any(feature = "tokio", feature = "async-std", all(feature = "wasm", target_arch = "wasm32"))))]
+    return Arc::new(ThreadSleeper);
+}
+
+/// Runs `future` until `sleep` is over, `None` if it is not done by then.
+pub(crate) async fn timeout<F: Future>(mut sleep: Sleep, future: F) -> Option<F::Output> {
+    let mut future = pin!(future);
+    poll_fn(|cx| {
+        if let Poll::Ready(output) = future.as_mut().poll(cx) {
+            return Poll::Ready(Some(output));
+        }
+        sleep.as_mut().poll(cx).map(|()| None)
+    }).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Instant;
+    use crate::Limiter;
+    use crate::logic::QuotaPer;
+    use super::*;
+
+    /// Logs every sleep, then leaves the actual sleeping to a thread, so no runtime is involved.
+    struct LoggingSleeper(Arc<Mutex<Vec<Duration>>>);
+
+    impl Sleeper for LoggingSleeper {
+        fn sleep(&self, duration: Duration) -> Sleep {
+            self.0.lock().unwrap().push(duration);
+            ThreadSleeper.sleep(duration)
+        }
+    }
+
+    #[test]
+    fn custom_sleeper() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let limiter = Limiter::builder(QuotaPer::new(1, Duration::from_millis(30)))
+            .sleeper(LoggingSleeper(log.clone()))
+            .build();
+
+        let started = Instant::now();
+        futures::executor::block_on(async {
+            limiter.sync(1).await;
+            limiter.sync(1).await;
+        });
+
+        assert!(started.elapsed() >= Duration::from_millis(25), "{:?}", started.elapsed());
+        let log = log.lock().unwrap();
+        assert!(!log.is_empty());
+        assert!(log.iter().all(|slept| *slept <= Duration::from_millis(30)), "{:?}", log);
+    }
+}
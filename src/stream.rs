@@ -0,0 +1,125 @@
+//! Stream throttling, enabled with the `stream` feature.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{ready, Context, Poll};
+use futures_core::Stream;
+use crate::{logic, Limiter};
+
+type Granted = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Adds `throttle` to every `Stream`.
+pub trait LimiterStreamExt: Stream + Sized {
+    /// Paces the stream with `limiter`, every item waits for `state_fn(&item)` to be granted before it is yielded.
+    ///
+    /// ## Example
+    ///
+    /// ```
+    /// use std::time::Duration;
+    /// use futures::StreamExt;
+    /// use ratelim::Limiter;
+    /// use ratelim::logic::QuotaPer;
+    /// use ratelim::stream::LimiterStreamExt;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() {
+    /// let limiter = Limiter::new(QuotaPer::new(100, Duration::from_secs(1)));
+    /// let items: Vec<_> = futures::stream::iter(0..10).throttle(limiter, |_| 1).collect().await;
+    /// assert_eq!(items, (0..10).collect::<Vec<_>>());
+    /// # }
+    /// ```
+    fn throttle<Logic, State, F>(self, limiter: Limiter<Logic, State>, state_fn: F) -> Throttled<Self, Logic, State, F>
+    where
+        Logic: logic::Logic<State>,
+        F: FnMut(&Self::Item) -> State,
+    {
+        Throttled {
+            stream: Box::pin(self),
+            limiter,
+            state_fn,
+            pending: None,
+        }
+    }
+}
+
+impl<S: Stream> LimiterStreamExt for S {}
+
+/// Stream returned by `LimiterStreamExt::throttle`.
+///
+/// The source is not polled while an item waits for the limiter, so backpressure propagates upstream.
+/// Dropping it while an item waits cancels the wait, for which nothing has been reserved yet.
+pub struct Throttled<S: Stream, Logic: logic::Logic<State>, State, F> {
+    stream: Pin<Box<S>>,
+    limiter: Limiter<Logic, State>,
+    state_fn: F,
+    // item waiting to be granted, along with the wait
+    pending: Option<(S::Item, Granted)>,
+}
+
+// nothing is pinned in place
+impl<S: Stream, Logic: logic::Logic<State>, State, F> Unpin for Throttled<S, Logic, State, F> {}
+
+impl<S, Logic, State, F> Stream for Throttled<S, Logic, State, F>
+where
+    S: Stream,
+    Logic: logic::Logic<State> + Send + 'static,
+    State: Send + 'static,
+    F: FnMut(&S::Item) -> State,
+{
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        let this = &mut *self;
+
+        if this.pending.is_none() {
+            let Some(item) = ready!(this.stream.as_mut().poll_next(cx)) else { return Poll::Ready(None) };
+
+            let state = (this.state_fn)(&item);
+            let limiter = this.limiter.clone();
+            this.pending = Some((item, Box::pin(async move { limiter.sync(state).await })));
+        }
+
+        let (_, granted) = this.pending.as_mut().expect("set above");
+        ready!(granted.as_mut().poll(cx));
+
+        Poll::Ready(this.pending.take().map(|(item, _)| item))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let pending = usize::from(self.pending.is_some());
+        let (lower, upper) = self.stream.size_hint();
+        (lower.saturating_add(pending), upper.and_then(|upper| upper.checked_add(pending)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use futures::StreamExt;
+    use crate::logic::QuotaPer;
+    use super::*;
+
+    #[tokio::test]
+    async fn paced() {
+        let limiter = Limiter::new(QuotaPer::new(2, Duration::from_millis(50)));
+        let started = Instant::now();
+        let items: Vec<_> = futures::stream::iter(0..6).throttle(limiter, |_| 1).collect().await;
+
+        assert_eq!(items, vec![0, 1, 2, 3, 4, 5]);
+        assert!(started.elapsed() >= Duration::from_millis(100), "{:?}", started.elapsed());
+    }
+
+    #[tokio::test]
+    async fn cancel() {
+        let limiter = Limiter::new(QuotaPer::new(2, Duration::from_secs(60)));
+        let mut throttled = futures::stream::iter(0..10).throttle(limiter.clone(), |_| 1);
+
+        assert_eq!(throttled.next().await, Some(0));
+        assert_eq!(throttled.next().await, Some(1));
+        assert!(tokio::time::timeout(Duration::from_millis(20), throttled.next()).await.is_err());
+        drop(throttled);
+
+        let stats = limiter.stats().await;
+        assert_eq!((stats.granted, stats.current_pending), (2, 2));
+    }
+}
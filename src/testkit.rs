@@ -0,0 +1,145 @@
+//! Property testing of `Logic` implementations with `proptest`, enabled with the `testkit` feature.
+//!
+//! Runs a logic through random sequences of grants, frees, time passing and resets, the way a `Limiter` drives it:
+//! only a ready logic is added to, and every free hands back exactly what `reserve` returned, once.
+//! A reset keeps the reservations made before it, they are still freed later, like permits held across
+//! `Limiter::reset`. Checked invariants:
+//!
+//! - nothing panics, e.g. a counter underflowing on a free after a reset,
+//! - freeing never turns a ready logic into a not ready one.
+//!
+//! ## Example
+//!
+//! ```
+//! use std::time::Duration;
+//! use ratelim::logic::QuotaPer;
+//! use ratelim::testkit;
+//!
+//! testkit::check(|| QuotaPer::new(10, Duration::from_secs(1)), 1..5u64).unwrap();
+//! ```
+
+use std::fmt::Debug;
+use std::time::Duration;
+use proptest::prelude::*;
+use proptest::test_runner::{TestError, TestRunner};
+use crate::logic::Logic;
+
+/// Step of a sequence run by `run`.
+#[derive(Clone, Debug)]
+pub enum Op<State> {
+    /// Grants `State` if the logic is ready for it, skipped otherwise.
+    Add(State),
+    /// Frees the oldest reservation right away, like a dropped permit.
+    Free,
+    /// Lets time pass, freeing the reservations due meanwhile.
+    Advance(Duration),
+    /// `Logic::reset`.
+    Reset,
+}
+
+/// Sequences of up to 64 `Op`s, with the added states drawn from `states`.
+pub fn ops<S>(states: S) -> impl Strategy<Value = Vec<Op<S::Value>>>
+where
+    S: Strategy + 'static,
+    S::Value: Clone,
+{
+    let op = prop_oneof![
+        4 => states.prop_map(Op::Add),
+        2 => Just(Op::Free),
+        2 => (0..100u64).prop_map(|millis| Op::Advance(Duration::from_millis(millis))),
+        1 => Just(Op::Reset),
+    ];
+    proptest::collection::vec(op, 0..64)
+}
+
+/// Runs `ops` on `logic`, failing on the first broken invariant, e.g. within a custom `proptest!`.
+/// The time only moves with `Op::Advance`, logics reading the clock on their own still see the real one.
+pub fn run<L: Logic<State>, State>(mut logic: L, ops: Vec<Op<State>>) -> Result<(), TestCaseError> {
+    let mut now = Duration::ZERO;
+    // in grant order, `None` for a reservation never due
+    let mut reserved: Vec<(Option<Duration>, State)> = Vec::new();
+
+    for op in ops {
+        match op {
+            Op::Add(state) if logic.is_ready_for(&state) => {
+                let (delay, state) = logic.reserve(state);
+                reserved.push((now.checked_add(delay), state));
+            }
+            Op::Add(_) => {}
+            Op::Free if !reserved.is_empty() => free(&mut logic, reserved.remove(0).1)?,
+            Op::Free => {}
+            Op::Advance(by) => {
+                now += by;
+                let (mut due, still_reserved): (Vec<_>, Vec<_>) =
+                    reserved.drain(..).partition(|(due, _)| due.is_some_and(|due| due <= now));
+                reserved = still_reserved;
+                due.sort_by_key(|(due, _)| *due);
+                for (_, state) in due {
+                    free(&mut logic, state)?;
+                }
+            }
+            Op::Reset => logic.reset(),
+        }
+    }
+    Ok(())
+}
+
+fn free<L: Logic<State>, State>(logic: &mut L, state: State) -> Result<(), TestCaseError> {
+    let was_ready = logic.is_ready();
+    logic.free(&state);
+    prop_assert!(!was_ready || logic.is_ready(), "a free turned the logic not ready");
+    Ok(())
+}
+
+/// Runs random `ops` of `states` on fresh logics from `new_logic`, returning the smallest failing sequence.
+pub fn check<L, S>(new_logic: impl Fn() -> L, states: S) -> Result<(), TestError<Vec<Op<S::Value>>>>
+where
+    L: Logic<S::Value>,
+    S: Strategy + 'static,
+    S::Value: Clone + Debug,
+{
+    TestRunner::default().run(&ops(states), |ops| run(new_logic(), ops))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logic::{QuotaPer, Timeout};
+    use super::*;
+
+    #[test]
+    fn builtin_logics() {
+        check(|| QuotaPer::new(10, Duration::from_millis(50)), 1..5u64).unwrap();
+        check(|| QuotaPer::new(2.5, Duration::from_millis(50)), 0.1..2.0f64).unwrap();
+        check(|| Timeout::new(Duration::from_millis(50)), Just(())).unwrap();
+    }
+
+    // `QuotaPer` before frees were clamped to zero
+    struct Unclamped {
+        quota: u64,
+        state: u64,
+    }
+
+    impl Logic<u64> for Unclamped {
+        fn is_ready(&self) -> bool { self.state < self.quota }
+
+        fn add_for(&mut self, state: &u64) -> Duration {
+            self.state += state;
+            Duration::from_millis(50)
+        }
+
+        fn free(&mut self, state: &u64) { self.state -= state; }
+
+        fn reset(&mut self) { self.state = 0; }
+    }
+
+    #[test]
+    fn catches_underflow() {
+        let Err(TestError::Fail(_, ops)) = check(|| Unclamped { quota: 10, state: 0 }, 1..5u64) else {
+            panic!("the underflow went unnoticed");
+        };
+        // a reservation made before the reset, freed after it
+        let reset = ops.iter().position(|op| matches!(op, Op::Reset)).expect("only a reset loses count");
+        assert!(ops[..reset].iter().any(|op| matches!(op, Op::Add(_))), "{:?}", ops);
+        assert!(ops[reset..].iter().any(|op| matches!(op, Op::Free | Op::Advance(_))), "{:?}", ops);
+    }
+}
@@ -0,0 +1,7 @@
+//! Time sources of the crate. `std::time::Instant` and `SystemTime` panic in the browser, so the `wasm` feature
+//! swaps in `web_time`'s, which are `std`'s own everywhere but on `wasm32`.
+
+#[cfg(not(feature = "wasm"))]
+pub use std::time::{Instant, SystemTime, UNIX_EPOCH};
+#[cfg(feature = "wasm")]
+pub use web_time::{Instant, SystemTime, UNIX_EPOCH};
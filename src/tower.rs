@@ -0,0 +1,164 @@
+//! Tower integration, enabled with the `tower` feature.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+use crate::{logic, Limiter};
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Wraps services with a `RateLimitService`, all of them sharing the same `limiter`.
+///
+/// ## Example
+///
+/// ```
+/// use std::time::Duration;
+/// use ratelim::Limiter;
+/// use ratelim::logic::QuotaPer;
+/// use ratelim::tower::RateLimitLayer;
+/// use tower::{service_fn, ServiceBuilder, ServiceExt};
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let limiter = Limiter::new(QuotaPer::new(10, Duration::from_secs(1)));
+/// let service = ServiceBuilder::new()
+///     // larger requests take more of the quota
+///     .layer(RateLimitLayer::new(limiter, |request: &String| request.len() as u64))
+///     .service(service_fn(|request: String| async move { Ok::<_, ()>(request) }));
+///
+/// assert_eq!(service.oneshot("hello".to_string()).await, Ok("hello".to_string()));
+/// # }
+/// ```
+pub struct RateLimitLayer<Logic: logic::Logic<State>, State, F> {
+    limiter: Limiter<Logic, State>,
+    state_fn: Arc<F>,
+}
+
+impl<Logic: logic::Logic<State>, State, F> RateLimitLayer<Logic, State, F> {
+    /// Every request reserves `state_fn(&request)` from `limiter`.
+    pub fn new(limiter: Limiter<Logic, State>, state_fn: F) -> RateLimitLayer<Logic, State, F> {
+        RateLimitLayer { limiter, state_fn: Arc::new(state_fn) }
+    }
+}
+
+impl<Logic: logic::Logic<State>, State, F> Clone for RateLimitLayer<Logic, State, F> {
+    fn clone(&self) -> RateLimitLayer<Logic, State, F> {
+        RateLimitLayer { limiter: self.limiter.clone(), state_fn: self.state_fn.clone() }
+    }
+}
+
+impl<S, Logic: logic::Logic<State>, State, F> Layer<S> for RateLimitLayer<Logic, State, F> {
+    type Service = RateLimitService<S, Logic, State, F>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            limiter: self.limiter.clone(),
+            state_fn: self.state_fn.clone(),
+            ready: None,
+        }
+    }
+}
+
+/// Paces the requests to `inner` with a `Limiter`.
+///
+/// `poll_ready` is pending until the limiter is ready, woken like any other waiter rather than polled.
+/// Readiness is not a reservation though, the request is only charged in `call`, which still waits
+/// if other callers took the capacity in between. The inner service is called once the request is granted.
+pub struct RateLimitService<S, Logic: logic::Logic<State>, State, F> {
+    inner: S,
+    limiter: Limiter<Logic, State>,
+    state_fn: Arc<F>,
+    ready: Option<BoxFuture<()>>,
+}
+
+impl<S: Clone, Logic: logic::Logic<State>, State, F> Clone for RateLimitService<S, Logic, State, F> {
+    fn clone(&self) -> RateLimitService<S, Logic, State, F> {
+        RateLimitService {
+            inner: self.inner.clone(),
+            limiter: self.limiter.clone(),
+            state_fn: self.state_fn.clone(),
+            ready: None,
+        }
+    }
+}
+
+impl<S, Logic, State, F, Request> Service<Request> for RateLimitService<S, Logic, State, F>
+where
+    S: Service<Request> + Clone + Send + 'static,
+    S::Future: Send,
+    Logic: logic::Logic<State> + Send + 'static,
+    State: Send + 'static,
+    F: Fn(&Request) -> State,
+    Request: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<Result<S::Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let limiter = self.limiter.clone();
+        let ready = self.ready.get_or_insert_with(|| Box::pin(async move { drop(limiter.wait_ready(None, None).await) }));
+        ready!(ready.as_mut().poll(cx));
+        self.ready = None;
+
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: Request) -> Self::Future {
+        let state = (self.state_fn)(&request);
+        let limiter = self.limiter.clone();
+
+        // the ready service goes into the future, its clone stays for the next `poll_ready`
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+
+        Box::pin(async move {
+            limiter.sync(state).await;
+            inner.call(request).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+    use tower::{service_fn, ServiceExt};
+    use crate::logic::QuotaPer;
+    use super::*;
+
+    #[tokio::test]
+    async fn paced() {
+        let limiter = Limiter::new(QuotaPer::new(2, Duration::from_millis(50)));
+        let mut service = RateLimitLayer::new(limiter.clone(), |_: &u32| 1)
+            .layer(service_fn(|request: u32| async move { Ok::<_, ()>(request * 2) }));
+
+        let started = Instant::now();
+        let mut granted = Vec::new();
+        for request in 0..6 {
+            assert_eq!(service.ready().await.unwrap().call(request).await, Ok(request * 2));
+            granted.push(started.elapsed());
+        }
+
+        // two per 50ms window
+        assert!(granted[1] < Duration::from_millis(40), "{:?}", granted);
+        assert!(granted[2] >= Duration::from_millis(50), "{:?}", granted);
+        assert!(granted[4] >= Duration::from_millis(100), "{:?}", granted);
+        assert_eq!(limiter.stats().await.granted, 6);
+    }
+
+    #[tokio::test]
+    async fn ready_waits_for_capacity() {
+        let limiter = Limiter::new(QuotaPer::new(1, Duration::from_millis(50)));
+        let mut service = RateLimitLayer::new(limiter.clone(), |_: &()| 1)
+            .layer(service_fn(|_: ()| async { Ok::<_, ()>(()) }));
+
+        assert!(limiter.try_sync(1).await.is_ok());
+        let started = Instant::now();
+        service.ready().await.unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(45), "{:?}", started.elapsed());
+    }
+}
@@ -0,0 +1,21 @@
+//! Run with `wasm-pack test --headless --firefox --no-default-features --features wasm -- --test wasm`.
+#![cfg(target_arch = "wasm32")]
+
+use std::time::Duration;
+use ratelim::Limiter;
+use ratelim::logic::QuotaPer;
+use ratelim::time::Instant;
+use wasm_bindgen_test::{wasm_bindgen_test, wasm_bindgen_test_configure};
+
+wasm_bindgen_test_configure!(run_in_browser);
+
+#[wasm_bindgen_test]
+async fn paced() {
+    let limiter = Limiter::new(QuotaPer::new(2, Duration::from_millis(50)));
+
+    let started = Instant::now();
+    for _ in 0..6 {
+        limiter.sync(1).await;
+    }
+    assert!(started.elapsed() >= Duration::from_millis(100), "{:?}", started.elapsed());
+}